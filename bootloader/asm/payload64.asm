@@ -0,0 +1,63 @@
+; A minimal 64-bit payload for `longmode::enter`. With the `payload` feature
+; the build appends it to stage0.asm past the end of the Rust image. We get
+; here in long mode with everything identity mapped, interrupts off and the
+; argument, the address of the E820 map, in rdi. We say so over COM1 and halt,
+; a real kernel would take over from here
+[bits 64]
+default rel
+
+%define COM1      0x3F8
+; Line status register: The transmit holding register is empty
+%define COM1_LSR  COM1 + 5
+%define LSR_THRE  0x20
+
+payload_entry:
+    mov rbx, rdi
+
+    lea rsi, [message]
+print_message:
+    lodsb
+    test al, al
+    jz  print_argument
+    call putc
+    jmp print_message
+
+    ; rbx as 16 hex digits, most significant first
+print_argument:
+    mov ecx, 16
+print_digit:
+    rol rbx, 4
+    mov al, bl
+    and al, 0x0F
+    add al, '0'
+    cmp al, '9'
+    jbe print_digit_out
+    add al, 'A' - '9' - 1
+print_digit_out:
+    call putc
+    dec ecx
+    jnz print_digit
+
+    mov al, 10
+    call putc
+
+halt:
+    cli
+    hlt
+    jmp halt
+
+; Write al to COM1 once it can take another byte, clobbers ah and dx
+putc:
+    mov ah, al
+    mov dx, COM1_LSR
+putc_wait:
+    in  al, dx
+    test al, LSR_THRE
+    jz  putc_wait
+    mov dx, COM1
+    mov al, ah
+    out dx, al
+    ret
+
+message:
+    db "Hello from long mode, E820 map at 0x", 0
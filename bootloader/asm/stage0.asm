@@ -2,6 +2,13 @@
 [org  0x7c00]
 [bits 16]
 
+; Where we leave the BIOS E820 memory map for Rust, a dword entry count then
+; 24 byte entries from E820_MAP + 8. Must match `memory::map` in Rust
+%define E820_MAP     0x5000
+%define E820_MAX     128
+%define E820_ENTRY   24
+%define E820_SMAP    0x534D4150
+
 entry:
     ; Disable interrupts and clear direction flag
     cli
@@ -12,9 +19,38 @@ entry:
 	or    al, 2
 	out 0x92, al
 
-    ; Clear DS
+    ; Clear DS and ES
     xor ax, ax
     mov ds, ax
+    mov es, ax
+
+    ; Collect the E820 memory map while we still have the BIOS
+    mov di, E820_MAP + 8
+    xor ebx, ebx
+    xor bp, bp
+e820_next:
+    ; Pre-set the ACPI 3 extended attributes to valid for BIOSes that only
+    ; return 20 bytes
+    mov dword [es:di + 20], 1
+    mov eax, 0xE820
+    mov edx, E820_SMAP
+    mov ecx, E820_ENTRY
+    int 0x15
+    ; Carry or a missing signature means no more entries (or no E820)
+    jc  e820_done
+    cmp eax, E820_SMAP
+    jne e820_done
+    inc bp
+    add di, E820_ENTRY
+    ; EBX is zero after the last entry
+    test ebx, ebx
+    jz  e820_done
+    cmp bp, E820_MAX
+    jb  e820_next
+e820_done:
+    movzx ebp, bp
+    mov [E820_MAP], ebp
+    cli
 
     ; Load a 32-bit GDT
     lgdt [ds:pm_gdt]
@@ -43,6 +79,20 @@ pm_entry:
     ; Who needs an allocator anyway?
     mov esp, 0x2000000
 
+    ; entry(entry_point, memory_map, image_end, readonly_start, readonly_end,
+    ; payload_start, payload_end) with the cdecl convention, the image values
+    ; are also defined during build. Without a payload both are 0
+%ifdef payload
+    push payload_end
+    push payload_start
+%else
+    push 0
+    push 0
+%endif
+    push readonly_end
+    push readonly_start
+    push image_end
+    push E820_MAP
     push entry_point
     ; Jump into Rust! (entry_point is a defined variable during build)
     call entry_point
@@ -66,4 +116,14 @@ pm_gdt:
 times 510-($-$$) db 0
 dw 0xaa55
 
-incbin "bootloader/build/bootloader.flat"
\ No newline at end of file
+incbin "bootloader/build/bootloader.flat"
+
+%ifdef payload
+; The 64-bit payload goes past the end of the Rust image in memory, .bss is
+; not in the flat binary so we pad over it first
+times (image_end - 0x7c00) - ($-$$) db 0
+align 16
+payload_start:
+incbin "bootloader/build/payload64.bin"
+payload_end:
+%endif
@@ -1,172 +1,727 @@
-//! This crate is responsible for all things ACPI
-//! WIP
-
-use crate::{print, cpu};
-
-#[derive(Debug)]
-struct RSDPDescripter{
-    signature: [u8; 8],
-    checksum: u8,
-    oem_id: [u8; 6],
-    revision: u8,
-    rsdt_address: u32,
-}
-
-impl RSDPDescripter{
-    /// This searches for the RSDP Descripter and if it finds it builds a Struct [`RSDPDescripter`] with the fields
-    /// 
-    fn new() -> Self{
-        let ebda = unsafe{ core::ptr::read(0x040E as *const u16) as u32 };
-
-        let ranges: [core::ops::Range<u32>; 2] = [(ebda..ebda+1024) , (0xE0000..0xFFFFF)];
-        let mut rsdp: usize = 0;
-
-        'outer: for range in ranges{
-            for line in range.step_by(0x10){
-                let b = get_mem(line as usize, 16);
-                if &b[..8] == b"RSD PTR "{
-                    rsdp = line as usize;
-                    break 'outer;
-                }
-            }
-        }
-        // Validate we found a rsdp
-        if rsdp == 0 { panic!("No RDSP Found") }
-
-        let raw = get_mem(rsdp, core::mem::size_of::<RSDPDescripter>());
-
-        let mut sum: usize = 0;
-        for r in raw{
-            sum += *r as usize;
-        };
-        // Validate the RSDP is valid, the sum of all elements must end in a 0
-        if sum % 10 != 0 { panic!("RDSP Checksum failed, Sum: {:#X}", sum) }
-
-        let mut signature = [0u8; 8];
-        signature.copy_from_slice(&raw[..8]);
-        let mut oem_id = [0u8; 6];
-        oem_id.copy_from_slice(&raw[9..15]);
-        let mut rsdt_address = [0u8; 4];
-        rsdt_address.copy_from_slice(&raw[16..20]);
-
-        Self{
-            signature,
-            checksum: raw[8],
-            oem_id,
-            revision: raw[15],
-            rsdt_address: u32::from_le_bytes(rsdt_address),
-        }
-    }
-}
-
-/// This table is found after we get the pointer to it from [`RSDPDescripter`]
-/// Contains a list of entries for ACPI tables
-/// 
-#[derive(Debug)]
-struct RSDT{
-    signature: [u8; 4],
-    length: [u8; 4],
-    revision: u8,
-    checksum: u8,
-    oem_id: [u8; 6],
-    oem_table_id: [u8; 8],
-    oem_revision: [u8; 4],
-    creator_id: [u8; 4],
-    creator_revision: [u8; 4],
-    entries: &'static [u32],
-}
-
-impl RSDT{
-    /// Takes a pointer to the RSDT and returns a struct with all fields
-    /// 
-    fn new(ptr: usize) -> Self{
-
-        let rdst_size = core::mem::size_of::<RSDT>() - core::mem::size_of::<&[u8]>();
-
-        let raw = get_mem(ptr as usize, rdst_size);
-
-        let mut signature = [0u8; 4];
-        signature.copy_from_slice(&raw[..4]);
-
-        let mut length = [0u8; 4];
-        length.copy_from_slice(&raw[4..8]);
-
-        let mut oem_id = [0u8; 6];
-        oem_id.copy_from_slice(&raw[10..16]);
-
-        let mut oem_table_id = [0u8; 8];
-        oem_table_id.copy_from_slice(&raw[16..24]);
-
-        let mut oem_revision = [0u8; 4];
-        oem_revision.copy_from_slice(&raw[24..28]);
-      
-        let mut creator_id = [0u8; 4];
-        creator_id.copy_from_slice(&raw[28..32]);
-
-        let mut creator_revision = [0u8; 4];
-        creator_revision.copy_from_slice(&raw[32..36]);
-
-        // This gets the 32bi addresses from the bottom of the table, their as 9 entries in the table so we subtact 9
-        // To get length we first convert length which in an array of [u8;4] to a u32 
-        let entries = get_mem32(ptr + rdst_size, (u32::from_le_bytes(length) / 4 - 9) as usize);
-        
-        Self{
-            signature,
-            length,
-            revision: raw[8],
-            checksum: raw[9],
-            oem_id,
-            oem_table_id,
-            oem_revision,
-            creator_id,
-            creator_revision,
-            entries,
-        }
-    }
-    /// This will be changed in future, just saving this code for now
-    fn inspect_entries(&self){
-        for entry in self.entries{
-            let hdr = get_mem(*entry as usize, 4);
-            let hdr_str = core::str::from_utf8(hdr);
-            print!("{:?}\n", hdr_str);
-        }
-    }
-}
-
-impl core::fmt::Display for RSDT{
-    
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        use core::str::from_utf8;
-        write!(f,   
-            "Signiture: {}\nlength: {}\nRevision: {:#X}\n\
-            Checksum: {:#X}\nOEMID: {}\nOEM Table ID: {}\n\
-            OEM Revision: {}\nCreator ID: {}\nCreator Revision: {}\n\
-            Entries: {:X?}", 
-            from_utf8(&self.signature).unwrap(),
-            u32::from_le_bytes(self.length) / 4,
-            self.revision,
-            self.checksum,
-            from_utf8(&self.oem_id).unwrap(),
-            from_utf8(&self.oem_table_id).unwrap(),
-            u32::from_le_bytes(self.oem_revision),
-            from_utf8(&self.creator_id).unwrap(),
-            u32::from_le_bytes(self.creator_revision),
-            self.entries,
-        )
-    }
-}
-
-struct Pci;
-
-fn get_mem(addr: usize, len: usize) -> &'static [u8]{
-    unsafe{
-        &*core::ptr::slice_from_raw_parts(addr as *const u8, len)
-    }
-}
-
-fn get_mem32(addr: usize, len: usize) -> &'static [u32]{
-    unsafe{
-        &*core::ptr::slice_from_raw_parts(addr as *const u32, len)
-    }
+//! This crate is responsible for all things ACPI, we find the RSDP, follow it
+//! to the RSDT or XSDT and validate the checksum of every table we find.
+//! Tables are looked up by signature with [`Acpi::find_table`] and the ones we
+//! use are parsed into typed structs such as [`Fadt`], [`Madt`], [`Hpet`] and
+//! [`Mcfg`]
+use core::mem::size_of;
+
+use crate::cpu;
+use crate::error::{Error, Result};
+
+/// The RSDP starts with this signature on a 16 byte boundary
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The ACPI 1.0 RSDP is this long, the first checksum only covers these bytes
+const RSDP_V1_LEN: usize = 20;
+/// BDA address that holds the real mode segment of the EBDA
+const BDA_EBDA_SEGMENT: usize = 0x040E;
+/// The main BIOS area we search for the RSDP
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+/// Size of the header on the front of every System Description Table
+const SDT_HEADER_LEN: usize = size_of::<SdtHeader>();
+/// The most tables we keep track of from the RSDT/XSDT
+const MAX_TABLES: usize = 32;
+
+/// Once we `init()` we keep a copy here for things like the panic handler
+/// that cannot be handed one
+static mut ACPI: Option<Acpi> = None;
+
+/// Root System Description Pointer, the revision tells us if the ACPI 2.0
+/// fields are valid
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+	signature: [u8; 8],
+	checksum: u8,
+	oem_id: [u8; 6],
+	revision: u8,
+	rsdt_address: u32,
+	// ACPI 2.0+
+	length: u32,
+	xsdt_address: u64,
+	extended_checksum: u8,
+	_reserved: [u8; 3],
+}
+
+impl Rsdp {
+	/// This searches the first 1KiB of the EBDA then the BIOS area for the
+	/// RSDP and validates its checksums
+	fn find() -> Result<Self> {
+		let ebda =
+			unsafe { core::ptr::read(BDA_EBDA_SEGMENT as *const u16) as usize }
+				<< 4;
+		let ranges = [(ebda..ebda + 1024), (BIOS_AREA_START..BIOS_AREA_END)];
+
+		for range in ranges {
+			for addr in range.step_by(0x10) {
+				if get_mem(addr, RSDP_SIGNATURE.len()) != RSDP_SIGNATURE {
+					continue;
+				}
+				// A stray signature can turn up in the BIOS, keep looking if
+				// this one is not valid
+				if !checksum(get_mem(addr, RSDP_V1_LEN)) {
+					continue;
+				}
+				let rsdp: Self = read(get_mem(addr, size_of::<Self>()));
+				if rsdp.revision >= 2
+					&& !checksum(get_mem(addr, rsdp.length as usize))
+				{
+					continue;
+				}
+				return Ok(rsdp);
+			}
+		}
+		Err(Error::NoRsdpFound)
+	}
+}
+
+/// The header on the front of every System Description Table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+	signature: [u8; 4],
+	length: u32,
+	revision: u8,
+	checksum: u8,
+	oem_id: [u8; 6],
+	oem_table_id: [u8; 8],
+	oem_revision: u32,
+	creator_id: [u8; 4],
+	creator_revision: u32,
+}
+
+/// A System Description Table we have found in memory and validated
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+	addr: usize,
+	header: SdtHeader,
+}
+
+impl Table {
+	/// Reads the header at `addr` and validates the checksum over the whole
+	/// table, returns [`None`] if it is not valid
+	fn new(addr: usize) -> Option<Self> {
+		if addr == 0 {
+			return None;
+		}
+		let header: SdtHeader = read(get_mem(addr, SDT_HEADER_LEN));
+		if (header.length as usize) < SDT_HEADER_LEN
+			|| !checksum(get_mem(addr, header.length as usize))
+		{
+			return None;
+		}
+		Some(Self { addr, header })
+	}
+	/// The 4 byte signature such as `b"APIC"`
+	pub fn signature(&self) -> [u8; 4] {
+		self.header.signature
+	}
+	/// The revision of this table, newer revisions are longer
+	#[allow(dead_code)]
+	pub fn revision(&self) -> u8 {
+		self.header.revision
+	}
+	/// The whole table including the header
+	pub fn bytes(&self) -> &'static [u8] {
+		get_mem(self.addr, self.header.length as usize)
+	}
+	/// The table with the header stripped off
+	pub fn data(&self) -> &'static [u8] {
+		&self.bytes()[SDT_HEADER_LEN..]
+	}
+}
+
+impl core::fmt::Display for Table {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		use core::str::from_utf8;
+		let signature = self.header.signature;
+		let oem_id = self.header.oem_id;
+		let oem_table_id = self.header.oem_table_id;
+		let length = self.header.length;
+		write!(
+			f,
+			"{} at {:#X}, Len: {}, Rev: {}, OEM: {} {}",
+			from_utf8(&signature).unwrap_or("????"),
+			self.addr,
+			length,
+			self.header.revision,
+			from_utf8(&oem_id).unwrap_or("??????"),
+			from_utf8(&oem_table_id).unwrap_or("????????"),
+		)
+	}
+}
+
+/// The ACPI tables we found through the RSDT or XSDT
+#[derive(Debug, Clone, Copy)]
+pub struct Acpi {
+	revision: u8,
+	tables: [Option<Table>; MAX_TABLES],
+}
+
+impl Acpi {
+	/// Find the RSDP and walk the XSDT (ACPI 2.0+) or RSDT, collecting every
+	/// table with a valid checksum
+	fn new() -> Result<Self> {
+		let rsdp = Rsdp::find()?;
+		let mut acpi = Self {
+			revision: rsdp.revision,
+			tables: [None; MAX_TABLES],
+		};
+
+		// We are 32-bit so can only follow the XSDT if it is below 4GiB
+		let xsdt_address = rsdp.xsdt_address;
+		let (root, entry_len) = if rsdp.revision >= 2
+			&& xsdt_address != 0
+			&& xsdt_address <= !0u32 as u64
+		{
+			(xsdt_address as usize, size_of::<u64>())
+		} else {
+			(rsdp.rsdt_address as usize, size_of::<u32>())
+		};
+
+		let root = Table::new(root).ok_or(Error::BadAcpiTable(*b"RSDT"))?;
+		let mut found = 0;
+		for entry in root.data().chunks_exact(entry_len) {
+			let mut addr = [0u8; 8];
+			addr[..entry_len].copy_from_slice(entry);
+			let addr = u64::from_le_bytes(addr);
+			if addr > !0u32 as u64 {
+				continue;
+			}
+			let table = match Table::new(addr as usize) {
+				Some(table) => table,
+				None => {
+					print!("ACPI table at {:#X} failed checksum\n", addr);
+					continue;
+				}
+			};
+			if found == MAX_TABLES {
+				print!("Too many ACPI tables, ignoring: {}\n", table);
+				continue;
+			}
+			acpi.tables[found] = Some(table);
+			found += 1;
+		}
+		Ok(acpi)
+	}
+	/// Returns the first table with a matching signature, such as `b"APIC"`
+	pub fn find_table(&self, signature: &[u8; 4]) -> Option<Table> {
+		self
+			.tables
+			.iter()
+			.flatten()
+			.find(|table| &table.signature() == signature)
+			.copied()
+	}
+	/// The Fixed ACPI Description Table
+	pub fn fadt(&self) -> Option<Fadt> {
+		self.find_table(b"FACP").map(Fadt::new)
+	}
+	/// The Multiple APIC Description Table, [`None`] if it is missing or too
+	/// short
+	pub fn madt(&self) -> Option<Madt> {
+		self.find_table(b"APIC").and_then(Madt::new)
+	}
+	/// The High Precision Event Timer Table
+	pub fn hpet(&self) -> Option<Hpet> {
+		self.find_table(b"HPET").map(Hpet::new)
+	}
+	/// The PCI Express memory mapped configuration table, [`None`] if it is
+	/// missing or too short
+	pub fn mcfg(&self) -> Option<Mcfg> {
+		self.find_table(b"MCFG").and_then(Mcfg::new)
+	}
+}
+
+impl core::fmt::Display for Acpi {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "ACPI Revision: {}", self.revision)?;
+		for table in self.tables.iter().flatten() {
+			write!(f, "\n  {}", table)?;
+		}
+		Ok(())
+	}
+}
+
+/// ACPI Generic Address Structure, describes a register in memory, I/O or
+/// PCI configuration space
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+	pub address_space: u8,
+	pub bit_width: u8,
+	pub bit_offset: u8,
+	pub access_size: u8,
+	pub address: u64,
+}
+
+/// Fixed ACPI Description Table, signature `FACP`. Older revisions are shorter
+/// so fields past the table length are zeroed
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+	pub firmware_ctrl: u32,
+	pub dsdt: u32,
+	_reserved0: u8,
+	pub preferred_pm_profile: u8,
+	pub sci_int: u16,
+	pub smi_cmd: u32,
+	pub acpi_enable: u8,
+	pub acpi_disable: u8,
+	pub s4bios_req: u8,
+	pub pstate_cnt: u8,
+	pub pm1a_evt_blk: u32,
+	pub pm1b_evt_blk: u32,
+	pub pm1a_cnt_blk: u32,
+	pub pm1b_cnt_blk: u32,
+	pub pm2_cnt_blk: u32,
+	pub pm_tmr_blk: u32,
+	pub gpe0_blk: u32,
+	pub gpe1_blk: u32,
+	pub pm1_evt_len: u8,
+	pub pm1_cnt_len: u8,
+	pub pm2_cnt_len: u8,
+	pub pm_tmr_len: u8,
+	pub gpe0_blk_len: u8,
+	pub gpe1_blk_len: u8,
+	pub gpe1_base: u8,
+	pub cst_cnt: u8,
+	pub p_lvl2_lat: u16,
+	pub p_lvl3_lat: u16,
+	pub flush_size: u16,
+	pub flush_stride: u16,
+	pub duty_offset: u8,
+	pub duty_width: u8,
+	pub day_alrm: u8,
+	pub mon_alrm: u8,
+	pub century: u8,
+	pub iapc_boot_arch: u16,
+	_reserved1: u8,
+	pub flags: u32,
+	// ACPI 2.0+
+	pub reset_reg: GenericAddress,
+	pub reset_value: u8,
+	pub arm_boot_arch: u16,
+	pub minor_version: u8,
+	pub x_firmware_ctrl: u64,
+	pub x_dsdt: u64,
+	pub x_pm1a_evt_blk: GenericAddress,
+	pub x_pm1b_evt_blk: GenericAddress,
+	pub x_pm1a_cnt_blk: GenericAddress,
+	pub x_pm1b_cnt_blk: GenericAddress,
+	pub x_pm2_cnt_blk: GenericAddress,
+	pub x_pm_tmr_blk: GenericAddress,
+	pub x_gpe0_blk: GenericAddress,
+	pub x_gpe1_blk: GenericAddress,
+}
+
+impl Fadt {
+	fn new(table: Table) -> Self {
+		read(table.data())
+	}
+	/// The Differentiated System Description Table holds the AML for the
+	/// system, it is not listed in the RSDT so we find it through here
+	pub fn dsdt(&self) -> Option<Table> {
+		let x_dsdt = self.x_dsdt;
+		if x_dsdt != 0 && x_dsdt <= !0u32 as u64 {
+			Table::new(x_dsdt as usize)
+		} else {
+			Table::new(self.dsdt as usize)
+		}
+	}
+}
+
+/// The Local APIC address and flags come before the MADT entries
+const MADT_HEADER_LEN: usize = 8;
+
+/// Multiple APIC Description Table, signature `APIC`. We find the Local APIC
+/// through its MSR so the header fields are only here for completeness
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+	pub local_apic_address: u32,
+	pub flags: u32,
+	/// The variable length interrupt controller structures
+	entries: &'static [u8],
+}
+
+impl Madt {
+	/// Returns [`None`] if the table is too short for the fixed fields
+	fn new(table: Table) -> Option<Self> {
+		let data = table.data();
+		if data.len() < MADT_HEADER_LEN {
+			return None;
+		}
+		Some(Self {
+			local_apic_address: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+			flags: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+			entries: &data[MADT_HEADER_LEN..],
+		})
+	}
+	/// Iterate over the interrupt controller structures
+	pub fn entries(&self) -> MadtEntries {
+		MadtEntries {
+			bytes: self.entries,
+			ptr: 0,
+		}
+	}
+}
+
+/// MADT interrupt controller structure types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// The MADT interrupt controller structures we understand
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+	/// A processor and its Local APIC, bit 0 of `flags` is set if it is
+	/// enabled and bit 1 if it can be enabled
+	LocalApic {
+		processor_id: u8,
+		apic_id: u8,
+		flags: u32,
+	},
+	/// An I/O APIC and the first Global System Interrupt it handles
+	IoApic { id: u8, address: u32, gsi_base: u32 },
+	/// An ISA IRQ that is not identity mapped to a GSI, `flags` holds the
+	/// polarity (bits 0-1) and trigger mode (bits 2-3)
+	InterruptSourceOverride {
+		bus: u8,
+		source: u8,
+		gsi: u32,
+		flags: u16,
+	},
+	/// Which LINT pin of a processor is wired to NMI, 0xFF is all processors
+	LocalApicNmi {
+		processor_id: u8,
+		flags: u16,
+		lint: u8,
+	},
+	/// 64-bit address of the Local APIC replacing the one in the header
+	LocalApicAddressOverride { address: u64 },
+	/// A structure we dont parse, holds the type
+	Unknown(u8),
+}
+
+/// Iterator over the [`MadtEntry`]s of a [`Madt`]
+pub struct MadtEntries {
+	bytes: &'static [u8],
+	ptr: usize,
 }
 
+impl Iterator for MadtEntries {
+	type Item = MadtEntry;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Every structure starts with its type and length
+		let entry_type = *self.bytes.get(self.ptr)?;
+		let len = *self.bytes.get(self.ptr + 1)? as usize;
+		// A zero length would have us loop forever
+		if len < 2 {
+			return None;
+		}
+		let entry = self.bytes.get(self.ptr..self.ptr + len)?;
+		self.ptr += len;
+
+		let u16_at = |i: usize| u16::from_le_bytes([entry[i], entry[i + 1]]);
+		let u32_at = |i: usize| {
+			u32::from_le_bytes([
+				entry[i],
+				entry[i + 1],
+				entry[i + 2],
+				entry[i + 3],
+			])
+		};
+
+		Some(match (entry_type, len) {
+			(MADT_LOCAL_APIC, 8..) => MadtEntry::LocalApic {
+				processor_id: entry[2],
+				apic_id: entry[3],
+				flags: u32_at(4),
+			},
+			(MADT_IO_APIC, 12..) => MadtEntry::IoApic {
+				id: entry[2],
+				address: u32_at(4),
+				gsi_base: u32_at(8),
+			},
+			(MADT_INTERRUPT_SOURCE_OVERRIDE, 10..) => {
+				MadtEntry::InterruptSourceOverride {
+					bus: entry[2],
+					source: entry[3],
+					gsi: u32_at(4),
+					flags: u16_at(8),
+				}
+			}
+			(MADT_LOCAL_APIC_NMI, 6..) => MadtEntry::LocalApicNmi {
+				processor_id: entry[2],
+				flags: u16_at(3),
+				lint: entry[5],
+			},
+			(MADT_LOCAL_APIC_ADDRESS_OVERRIDE, 12..) => {
+				MadtEntry::LocalApicAddressOverride {
+					address: u32_at(4) as u64 | (u32_at(8) as u64) << 32,
+				}
+			}
+			(entry_type, _) => MadtEntry::Unknown(entry_type),
+		})
+	}
+}
+
+/// High Precision Event Timer Table, signature `HPET`
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Hpet {
+	pub event_timer_block_id: u32,
+	pub base_address: GenericAddress,
+	pub hpet_number: u8,
+	pub minimum_tick: u16,
+	pub page_protection: u8,
+}
+
+impl Hpet {
+	fn new(table: Table) -> Self {
+		read(table.data())
+	}
+}
+
+/// PCI Express memory mapped configuration table, signature `MCFG`
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+	entries: &'static [u8],
+}
+
+/// An ECAM region for a range of buses on a PCI segment
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+	pub base_address: u64,
+	pub segment: u16,
+	pub start_bus: u8,
+	pub end_bus: u8,
+	_reserved: u32,
+}
+
+impl Mcfg {
+	/// 8 reserved bytes come before the entries, returns [`None`] if the
+	/// table is too short to hold them
+	fn new(table: Table) -> Option<Self> {
+		Some(Self {
+			entries: table.data().get(8..)?,
+		})
+	}
+	/// Iterate over the ECAM regions
+	pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+		let (entries, _) = self.entries.as_chunks::<{ size_of::<McfgEntry>() }>();
+		entries.iter().map(|entry| read::<McfgEntry>(entry))
+	}
+}
+
+/// PM1 control register: SCI_EN is set once the system is in ACPI mode
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+/// PM1 control register: writing SLP_EN enters the sleep state in SLP_TYP
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+/// PM1 control register: SLP_TYP is bits 10-12
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+/// FADT flags: The reset register is supported
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+/// Generic address spaces we know how to write to
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+const ADDRESS_SPACE_PCI: u8 = 2;
+/// AML opcodes we need to find the `\_S5` package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+const AML_ONES_OP: u8 = 0xFF;
+/// Ports QEMU and Bochs power off on if we have no usable ACPI
+const QEMU_SHUTDOWN_PORTS: [(u16, u16); 2] =
+	[(0x604, 0x2000), (0xB004, 0x2000)];
+
+/// Finds the `\_S5` object in the DSDT AML and returns the SLP_TYPa and
+/// SLP_TYPb values from its package, we do not have an AML interpreter so we
+/// pattern match the bytes `NameOp "_S5_" PackageOp PkgLength NumElements`
+fn s5_sleep_type(aml: &[u8]) -> Option<(u16, u16)> {
+	let pos = aml.windows(4).position(|name| name == b"_S5_")?;
+	// The name is preceded by NameOp, optionally with a root `\` prefix
+	let name_op = match pos {
+		0 => return None,
+		1 => aml[0],
+		_ if aml[pos - 1] == b'\\' => aml[pos - 2],
+		_ => aml[pos - 1],
+	};
+	let mut ptr = pos + 4;
+	if name_op != AML_NAME_OP || *aml.get(ptr)? != AML_PACKAGE_OP {
+		return None;
+	}
+	ptr += 1;
+	// PkgLength, the top 2 bits of the first byte are how many bytes follow
+	ptr += 1 + (*aml.get(ptr)? >> 6) as usize;
+	// NumElements
+	ptr += 1;
+
+	let slp_typa = aml_integer(aml, &mut ptr)?;
+	let slp_typb = aml_integer(aml, &mut ptr)?;
+	Some((slp_typa as u16, slp_typb as u16))
+}
+
+/// Parses an AML integer constant at `ptr` and moves `ptr` past it
+fn aml_integer(aml: &[u8], ptr: &mut usize) -> Option<u32> {
+	let op = *aml.get(*ptr)?;
+	*ptr += 1;
+	let len = match op {
+		AML_ZERO_OP => return Some(0),
+		AML_ONE_OP => return Some(1),
+		AML_ONES_OP => return Some(!0),
+		AML_BYTE_PREFIX => 1,
+		AML_WORD_PREFIX => 2,
+		AML_DWORD_PREFIX => 4,
+		// Anything else is not an integer constant, like a method call
+		_ => return None,
+	};
+	let bytes = aml.get(*ptr..*ptr + len)?;
+	*ptr += len;
+	let mut val = [0u8; 4];
+	val[..len].copy_from_slice(bytes);
+	Some(u32::from_le_bytes(val))
+}
+
+/// Writes `val` to an ACPI Generic Address, returns false if it is in an
+/// address space we do not support
+fn write_generic_address(reg: GenericAddress, val: u8) -> bool {
+	let address = reg.address;
+	match reg.address_space {
+		ADDRESS_SPACE_MEMORY if address <= !0u32 as u64 => {
+			unsafe { core::ptr::write_volatile(address as usize as *mut u8, val) };
+			true
+		}
+		ADDRESS_SPACE_IO if address <= u16::MAX as u64 => {
+			cpu::out8(address as u16, val);
+			true
+		}
+		// Bus 0, device in bits 32-47, function in bits 16-31 and the register
+		// offset in bits 0-15
+		ADDRESS_SPACE_PCI => {
+			let device = (address >> 32) as u32 & 0x1F;
+			let function = (address >> 16) as u32 & 0x7;
+			let offset = address as u32 & 0xFF;
+			cpu::out32(
+				0xCF8,
+				1 << 31 | device << 11 | function << 8 | (offset & 0xFC),
+			);
+			cpu::out8(0xCFC + (offset & 3) as u16, val);
+			true
+		}
+		_ => false,
+	}
+}
+
+/// Switch from legacy mode into ACPI mode if the firmware has not already,
+/// the PM1 control writes are ignored until we do
+fn enable(fadt: &Fadt) {
+	let pm1a_cnt = fadt.pm1a_cnt_blk as u16;
+	if cpu::in16(pm1a_cnt) & PM1_CNT_SCI_EN != 0
+		|| fadt.smi_cmd == 0
+		|| fadt.acpi_enable == 0
+	{
+		return;
+	}
+	cpu::out8(fadt.smi_cmd as u16, fadt.acpi_enable);
+	// Give the firmware a bounded amount of time to hand over
+	for _ in 0..1_000_000 {
+		if cpu::in16(pm1a_cnt) & PM1_CNT_SCI_EN != 0 {
+			return;
+		}
+	}
+}
+
+/// Power the machine off by entering the ACPI S5 sleep state, falling back to
+/// the QEMU/Bochs shutdown ports and halting if that does not work
+pub fn shutdown() -> ! {
+	if let Some(fadt) = get().and_then(|acpi| acpi.fadt()) {
+		match fadt.dsdt().and_then(|dsdt| s5_sleep_type(dsdt.data())) {
+			Some((slp_typa, slp_typb)) if fadt.pm1a_cnt_blk != 0 => {
+				enable(&fadt);
+				cpu::out16(
+					fadt.pm1a_cnt_blk as u16,
+					slp_typa << PM1_CNT_SLP_TYP_SHIFT | PM1_CNT_SLP_EN,
+				);
+				if fadt.pm1b_cnt_blk != 0 {
+					cpu::out16(
+						fadt.pm1b_cnt_blk as u16,
+						slp_typb << PM1_CNT_SLP_TYP_SHIFT | PM1_CNT_SLP_EN,
+					);
+				}
+			}
+			_ => print!("No usable \\_S5 sleep state in the DSDT\n"),
+		}
+	}
+	for (port, val) in QEMU_SHUTDOWN_PORTS {
+		cpu::out16(port, val);
+	}
+	print!("ACPI shutdown failed, halting\n");
+	cpu::halt();
+}
+
+/// Reset the machine with the FADT reset register, falling back to pulsing
+/// the reset line on the keyboard controller and then a triple fault
+pub fn reboot() -> ! {
+	if let Some(fadt) = get().and_then(|acpi| acpi.fadt()) {
+		if fadt.flags & FADT_RESET_REG_SUP != 0 {
+			write_generic_address(fadt.reset_reg, fadt.reset_value);
+		}
+	}
+	// Wait for the keyboard controller input buffer to be empty then pulse
+	// the CPU reset line
+	for _ in 0..100_000 {
+		if cpu::in8(0x64) & 0x02 == 0 {
+			break;
+		}
+	}
+	cpu::out8(0x64, 0xFE);
+
+	cpu::triple_fault();
+}
+
+/// Find and validate the ACPI tables, we keep a copy for later use by [`get`]
+pub fn init() -> Result<Acpi> {
+	let acpi = Acpi::new()?;
+	unsafe { ACPI = Some(acpi) };
+	Ok(acpi)
+}
+
+/// The tables found by [`init`], [`None`] if it has not run or failed
+pub fn get() -> Option<Acpi> {
+	unsafe { ACPI }
+}
+
+/// ACPI checksums are valid when all the bytes sum to 0 mod 256
+fn checksum(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Reads a `T` from the start of `bytes`, anything past the end of `bytes`
+/// is left zeroed which is what ACPI wants for older shorter tables
+fn read<T: Copy>(bytes: &[u8]) -> T {
+	let len = bytes.len().min(size_of::<T>());
+	unsafe {
+		let mut val: T = core::mem::zeroed();
+		core::ptr::copy_nonoverlapping(
+			bytes.as_ptr(),
+			&mut val as *mut T as *mut u8,
+			len,
+		);
+		val
+	}
+}
+
+fn get_mem(addr: usize, len: usize) -> &'static [u8] {
+	unsafe { &*core::ptr::slice_from_raw_parts(addr as *const u8, len) }
+}
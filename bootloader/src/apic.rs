@@ -0,0 +1,458 @@
+//! This crate replaces the legacy PIC with the Local APIC and I/O APICs we find
+//! in the ACPI MADT. We mask the PIC, enable the Local APIC, route the ISA
+//! IRQs through the I/O APICs honouring the MADT overrides and calibrate the
+//! Local APIC timer against the PIT. We have no IDT yet so every route is left
+//! masked until a caller unmasks it
+use crate::acpi::{self, MadtEntry};
+use crate::cpu;
+use crate::cpuid;
+use crate::error::{Error, Result};
+use crate::pit;
+
+/// IA32_APIC_BASE: The APIC is globally enabled
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// IA32_APIC_BASE: The physical base address is bits 12-35
+const APIC_BASE_ADDR_MASK: u64 = 0xF_FFFF_F000;
+
+/// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Spurious Vector Register: Software enable the APIC
+const SVR_ENABLE: u32 = 1 << 8;
+/// Local vector table: Masked
+const LVT_MASKED: u32 = 1 << 16;
+/// Local vector table: Timer is periodic instead of one shot
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Local vector table: Deliver as NMI
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+/// Local vector table: Active low
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+/// Local vector table: Level triggered
+const LVT_LEVEL: u32 = 1 << 15;
+/// Interrupt Command Register: Delivery mode INIT
+pub const ICR_INIT: u32 = 0b101 << 8;
+/// Interrupt Command Register: Delivery mode Start Up, the low byte is the
+/// page the processor starts executing at
+pub const ICR_STARTUP: u32 = 0b110 << 8;
+/// Interrupt Command Register: Level assert, required for everything but
+/// INIT de-assert
+pub const ICR_ASSERT: u32 = 1 << 14;
+/// Interrupt Command Register: The IPI has not been accepted yet
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// Timer divide configuration for divide by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// How long we count the Local APIC timer against the PIT for
+const CALIBRATION_MS: u32 = 10;
+
+/// Vector for spurious interrupts, the low 4 bits must be set on older CPUs
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// ISA IRQ `n` is routed to vector `ISA_VECTOR_BASE + n`, the same place we
+/// remap the PIC so a spurious PIC IRQ looks the same
+pub const ISA_VECTOR_BASE: u8 = 0x20;
+
+/// I/O APIC register select and data window
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WIN: usize = 0x10;
+/// I/O APIC registers
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION: u32 = 0x10;
+
+/// Redirection entry: Active low
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+/// Redirection entry: Level triggered
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+/// Redirection entry: Masked
+const REDIRECTION_MASKED: u64 = 1 << 16;
+/// Redirection entry: The destination APIC ID is bits 56-63
+const REDIRECTION_DEST_SHIFT: u64 = 56;
+
+/// MPS INTI flags: Polarity is bits 0-1, trigger mode bits 2-3
+const MPS_POLARITY_MASK: u16 = 0b0011;
+const MPS_POLARITY_LOW: u16 = 0b0011;
+const MPS_TRIGGER_MASK: u16 = 0b1100;
+const MPS_TRIGGER_LEVEL: u16 = 0b1100;
+
+/// The legacy PIC ports
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+/// Most I/O APICs we keep track of
+const MAX_IO_APICS: usize = 4;
+/// Number of ISA IRQs
+const ISA_IRQS: usize = 16;
+
+/// Once we `init()` we keep a copy here for drivers that need to route
+/// interrupts
+static mut APIC: Option<Apic> = None;
+
+/// The Local APIC of the processor we are running on
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+	base: usize,
+	/// Timer ticks per millisecond at divide by 16, found by [`calibrate`]
+	ticks_per_ms: u32,
+}
+
+impl LocalApic {
+	fn read(&self, reg: usize) -> u32 {
+		unsafe { core::ptr::read_volatile((self.base + reg) as *const u32) }
+	}
+	fn write(&self, reg: usize, val: u32) {
+		unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, val) }
+	}
+	/// Globally enable the Local APIC in the MSR then software enable it with
+	/// our spurious vector. LINT0/1 are the virtual wire to the PIC on the BSP
+	/// so we leave ExtINT masked and NMI on LINT1 as per the MADT default. Each
+	/// processor has to do this for its own Local APIC
+	pub fn enable(&self, nmi_lint: Option<(u8, u16)>) {
+		let base = cpu::rdmsr(cpu::IA32_APIC_BASE);
+		cpu::wrmsr(cpu::IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+
+		self.write(LAPIC_TPR, 0);
+		self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+		self.write(LAPIC_LVT_LINT1, LVT_MASKED);
+		if let Some((lint, flags)) = nmi_lint {
+			let mut lvt = LVT_DELIVERY_NMI;
+			if flags & MPS_POLARITY_MASK == MPS_POLARITY_LOW {
+				lvt |= LVT_ACTIVE_LOW;
+			}
+			if flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+				lvt |= LVT_LEVEL;
+			}
+			let reg = if lint == 0 {
+				LAPIC_LVT_LINT0
+			} else {
+				LAPIC_LVT_LINT1
+			};
+			self.write(reg, lvt);
+		}
+		self.write(LAPIC_LVT_ERROR, LVT_MASKED);
+		self.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+	}
+	/// Count the timer down against a [`CALIBRATION_MS`] PIT wait
+	fn calibrate(&mut self) {
+		self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+		self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+		self.write(LAPIC_TIMER_INITIAL, !0);
+
+		pit::wait_us(CALIBRATION_MS * 1000);
+
+		let elapsed = !0 - self.read(LAPIC_TIMER_CURRENT);
+		self.write(LAPIC_TIMER_INITIAL, 0);
+		self.ticks_per_ms = elapsed / CALIBRATION_MS;
+	}
+	/// The APIC ID of this processor
+	pub fn id(&self) -> u8 {
+		(self.read(LAPIC_ID) >> 24) as u8
+	}
+	/// Timer ticks per millisecond at divide by 16
+	#[allow(dead_code)]
+	pub fn ticks_per_ms(&self) -> u32 {
+		self.ticks_per_ms
+	}
+	/// Send an Inter-Processor Interrupt, `icr` is the low dword of the
+	/// Interrupt Command Register, we wait for it to be delivered
+	pub fn send_ipi(&self, dest: u8, icr: u32) {
+		self.write(LAPIC_ICR_HIGH, (dest as u32) << 24);
+		self.write(LAPIC_ICR_LOW, icr);
+		while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+			core::hint::spin_loop();
+		}
+	}
+	/// Signal the end of an interrupt
+	#[allow(dead_code)]
+	pub fn eoi(&self) {
+		self.write(LAPIC_EOI, 0);
+	}
+	/// Start the timer raising `vector` after `ms`, repeating if `periodic`
+	#[allow(dead_code)]
+	pub fn start_timer(&self, ms: u32, vector: u8, periodic: bool) {
+		let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+		self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+		self.write(LAPIC_LVT_TIMER, mode | vector as u32);
+		self.write(LAPIC_TIMER_INITIAL, ms.saturating_mul(self.ticks_per_ms));
+	}
+	/// Stop the timer
+	#[allow(dead_code)]
+	pub fn stop_timer(&self) {
+		self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+		self.write(LAPIC_TIMER_INITIAL, 0);
+	}
+	/// Ticks left before the timer fires, lets us use it without an IDT
+	#[allow(dead_code)]
+	pub fn timer_current(&self) -> u32 {
+		self.read(LAPIC_TIMER_CURRENT)
+	}
+}
+
+/// An I/O APIC, handles the GSIs from `gsi_base` for `redirections` entries
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+	id: u8,
+	base: usize,
+	gsi_base: u32,
+	redirections: u32,
+}
+
+impl IoApic {
+	fn new(id: u8, base: usize, gsi_base: u32) -> Self {
+		let mut io_apic = Self {
+			id,
+			base,
+			gsi_base,
+			redirections: 0,
+		};
+		// Max redirection entry is bits 16-23 of the version register
+		io_apic.redirections =
+			(io_apic.read(IOAPIC_REG_VERSION) >> 16 & 0xFF) + 1;
+		io_apic
+	}
+	fn read(&self, reg: u32) -> u32 {
+		unsafe {
+			core::ptr::write_volatile(
+				(self.base + IOAPIC_REGSEL) as *mut u32,
+				reg,
+			);
+			core::ptr::read_volatile((self.base + IOAPIC_WIN) as *const u32)
+		}
+	}
+	fn write(&self, reg: u32, val: u32) {
+		unsafe {
+			core::ptr::write_volatile(
+				(self.base + IOAPIC_REGSEL) as *mut u32,
+				reg,
+			);
+			core::ptr::write_volatile((self.base + IOAPIC_WIN) as *mut u32, val);
+		}
+	}
+	/// Does this I/O APIC handle `gsi`
+	fn handles(&self, gsi: u32) -> bool {
+		(self.gsi_base..self.gsi_base + self.redirections).contains(&gsi)
+	}
+	/// Write the 64-bit redirection entry for `gsi`
+	fn set_redirection(&self, gsi: u32, entry: u64) {
+		let reg = IOAPIC_REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+		// Mask first so we never have a half written entry live
+		self.write(reg, REDIRECTION_MASKED as u32);
+		self.write(reg + 1, (entry >> 32) as u32);
+		self.write(reg, entry as u32);
+	}
+}
+
+/// An ISA IRQ that the MADT says is wired to a different GSI or polarity
+#[derive(Debug, Clone, Copy)]
+struct Override {
+	gsi: u32,
+	flags: u16,
+}
+
+/// The interrupt controllers we found in the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct Apic {
+	pub local: LocalApic,
+	io_apics: [Option<IoApic>; MAX_IO_APICS],
+	isa_overrides: [Option<Override>; ISA_IRQS],
+}
+
+impl Apic {
+	/// Route the GSI to `vector` on the processor `dest`, honouring the
+	/// polarity and trigger mode from the MPS INTI `flags`
+	pub fn route_gsi(
+		&self,
+		gsi: u32,
+		flags: u16,
+		vector: u8,
+		dest: u8,
+		masked: bool,
+	) -> Result<()> {
+		let io_apic = self
+			.io_apics
+			.iter()
+			.flatten()
+			.find(|io_apic| io_apic.handles(gsi))
+			.ok_or(Error::NoIoApicForGsi(gsi))?;
+
+		let mut entry = vector as u64 | (dest as u64) << REDIRECTION_DEST_SHIFT;
+		if flags & MPS_POLARITY_MASK == MPS_POLARITY_LOW {
+			entry |= REDIRECTION_ACTIVE_LOW;
+		}
+		if flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+			entry |= REDIRECTION_LEVEL;
+		}
+		if masked {
+			entry |= REDIRECTION_MASKED;
+		}
+		io_apic.set_redirection(gsi, entry);
+		Ok(())
+	}
+	/// Route ISA IRQ `irq` to vector [`ISA_VECTOR_BASE`] + `irq` on this
+	/// processor, ISA IRQs are identity mapped to GSIs unless overridden
+	pub fn route_isa_irq(&self, irq: u8, masked: bool) -> Result<()> {
+		let (gsi, flags) = match self.isa_overrides.get(irq as usize) {
+			Some(Some(iso)) => (iso.gsi, iso.flags),
+			_ => (irq as u32, 0),
+		};
+		self.route_gsi(gsi, flags, ISA_VECTOR_BASE + irq, self.local.id(), masked)
+	}
+	/// True if ISA IRQ `irq` has no override but another ISA IRQ is overridden
+	/// onto its GSI, like IRQ0 onto GSI2 on QEMU, so its identity route would
+	/// take the GSI from that IRQ
+	fn isa_gsi_taken(&self, irq: u8) -> bool {
+		self.isa_overrides[irq as usize].is_none()
+			&& self
+				.isa_overrides
+				.iter()
+				.flatten()
+				.any(|iso| iso.gsi == irq as u32)
+	}
+}
+
+impl core::fmt::Display for Apic {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"LAPIC ID: {} at {:#X}, Timer: {} ticks/ms",
+			self.local.id(),
+			self.local.base,
+			self.local.ticks_per_ms,
+		)?;
+		for io_apic in self.io_apics.iter().flatten() {
+			write!(
+				f,
+				"\n  IOAPIC ID: {} at {:#X}, GSIs {}-{}",
+				io_apic.id,
+				io_apic.base,
+				io_apic.gsi_base,
+				io_apic.gsi_base + io_apic.redirections - 1,
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// Remap the PIC away from the CPU exceptions then mask every line, it stays
+/// wired up but will never raise anything
+fn disable_pic() {
+	// ICW1: Initialise, ICW4 needed
+	cpu::out8(PIC1_COMMAND, 0x11);
+	cpu::out8(PIC2_COMMAND, 0x11);
+	// ICW2: Vector offsets
+	cpu::out8(PIC1_DATA, ISA_VECTOR_BASE);
+	cpu::out8(PIC2_DATA, ISA_VECTOR_BASE + 8);
+	// ICW3: Slave is on IRQ2 of the master, slave cascade identity is 2
+	cpu::out8(PIC1_DATA, 1 << 2);
+	cpu::out8(PIC2_DATA, 2);
+	// ICW4: 8086 mode
+	cpu::out8(PIC1_DATA, 0x01);
+	cpu::out8(PIC2_DATA, 0x01);
+	// Mask everything
+	cpu::out8(PIC1_DATA, 0xFF);
+	cpu::out8(PIC2_DATA, 0xFF);
+}
+
+/// Parse the MADT, disable the PIC and bring up the Local APIC of this
+/// processor and the I/O APICs with every ISA IRQ routed but masked
+pub fn init() -> Result<Apic> {
+	let features = cpuid::features();
+	if !features.apic || !features.msr {
+		return Err(Error::NoLocalApic);
+	}
+	let madt = acpi::get()
+		.and_then(|acpi| acpi.madt())
+		.ok_or(Error::NoMadt)?;
+
+	let mut apic = Apic {
+		local: LocalApic {
+			base: (cpu::rdmsr(cpu::IA32_APIC_BASE) & APIC_BASE_ADDR_MASK) as usize,
+			ticks_per_ms: 0,
+		},
+		io_apics: [None; MAX_IO_APICS],
+		isa_overrides: [None; ISA_IRQS],
+	};
+
+	let mut io_apics = 0;
+	for entry in madt.entries() {
+		match entry {
+			MadtEntry::IoApic {
+				id,
+				address,
+				gsi_base,
+			} => {
+				if io_apics == MAX_IO_APICS {
+					print!("Too many IOAPICs, ignoring ID: {}\n", id);
+					continue;
+				}
+				apic.io_apics[io_apics] =
+					Some(IoApic::new(id, address as usize, gsi_base));
+				io_apics += 1;
+			}
+			MadtEntry::InterruptSourceOverride {
+				bus: 0,
+				source,
+				gsi,
+				flags,
+			} if (source as usize) < ISA_IRQS => {
+				apic.isa_overrides[source as usize] = Some(Override { gsi, flags });
+			}
+			_ => {}
+		}
+	}
+
+	// We only have the BSP up so only care about NMI entries for it or all
+	// processors, they refer to the ACPI processor ID not the APIC ID
+	let bsp_id = apic.local.id();
+	let bsp_processor = madt.entries().find_map(|entry| match entry {
+		MadtEntry::LocalApic {
+			processor_id,
+			apic_id,
+			..
+		} if apic_id == bsp_id => Some(processor_id),
+		_ => None,
+	});
+	let nmi_lint = madt.entries().find_map(|entry| match entry {
+		MadtEntry::LocalApicNmi {
+			processor_id,
+			flags,
+			lint,
+		} if processor_id == 0xFF || Some(processor_id) == bsp_processor => {
+			Some((lint, flags))
+		}
+		_ => None,
+	});
+
+	disable_pic();
+	apic.local.enable(nmi_lint);
+	apic.local.calibrate();
+
+	for irq in 0..ISA_IRQS as u8 {
+		if apic.isa_gsi_taken(irq) {
+			continue;
+		}
+		// Some ISA IRQs are not connected on every system, not fatal
+		if let Err(e) = apic.route_isa_irq(irq, true) {
+			print!("Cannot route ISA IRQ {}: {:X?}\n", irq, e);
+		}
+	}
+
+	unsafe { APIC = Some(apic) };
+	Ok(apic)
+}
+
+/// The APICs set up by [`init`], [`None`] if it has not run or failed
+pub fn get() -> Option<Apic> {
+	unsafe { APIC }
+}
@@ -1,6 +1,8 @@
 //! This is the hacky stuff we do to let the rust compiler compile in
 //! `#![no_std]` with [`i586-pc-windows-msvc`], not written by myself for the
 //! most part.
+use core::arch::global_asm;
+
 /// Whether or not floats are used. This is used by the MSVC calling convention
 /// and it just has to exist.
 #[export_name = "_fltused"]
@@ -12,6 +14,7 @@ pub static FLTUSED: usize = 0;
 /// * `dest` - Pointer to memory to copy to
 /// * `src`  - Pointer to memory to copy from
 /// * `n`    - Number of bytes to copy
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn memmove(
 	dest: *mut u8,
@@ -46,6 +49,7 @@ pub unsafe extern "C" fn memmove(
 /// * `dest` - Pointer to memory to copy to
 /// * `src`  - Pointer to memory to copy from
 /// * `n`    - Number of bytes to copy
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn memcpy(
 	dest: *mut u8,
@@ -61,6 +65,7 @@ pub unsafe extern "C" fn memcpy(
 /// * `s1` - Pointer to memory to compare with s2
 /// * `s2` - Pointer to memory to compare with s1
 /// * `n`  - Number of bytes to set
+#[cfg(not(test))]
 #[no_mangle]
 unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
 	let mut ii = 0;
@@ -81,6 +86,7 @@ unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
 /// * `s` - Pointer to memory to set
 /// * `c` - Character to set `n` bytes in `s` to
 /// * `n` - Number of bytes to set
+#[cfg(not(test))]
 #[no_mangle]
 unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
 	let mut i = 0;
@@ -90,17 +96,96 @@ unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
 	}
 	s
 }
-/// [https://source.winehq.org/WineAPI/_aulldiv.html](https://source.winehq.org/WineAPI/_aulldiv.html)
-/// No idea why I need this!
-#[no_mangle]
-unsafe extern "C" fn _aulldiv(_a: usize, _b: usize) -> usize {
-	0
+// [https://source.winehq.org/WineAPI/_aulldiv.html](https://source.winehq.org/WineAPI/_aulldiv.html)
+// LLVM calls these for 64-bit division on 32-bit MSVC targets. They are
+// stdcall so the callee pops the two u64 arguments, we re-push them and call
+// the cdecl Rust implementations below which return in edx:eax
+#[cfg(not(test))]
+global_asm!(
+	".globl __aulldiv",
+	"__aulldiv:",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"call {udiv}",
+	"add esp, 16",
+	"ret 16",
+	".globl __aullrem",
+	"__aullrem:",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"call {urem}",
+	"add esp, 16",
+	"ret 16",
+	".globl __alldiv",
+	"__alldiv:",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"call {idiv}",
+	"add esp, 16",
+	"ret 16",
+	".globl __allrem",
+	"__allrem:",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"push dword ptr [esp + 16]",
+	"call {irem}",
+	"add esp, 16",
+	"ret 16",
+	udiv = sym aulldiv,
+	urem = sym aullrem,
+	idiv = sym alldiv,
+	irem = sym allrem,
+);
+
+/// Unsigned 64-bit division returning (quotient, remainder). We cannot use
+/// `/` on a u64 in here as that is what calls us, so when either side needs
+/// more than 32 bits we do binary long division
+fn udivmod64(n: u64, d: u64) -> (u64, u64) {
+	if n >> 32 == 0 && d >> 32 == 0 {
+		let (n, d) = (n as u32, d as u32);
+		return ((n / d) as u64, (n % d) as u64);
+	}
+	let mut quotient = 0u64;
+	let mut remainder = 0u64;
+	for bit in (0..64).rev() {
+		remainder = (remainder << 1) | ((n >> bit) & 1);
+		if remainder >= d {
+			remainder -= d;
+			quotient |= 1 << bit;
+		}
+	}
+	(quotient, remainder)
 }
-/// [https://source.winehq.org/WineAPI/_aullrem.html](https://source.winehq.org/WineAPI/_aullrem.html)
-/// No idea why I need this!
-#[no_mangle]
-unsafe extern "C" fn _aullrem(_a: usize, _b: usize) -> usize {
-	0
+extern "C" fn aulldiv(n: u64, d: u64) -> u64 {
+	udivmod64(n, d).0
+}
+extern "C" fn aullrem(n: u64, d: u64) -> u64 {
+	udivmod64(n, d).1
+}
+/// The quotient is negative if the signs differ, the remainder takes the sign
+/// of the dividend
+extern "C" fn alldiv(n: i64, d: i64) -> i64 {
+	let quotient = udivmod64(n.unsigned_abs(), d.unsigned_abs()).0 as i64;
+	if (n < 0) != (d < 0) {
+		quotient.wrapping_neg()
+	} else {
+		quotient
+	}
+}
+extern "C" fn allrem(n: i64, d: i64) -> i64 {
+	let remainder = udivmod64(n.unsigned_abs(), d.unsigned_abs()).1 as i64;
+	if n < 0 {
+		remainder.wrapping_neg()
+	} else {
+		remainder
+	}
 }
 
 /// No idea why I need this!
@@ -109,7 +194,96 @@ unsafe extern "C" fn __CxxFrameHandler3() {
 	unreachable!()
 }
 
-/// Checks if stack is too large, we need to overwrite this for our
-/// allocationless OS Remove this if we add an allocator!
-#[no_mangle]
-unsafe extern "C" fn _chkstk() {}
+// [https://learn.microsoft.com/en-us/windows/win32/devnotes/-win32-chkstk](https://learn.microsoft.com/en-us/windows/win32/devnotes/-win32-chkstk)
+// LLVM calls this with the frame size in eax before any function whose frame
+// is bigger than a page. It has nothing to do with the heap, on 32-bit it has
+// to touch each page of the new frame in order and move esp down itself as
+// the caller will not. eax and ecx are scratch, everything else is preserved.
+// Before probing we ask `stack` how low the caller's stack may go and panic
+// naming the caller instead of running into the canary region
+#[cfg(not(test))]
+global_asm!(
+	".globl __chkstk",
+	"__chkstk:",
+	"push ecx",
+	"push edx",
+	"push eax",
+	"lea ecx, [esp + 16]",
+	"push ecx",
+	"call {limit}",
+	"add esp, 4",
+	// ecx is where the new frame would take esp, a borrow means it wrapped
+	"lea ecx, [esp + 16]",
+	"sub ecx, dword ptr [esp]",
+	"jb 4f",
+	"cmp ecx, eax",
+	"jb 4f",
+	"pop eax",
+	"pop edx",
+	// ecx is the caller's esp from before it called us
+	"lea ecx, [esp + 8]",
+	"2:",
+	"cmp eax, 0x1000",
+	"jb 3f",
+	"sub ecx, 0x1000",
+	"test dword ptr [ecx], eax",
+	"sub eax, 0x1000",
+	"jmp 2b",
+	"3:",
+	"sub ecx, eax",
+	"test dword ptr [ecx], eax",
+	// Move to the new stack and return from there, the saved ecx and return
+	// address are above it inside the new frame
+	"mov eax, esp",
+	"mov esp, ecx",
+	"mov ecx, dword ptr [eax]",
+	"push dword ptr [eax + 4]",
+	"ret",
+	// chkstk_overflow(return address into the caller, new esp)
+	"4:",
+	"push ecx",
+	"push dword ptr [esp + 16]",
+	"call {overflow}",
+	limit = sym crate::stack::chkstk_limit,
+	overflow = sym crate::stack::chkstk_overflow,
+);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Both halves of the fast path and the long division against `/` and `%`
+	#[test]
+	fn udivmod64_matches_native() {
+		let values = [
+			0,
+			1,
+			2,
+			7,
+			0xFFFF_FFFF,
+			0x1_0000_0000,
+			0x1_0000_0001,
+			1_000_000_000,
+			0x0123_4567_89AB_CDEF,
+			0x8000_0000_0000_0000,
+			u64::MAX - 1,
+			u64::MAX,
+		];
+		for &n in &values {
+			for &d in values.iter().filter(|&&d| d != 0) {
+				assert_eq!(udivmod64(n, d), (n / d, n % d), "{} / {}", n, d);
+			}
+		}
+	}
+
+	#[test]
+	fn signed_division_rounds_towards_zero() {
+		for (n, d) in [(7, 2), (-7, 2), (7, -2), (-7, -2), (i64::MIN, 3)] {
+			assert_eq!(alldiv(n, d), n / d, "{} / {}", n, d);
+			assert_eq!(allrem(n, d), n % d, "{} % {}", n, d);
+		}
+		assert_eq!(alldiv(i64::MIN, -1), i64::MIN);
+		assert_eq!(aulldiv(u64::MAX, 10), u64::MAX / 10);
+		assert_eq!(aullrem(u64::MAX, 10), u64::MAX % 10);
+	}
+}
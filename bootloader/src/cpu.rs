@@ -21,6 +21,12 @@ pub fn out8(addr: u16, val: u8) {
 	}
 }
 #[inline]
+pub fn out16(addr: u16, val: u16) {
+	unsafe {
+		asm!("out dx, ax", in("dx") addr, in("ax") val);
+	}
+}
+#[inline]
 pub fn out32(addr: u16, val: u32) {
 	unsafe {
 		asm!("out dx, eax", in("dx") addr, in("eax") val);
@@ -36,6 +42,14 @@ pub fn in8(addr: u16) -> u8 {
 	val
 }
 #[inline]
+pub fn in16(addr: u16) -> u16 {
+	let val: u16;
+	unsafe {
+		asm!("in ax, dx", in("dx") addr, out("ax") val);
+	}
+	val
+}
+#[inline]
 pub fn in32(addr: u16) -> u32 {
 	let val: u32;
 	unsafe {
@@ -43,12 +57,103 @@ pub fn in32(addr: u16) -> u32 {
 	}
 	val
 }
+/// The MSR that holds the Local APIC base address and enable bits
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// [https://www.felixcloutier.com/x86/rdmsr](https://www.felixcloutier.com/x86/rdmsr)
+#[inline]
+pub fn rdmsr(msr: u32) -> u64 {
+	let (high, low): (u32, u32);
+	unsafe {
+		asm!("rdmsr", in("ecx") msr, out("edx") high, out("eax") low);
+	}
+	(high as u64) << 32 | low as u64
+}
+/// [https://www.felixcloutier.com/x86/wrmsr](https://www.felixcloutier.com/x86/wrmsr)
+#[inline]
+pub fn wrmsr(msr: u32, val: u64) {
+	unsafe {
+		asm!(
+			"wrmsr",
+			in("ecx") msr,
+			in("edx") (val >> 32) as u32,
+			in("eax") val as u32,
+		);
+	}
+}
+/// Returns (eax, ebx, ecx, edx) for a CPUID `leaf` and `subleaf`, LLVM
+/// reserves ebx so we use the intrinsic that saves it for us
+/// [https://www.felixcloutier.com/x86/cpuid](https://www.felixcloutier.com/x86/cpuid)
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+	#[cfg(target_arch = "x86")]
+	use core::arch::x86::__cpuid_count;
+	#[cfg(target_arch = "x86_64")]
+	use core::arch::x86_64::__cpuid_count;
+
+	#[allow(unused_unsafe)]
+	let result = unsafe { __cpuid_count(leaf, subleaf) };
+	(result.eax, result.ebx, result.ecx, result.edx)
+}
+/// The MSR that enables long mode
+pub const IA32_EFER: u32 = 0xC000_0080;
+/// [https://www.felixcloutier.com/x86/mov-1](https://www.felixcloutier.com/x86/mov-1)
+#[inline]
+pub fn read_cr0() -> usize {
+	let val;
+	unsafe { asm!("mov {}, cr0", out(reg) val) };
+	val
+}
+#[inline]
+pub fn write_cr0(val: usize) {
+	unsafe { asm!("mov cr0, {}", in(reg) val) };
+}
+#[inline]
+pub fn read_cr3() -> usize {
+	let val;
+	unsafe { asm!("mov {}, cr3", out(reg) val) };
+	val
+}
+#[inline]
+pub fn write_cr3(val: usize) {
+	unsafe { asm!("mov cr3, {}", in(reg) val) };
+}
+#[inline]
+pub fn read_cr4() -> usize {
+	let val;
+	unsafe { asm!("mov {}, cr4", out(reg) val) };
+	val
+}
+#[inline]
+pub fn write_cr4(val: usize) {
+	unsafe { asm!("mov cr4, {}", in(reg) val) };
+}
+/// Flush the TLB entry for the page holding `addr`
+/// [https://www.felixcloutier.com/x86/invlpg](https://www.felixcloutier.com/x86/invlpg)
+#[inline]
+pub fn invlpg(addr: usize) {
+	unsafe { asm!("invlpg [{}]", in(reg) addr) };
+}
+/// Reset the processor by loading an empty IDT and raising an interrupt,
+/// with no handler to go to we double then triple fault
+pub fn triple_fault() -> ! {
+	let idtr = [0u16; 3];
+	unsafe {
+		asm!("lidt [{}]", "int3", in(reg) &idtr);
+	}
+	halt();
+}
 /// `<https://wiki.osdev.org/CMOS>`
 #[inline]
 pub fn rtc_register(offset: u8) -> u8 {
 	out8(0x70, offset);
 	in8(0x71)
 }
+/// Write a value to a CMOS register, see [`rtc_register`]
+#[inline]
+pub fn rtc_write(offset: u8, val: u8) {
+	out8(0x70, offset);
+	out8(0x71, val);
+}
 /// Get the current stack pointer
 #[inline]
 #[allow(unused_assignments)]
@@ -59,21 +164,51 @@ pub fn esp() -> u32 {
 		x
 	}
 }
-// /// Does not work well on 32 bit, printing hangs on 64 bits
-// #[inline]
-// pub fn rdtsc() -> u64 {
-//     let mut h: u32 = 0;
-//     let mut l: u32 = 0;
-//     unsafe {
-//         asm!(
-//             "rdtsc",
-//             out("edx") h,
-//             out("eax") l
-//         )
-//     }
-//     // Hacky work around for 32 bit
-//     (h  as u64) << 32 | l as u64
-// }
+/// Get the current frame pointer, only meaningful when built with frame
+/// pointers which `.cargo/config.toml` forces
+#[inline]
+pub fn ebp() -> u32 {
+	let val;
+	unsafe { asm!("mov {:e}, ebp", out(reg) val) };
+	val
+}
+/// Get the flags register
+/// [https://www.felixcloutier.com/x86/pushf:pushfd:pushfq](https://www.felixcloutier.com/x86/pushf:pushfd:pushfq)
+#[inline]
+pub fn eflags() -> u32 {
+	let val;
+	unsafe { asm!("pushfd", "pop {:e}", out(reg) val) };
+	val
+}
+/// The address of the last page fault
+#[inline]
+pub fn read_cr2() -> usize {
+	let val;
+	unsafe { asm!("mov {}, cr2", out(reg) val) };
+	val
+}
+/// Read the time stamp counter, check [`crate::cpuid::Features::tsc`] first
+/// [https://www.felixcloutier.com/x86/rdtsc](https://www.felixcloutier.com/x86/rdtsc)
+#[inline]
+pub fn rdtsc() -> u64 {
+	let (high, low): (u32, u32);
+	unsafe {
+		asm!("rdtsc", out("edx") high, out("eax") low);
+	}
+	(high as u64) << 32 | low as u64
+}
+/// One hardware random number, [`None`] if the DRNG ran dry which the SDM
+/// says callers should retry a few times, check
+/// [`crate::cpuid::Features::rdrand`] first
+/// [https://www.felixcloutier.com/x86/rdrand](https://www.felixcloutier.com/x86/rdrand)
+#[inline]
+pub fn rdrand() -> Option<u32> {
+	let (val, ok): (u32, u8);
+	unsafe {
+		asm!("rdrand {0:e}", "setc {1}", out(reg) val, out(reg_byte) ok);
+	}
+	(ok != 0).then_some(val)
+}
 // /// Sleep for the specified cycle count
 // pub fn sleep(cycles: u64){
 //     let start = rdtsc();
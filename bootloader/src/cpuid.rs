@@ -0,0 +1,212 @@
+//! This crate asks the processor what it supports with CPUID so drivers can
+//! pick a code path instead of assuming, the result is read once at boot and
+//! printed as a summary. The leaves are documented in the Intel SDM volume 2A
+//! `<https://wiki.osdev.org/CPUID>`
+use crate::cpu;
+
+/// Leaf 0x0: Highest basic leaf and the vendor string
+const LEAF_VENDOR: u32 = 0x0;
+/// Leaf 0x1: Family/model/stepping and the basic feature flags
+const LEAF_FEATURES: u32 = 0x1;
+/// Leaf 0x8000_0000: Highest extended leaf
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+/// Leaf 0x8000_0001: Extended feature flags
+const LEAF_EXTENDED_FEATURES: u32 = 0x8000_0001;
+/// Leaves 0x8000_0002-0x8000_0004: 16 bytes of the brand string each
+const LEAF_BRAND: u32 = 0x8000_0002;
+/// Leaf 0x8000_0007: Advanced power management
+const LEAF_POWER: u32 = 0x8000_0007;
+
+/// Leaf 0x1 EDX bits
+const EDX_PSE: u32 = 1 << 3;
+const EDX_TSC: u32 = 1 << 4;
+const EDX_MSR: u32 = 1 << 5;
+const EDX_PAE: u32 = 1 << 6;
+const EDX_APIC: u32 = 1 << 9;
+/// Leaf 0x1 ECX bits
+const ECX_X2APIC: u32 = 1 << 21;
+const ECX_RDRAND: u32 = 1 << 30;
+/// Leaf 0x8000_0001 EDX: Long mode
+const EDX_LONG_MODE: u32 = 1 << 29;
+/// Leaf 0x8000_0007 EDX: The TSC runs at a constant rate in every C/P state
+const EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Read once by [`init`]
+static mut CPUID: Option<Cpuid> = None;
+
+/// The feature flags the rest of the OS cares about
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Features {
+	/// `rdtsc` is available
+	pub tsc: bool,
+	/// `rdmsr`/`wrmsr` are available
+	pub msr: bool,
+	/// 4MiB pages in 32-bit paging
+	pub pse: bool,
+	/// Physical Address Extension, needed for long mode
+	pub pae: bool,
+	/// There is a Local APIC
+	pub apic: bool,
+	/// The Local APIC can be driven through MSRs
+	pub x2apic: bool,
+	/// 64-bit long mode
+	pub long_mode: bool,
+	/// The TSC ticks at a constant rate so it can be used as a clock
+	pub invariant_tsc: bool,
+	/// `rdrand` is available
+	pub rdrand: bool,
+}
+
+impl core::fmt::Display for Features {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let flags = [
+			(self.tsc, "TSC"),
+			(self.msr, "MSR"),
+			(self.pse, "PSE"),
+			(self.pae, "PAE"),
+			(self.apic, "APIC"),
+			(self.x2apic, "x2APIC"),
+			(self.long_mode, "LM"),
+			(self.invariant_tsc, "InvariantTSC"),
+			(self.rdrand, "RDRAND"),
+		];
+		let mut first = true;
+		for (_, name) in flags.iter().filter(|(present, _)| *present) {
+			if !first {
+				write!(f, " ")?;
+			}
+			write!(f, "{}", name)?;
+			first = false;
+		}
+		Ok(())
+	}
+}
+
+/// What the processor told us about itself
+#[derive(Debug, Clone, Copy)]
+pub struct Cpuid {
+	/// Vendor string such as `GenuineIntel` or `AuthenticAMD`
+	vendor: [u8; 12],
+	/// Brand string, NUL padded, empty if the processor has none
+	brand: [u8; 48],
+	pub family: u32,
+	pub model: u32,
+	pub stepping: u32,
+	pub features: Features,
+}
+
+impl Cpuid {
+	/// Run every leaf we need
+	fn detect() -> Self {
+		let (max_leaf, ebx, ecx, edx) = cpu::cpuid(LEAF_VENDOR, 0);
+		let mut vendor = [0u8; 12];
+		vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+		vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+		vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+
+		let mut cpuid = Self {
+			vendor,
+			brand: [0; 48],
+			family: 0,
+			model: 0,
+			stepping: 0,
+			features: Features::default(),
+		};
+
+		if max_leaf >= LEAF_FEATURES {
+			let (eax, _, ecx, edx) = cpu::cpuid(LEAF_FEATURES, 0);
+			// The extended family and model only apply to some base families
+			let base_family = (eax >> 8) & 0xF;
+			let mut model = (eax >> 4) & 0xF;
+			cpuid.family = base_family;
+			if base_family == 0xF {
+				cpuid.family += (eax >> 20) & 0xFF;
+			}
+			if base_family == 0x6 || base_family == 0xF {
+				model |= ((eax >> 16) & 0xF) << 4;
+			}
+			cpuid.model = model;
+			cpuid.stepping = eax & 0xF;
+
+			cpuid.features.pse = edx & EDX_PSE != 0;
+			cpuid.features.tsc = edx & EDX_TSC != 0;
+			cpuid.features.msr = edx & EDX_MSR != 0;
+			cpuid.features.pae = edx & EDX_PAE != 0;
+			cpuid.features.apic = edx & EDX_APIC != 0;
+			cpuid.features.x2apic = ecx & ECX_X2APIC != 0;
+			cpuid.features.rdrand = ecx & ECX_RDRAND != 0;
+		}
+
+		let (max_extended, _, _, _) = cpu::cpuid(LEAF_EXTENDED_MAX, 0);
+		if max_extended >= LEAF_EXTENDED_FEATURES {
+			let (_, _, _, edx) = cpu::cpuid(LEAF_EXTENDED_FEATURES, 0);
+			cpuid.features.long_mode = edx & EDX_LONG_MODE != 0;
+		}
+		if max_extended >= LEAF_BRAND + 2 {
+			let (chunks, _) = cpuid.brand.as_chunks_mut::<16>();
+			for (index, chunk) in chunks.iter_mut().enumerate() {
+				let (eax, ebx, ecx, edx) = cpu::cpuid(LEAF_BRAND + index as u32, 0);
+				chunk[0..4].copy_from_slice(&eax.to_le_bytes());
+				chunk[4..8].copy_from_slice(&ebx.to_le_bytes());
+				chunk[8..12].copy_from_slice(&ecx.to_le_bytes());
+				chunk[12..16].copy_from_slice(&edx.to_le_bytes());
+			}
+		}
+		if max_extended >= LEAF_POWER {
+			let (_, _, _, edx) = cpu::cpuid(LEAF_POWER, 0);
+			cpuid.features.invariant_tsc = edx & EDX_INVARIANT_TSC != 0;
+		}
+		cpuid
+	}
+	/// The vendor string, `?` if it is not ASCII
+	pub fn vendor(&self) -> &str {
+		core::str::from_utf8(&self.vendor).unwrap_or("?")
+	}
+	/// The brand string without its padding, empty if there is none
+	pub fn brand(&self) -> &str {
+		let len = self
+			.brand
+			.iter()
+			.position(|&byte| byte == 0)
+			.unwrap_or(self.brand.len());
+		core::str::from_utf8(&self.brand[..len])
+			.unwrap_or("")
+			.trim()
+	}
+}
+
+impl core::fmt::Display for Cpuid {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"CPU: {} \"{}\" family {:#X} model {:#X} stepping {}, features: {}",
+			self.vendor(),
+			self.brand(),
+			self.family,
+			self.model,
+			self.stepping,
+			self.features,
+		)
+	}
+}
+
+/// Read the processor information and keep it for [`get`]
+pub fn init() -> Cpuid {
+	let cpuid = Cpuid::detect();
+	unsafe { CPUID = Some(cpuid) };
+	cpuid
+}
+
+/// The processor information, CPUID is always there on an i586 so if [`init`]
+/// has not run yet we run it now
+pub fn get() -> Cpuid {
+	match unsafe { CPUID } {
+		Some(cpuid) => cpuid,
+		None => init(),
+	}
+}
+
+/// The feature flags, shorthand for `get().features`
+pub fn features() -> Features {
+	get().features
+}
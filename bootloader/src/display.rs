@@ -19,7 +19,7 @@ impl Vga{
             if OFFSET > 0xFA0 { OFFSET = 0; }
             for byte in bytes{
                 // Newline `\n` will act as a CRLF in my OS, 0x50 is 80 decimal, the VGA width
-                if *byte == '\n' as u8 {
+                if *byte == b'\n' {
                     let tmp = OFFSET % 0x50;
                     OFFSET += 0x50-tmp;
                     continue;
@@ -57,7 +57,7 @@ impl core::fmt::Write for VgaWriter{
 }
 
 /// Our core implementation of [`std::print!`](https://doc.rust-lang.org/std/macro.print.html)
-#[macro_export]
+#[allow(unused_macros)]
 macro_rules! print {
     ($($arg:tt)*) => {
         let _ = core::fmt::Write::write_fmt(
@@ -69,6 +69,6 @@ macro_rules! print {
 #[macro_export]
 macro_rules! clear {
     () => {
-        crate::display::VgaWriter::clear();
+        $crate::display::VgaWriter::clear();
     }
 }
\ No newline at end of file
@@ -2,6 +2,8 @@
 //! meaningful errors
 pub type Result<T> = core::result::Result<T, self::Error>;
 
+// The fields are only read by `Debug` when we print an error
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum Error {
 	/// Found a NIC but it is not one we have a driver for
@@ -9,6 +11,81 @@ pub enum Error {
 
 	/// No PCI network card found
 	NoNICFound,
+
+	/// This device has no memory BAR we can reach for its registers
+	NoMmioBar((u16, u16)),
+
+	/// This device has no I/O BAR for its registers
+	NoIoBar((u16, u16)),
+
+	/// This virtio device has neither modern config structures we can reach
+	/// nor a legacy I/O BAR
+	NoVirtioTransport((u16, u16)),
+
+	/// The virtio device did not accept these features, or does not offer
+	/// virtio 1.0 on the modern transport
+	VirtioFeaturesRejected(u64),
+
+	/// This virtqueue is missing or not a size we can use
+	BadVirtqueue(u16),
+
+	/// This device has no MSI/MSI-X capability, or its MSI-X table is not
+	/// somewhere we can reach
+	NoMsi((u16, u16)),
+
+	/// The MSI-X table does not have this entry
+	BadMsixEntry(u16),
+
+	/// There were more PCI functions than we have room for
+	TooManyPciDevices(usize),
+
+	/// Could not find a valid RSDP in the EBDA or BIOS area
+	NoRsdpFound,
+
+	/// An ACPI table we cannot continue without failed validation
+	BadAcpiTable([u8; 4]),
+
+	/// There is no MADT so we cannot find the APICs
+	NoMadt,
+
+	/// CPUID says this processor has no Local APIC or no MSRs to find it with
+	NoLocalApic,
+
+	/// None of the I/O APICs handle this Global System Interrupt
+	NoIoApicForGsi(u32),
+
+	/// There is no running AP with this CPU index
+	NoSuchCpu(usize),
+
+	/// The AP with this CPU index is still running its last work
+	CpuBusy(usize),
+
+	/// There is no usable memory mapped HPET in the ACPI tables
+	NoHpet,
+
+	/// None of the I/O APIC inputs in this HPET routing bitmap are usable
+	NoHpetRoute(u32),
+
+	/// There is no unclaimed usable RAM big enough for a heap of this size
+	NoHeapRegion(u64),
+
+	/// `stage0.asm` did not give us an E820 memory map
+	NoMemoryMap,
+
+	/// There is no unclaimed usable RAM for the page tables
+	NoPageTableRegion,
+
+	/// This date or time is out of range for the RTC
+	InvalidDateTime,
+
+	/// CPUID says this processor cannot map 4MiB pages
+	NoLargePages,
+
+	/// The processor does not support PAE and long mode
+	LongModeUnsupported,
+
+	/// The NIC has not finished sending the packets it was already given
+	TransmitBusy,
 	//// We have not implemented this network protocol
 	// UnsupportedEtherType(u16),
 }
@@ -0,0 +1,309 @@
+//! This crate drives the High Precision Event Timer described by the ACPI HPET
+//! table. We use its main counter as a high resolution clock for
+//! [`crate::time::Instant`] and comparator 0 as a periodic or one-shot timer.
+//! The comparator interrupt is routed through the I/O APIC but left masked as
+//! we have no IDT, instead it is level triggered so [`Hpet::poll`] can see it
+//! in the interrupt status register
+//! `<https://wiki.osdev.org/HPET>`
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::acpi;
+use crate::apic;
+use crate::error::{Error, Result};
+
+/// Register offsets from the HPET base address
+const HPET_CAPABILITIES: usize = 0x000;
+const HPET_CONFIG: usize = 0x010;
+const HPET_INTERRUPT_STATUS: usize = 0x020;
+const HPET_MAIN_COUNTER: usize = 0x0F0;
+/// Timer 0 registers, timer N has the same ones 0x20 * N further on
+const HPET_TIMER_CONFIG: usize = 0x100;
+const HPET_TIMER_COMPARATOR: usize = 0x108;
+
+/// Capabilities: The main counter is 64 bits wide
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+/// Capabilities: Number of timers minus one in bits 8 to 12
+const CAP_NUM_TIMERS_SHIFT: u64 = 8;
+const CAP_NUM_TIMERS_MASK: u64 = 0x1F;
+/// Capabilities: The counter period in femtoseconds is the top 32 bits
+const CAP_PERIOD_SHIFT: u64 = 32;
+/// The spec caps the counter period at 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// General configuration: Run the main counter
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// General configuration: Legacy replacement routing, we leave this off
+const CONFIG_LEGACY: u64 = 1 << 1;
+
+/// Timer configuration: Level triggered, required for the status register to
+/// latch the interrupt so we can poll it
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Timer configuration: The next comparator write sets the value directly
+/// instead of the periodic accumulator
+const TIMER_VALUE_SET: u64 = 1 << 6;
+/// Timer configuration: I/O APIC input the timer interrupts on, bits 9 to 13
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+/// Timer configuration: Bitmap of the I/O APIC inputs this timer can use
+const TIMER_ROUTE_CAP_SHIFT: u64 = 32;
+
+/// MPS INTI flags for an active high, level triggered interrupt
+const MPS_LEVEL_ACTIVE_HIGH: u16 = 0b1101;
+/// Vector comparator 0 is routed to once we have an IDT
+pub const TIMER_VECTOR: u8 = 0x30;
+
+/// Once we `init()` we keep a copy here for [`crate::time`]
+static mut HPET: Option<Hpet> = None;
+/// The ticks accumulated from a 32-bit counter, at QEMU's 100MHz the counter
+/// wraps every ~43s so [`Hpet::elapsed`] has to be called at least that often.
+/// Both start from zero so the low 32 bits are also the count when we last
+/// read it, which lets any CPU update it with one compare and exchange
+static COUNTER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A HPET block from the ACPI table
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+	base: usize,
+	/// Main counter period in femtoseconds
+	period_fs: u64,
+	/// Main counter frequency in Hz
+	frequency: u64,
+	timers: u8,
+	counter_64: bool,
+	/// The GSI comparator 0 interrupts on, [`None`] without an I/O APIC input
+	/// we can route it to
+	gsi: Option<u32>,
+}
+
+impl Hpet {
+	fn read(&self, reg: usize) -> u64 {
+		let ptr = (self.base + reg) as *const u32;
+		unsafe {
+			let low = core::ptr::read_volatile(ptr) as u64;
+			let high = core::ptr::read_volatile(ptr.add(1)) as u64;
+			high << 32 | low
+		}
+	}
+	fn write(&self, reg: usize, val: u64) {
+		let ptr = (self.base + reg) as *mut u32;
+		unsafe {
+			core::ptr::write_volatile(ptr, val as u32);
+			core::ptr::write_volatile(ptr.add(1), (val >> 32) as u32);
+		}
+	}
+	fn write32(&self, reg: usize, val: u32) {
+		unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, val) };
+	}
+	/// Reads the main counter. We can only read 32 bits at a time so we read
+	/// the high half either side of the low half and retry if it rolled over
+	pub fn counter(&self) -> u64 {
+		let ptr = (self.base + HPET_MAIN_COUNTER) as *const u32;
+		if !self.counter_64 {
+			return unsafe { core::ptr::read_volatile(ptr) } as u64;
+		}
+		loop {
+			let (high, low, high2) = unsafe {
+				(
+					core::ptr::read_volatile(ptr.add(1)),
+					core::ptr::read_volatile(ptr),
+					core::ptr::read_volatile(ptr.add(1)),
+				)
+			};
+			if high == high2 {
+				return (high as u64) << 32 | low as u64;
+			}
+		}
+	}
+	/// Main counter frequency in Hz
+	#[allow(dead_code)]
+	pub fn frequency(&self) -> u64 {
+		self.frequency
+	}
+	/// Ticks since the main counter was started, a 32-bit counter is widened
+	/// by accumulating how far it moved since we last looked, the difference
+	/// wraps naturally in a u32
+	fn ticks_since_start(&self) -> u64 {
+		if self.counter_64 {
+			return self.counter();
+		}
+		let mut ticks = COUNTER_TICKS.load(Ordering::Relaxed);
+		loop {
+			// Read the counter after the ticks so it is never older than the
+			// count they were last updated from
+			let count = self.counter() as u32;
+			let next = ticks + count.wrapping_sub(ticks as u32) as u64;
+			match COUNTER_TICKS.compare_exchange_weak(
+				ticks,
+				next,
+				Ordering::Relaxed,
+				Ordering::Relaxed,
+			) {
+				Ok(_) => return next,
+				Err(current) => ticks = current,
+			}
+		}
+	}
+	/// Time since the main counter was started
+	pub fn elapsed(&self) -> Duration {
+		let ticks = self.ticks_since_start();
+		let secs = ticks / self.frequency;
+		let nanos = (ticks % self.frequency) * 1_000_000_000 / self.frequency;
+		Duration::new(secs, nanos as u32)
+	}
+	/// Counter ticks in `duration`, saturating for durations too long to count
+	fn ticks(&self, duration: Duration) -> u64 {
+		let nanos = (duration.subsec_nanos() as u64)
+			.saturating_mul(self.frequency)
+			/ 1_000_000_000;
+		duration
+			.as_secs()
+			.saturating_mul(self.frequency)
+			.saturating_add(nanos)
+	}
+	/// Program comparator 0 to fire after `duration`, and every `duration`
+	/// after that if `periodic` and the timer supports it. Returns false if
+	/// we had to fall back to one-shot, errors if [`init`] found no route for
+	/// its interrupt
+	#[allow(dead_code)]
+	pub fn start_timer(
+		&self,
+		duration: Duration,
+		periodic: bool,
+	) -> Result<bool> {
+		let gsi = self.gsi.ok_or(Error::NoHpetRoute(self.routes()))?;
+		let ticks = self.ticks(duration).max(1);
+		let mut config = self.read(HPET_TIMER_CONFIG) & !TIMER_ROUTE_MASK;
+		config &= !(TIMER_PERIODIC | TIMER_VALUE_SET);
+		config |=
+			TIMER_LEVEL | TIMER_INT_ENABLE | (gsi as u64) << TIMER_ROUTE_SHIFT;
+		let periodic = periodic && config & TIMER_PERIODIC_CAPABLE != 0;
+
+		if periodic {
+			// With VALUE_SET the comparator write sets its value, otherwise it
+			// sets the period it is advanced by. We can only write 32 bits at a
+			// time and the bit clears after each write so set it per half
+			let deadline = self.counter().wrapping_add(ticks);
+			let timer = config | TIMER_PERIODIC;
+			for half in [1, 0] {
+				self.write32(HPET_TIMER_CONFIG, (timer | TIMER_VALUE_SET) as u32);
+				self.write32(
+					HPET_TIMER_COMPARATOR + half * 4,
+					(deadline >> (half * 32)) as u32,
+				);
+			}
+			self.write(HPET_TIMER_CONFIG, timer);
+			self.write(HPET_TIMER_COMPARATOR, ticks);
+		} else {
+			self.write(HPET_TIMER_CONFIG, config);
+			self.write(HPET_TIMER_COMPARATOR, self.counter().wrapping_add(ticks));
+		}
+		self.acknowledge();
+		Ok(periodic)
+	}
+	/// Stop comparator 0 from raising interrupts
+	pub fn stop_timer(&self) {
+		let config = self.read(HPET_TIMER_CONFIG);
+		self.write(HPET_TIMER_CONFIG, config & !TIMER_INT_ENABLE);
+		self.acknowledge();
+	}
+	/// True if comparator 0 has fired since we last polled, this also clears
+	/// it so the next one can be seen
+	#[allow(dead_code)]
+	pub fn poll(&self) -> bool {
+		let fired = self.read(HPET_INTERRUPT_STATUS) & 1 != 0;
+		if fired {
+			self.acknowledge();
+		}
+		fired
+	}
+	/// Writing 1 to a status bit clears it
+	fn acknowledge(&self) {
+		self.write(HPET_INTERRUPT_STATUS, 1);
+	}
+	/// Bitmap of the I/O APIC inputs comparator 0 can interrupt on
+	fn routes(&self) -> u32 {
+		(self.read(HPET_TIMER_CONFIG) >> TIMER_ROUTE_CAP_SHIFT) as u32
+	}
+}
+
+impl core::fmt::Display for Hpet {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"HPET at {:#X}: {} timers, {}-bit counter at {} Hz",
+			self.base,
+			self.timers,
+			if self.counter_64 { 64 } else { 32 },
+			self.frequency,
+		)?;
+		match self.gsi {
+			Some(gsi) => write!(f, ", timer 0 on GSI {}", gsi),
+			None => write!(f, ", timer 0 not routed"),
+		}
+	}
+}
+
+/// Find the HPET from ACPI, start its main counter and route comparator 0
+/// through the I/O APIC. The counter works without the APIC so not being able
+/// to route the comparator only stops [`Hpet::start_timer`]
+pub fn init() -> Result<Hpet> {
+	let table = acpi::get()
+		.and_then(|acpi| acpi.hpet())
+		.ok_or(Error::NoHpet)?;
+	let address = table.base_address;
+	if address.address_space != acpi::ADDRESS_SPACE_MEMORY
+		|| address.address > u32::MAX as u64
+	{
+		return Err(Error::NoHpet);
+	}
+
+	let mut hpet = Hpet {
+		base: address.address as usize,
+		period_fs: 0,
+		frequency: 0,
+		timers: 0,
+		counter_64: false,
+		gsi: None,
+	};
+	let capabilities = hpet.read(HPET_CAPABILITIES);
+	hpet.period_fs = capabilities >> CAP_PERIOD_SHIFT;
+	if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+		return Err(Error::NoHpet);
+	}
+	hpet.frequency = FS_PER_SEC / hpet.period_fs;
+	hpet.timers =
+		((capabilities >> CAP_NUM_TIMERS_SHIFT) & CAP_NUM_TIMERS_MASK) as u8 + 1;
+	hpet.counter_64 = capabilities & CAP_COUNT_SIZE_64 != 0;
+
+	// Comparator 0 can only interrupt on the I/O APIC inputs in its bitmap,
+	// we take the first one our I/O APICs actually handle
+	let routes = hpet.routes();
+	hpet.gsi = apic::get().and_then(|apic| {
+		(0..32).filter(|gsi| routes & (1 << gsi) != 0).find(|&gsi| {
+			apic
+				.route_gsi(gsi, MPS_LEVEL_ACTIVE_HIGH, TIMER_VECTOR, 0, true)
+				.is_ok()
+		})
+	});
+	hpet.stop_timer();
+
+	// Restart the main counter from zero without legacy replacement
+	let config = hpet.read(HPET_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY);
+	hpet.write(HPET_CONFIG, config);
+	hpet.write(HPET_MAIN_COUNTER, 0);
+	COUNTER_TICKS.store(0, Ordering::Relaxed);
+	hpet.write(HPET_CONFIG, config | CONFIG_ENABLE);
+
+	unsafe { HPET = Some(hpet) };
+	Ok(hpet)
+}
+
+/// The HPET if [`init`] found one
+pub fn get() -> Option<Hpet> {
+	unsafe { HPET }
+}
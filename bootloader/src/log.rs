@@ -0,0 +1,128 @@
+//! This crate keeps the last [`LOG_SIZE`] bytes we printed in a ring buffer,
+//! so the panic handler can repeat the lines leading up to a panic on the VGA
+//! screen or to a serial console that was attached late
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// How many bytes of output we remember
+const LOG_SIZE: usize = 0x1000;
+
+/// The ring, [`LOG_HEAD`] counts every byte ever written so the oldest byte is
+/// at `LOG_HEAD % LOG_SIZE` once it has wrapped
+static mut LOG: [u8; LOG_SIZE] = [0; LOG_SIZE];
+static mut LOG_HEAD: usize = 0;
+/// Set by [`freeze`] so the panic output does not push out what led up to it
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Append `bytes` to the ring, called for everything `print!` writes
+pub fn record(bytes: &[u8]) {
+	if FROZEN.load(Ordering::Relaxed) {
+		return;
+	}
+	unsafe {
+		for byte in bytes {
+			LOG[LOG_HEAD % LOG_SIZE] = *byte;
+			LOG_HEAD += 1;
+		}
+	}
+}
+
+/// Stop recording, what is in the ring stays there
+pub fn freeze() {
+	FROZEN.store(true, Ordering::Relaxed);
+}
+
+/// Write the last `lines` complete lines in the ring to `f`, a line cut in
+/// half by the ring wrapping is skipped
+pub fn write_last_lines(
+	f: &mut impl core::fmt::Write,
+	lines: usize,
+) -> core::fmt::Result {
+	let (log, head) = unsafe { (&*core::ptr::addr_of!(LOG), LOG_HEAD) };
+	let oldest = head.saturating_sub(LOG_SIZE);
+	let byte = |pos: usize| log[pos % LOG_SIZE];
+
+	// Walk back over `lines` newlines, ignoring one ending the last line
+	let mut start = head;
+	let mut found = 0;
+	while start > oldest {
+		if byte(start - 1) == b'\n' && start != head {
+			found += 1;
+			if found == lines {
+				break;
+			}
+		}
+		start -= 1;
+	}
+	if start == oldest && oldest != 0 {
+		while start < head && byte(start) != b'\n' {
+			start += 1;
+		}
+		start = (start + 1).min(head);
+	}
+
+	// The range is at most two contiguous pieces of the ring
+	let (first, second) = if head - start > LOG_SIZE - start % LOG_SIZE {
+		(
+			&log[start % LOG_SIZE..],
+			&log[..head - start - (LOG_SIZE - start % LOG_SIZE)],
+		)
+	} else {
+		(
+			&log[start % LOG_SIZE..start % LOG_SIZE + (head - start)],
+			&log[..0],
+		)
+	};
+	for piece in [first, second] {
+		for chunk in piece.utf8_chunks() {
+			f.write_str(chunk.valid())?;
+			if !chunk.invalid().is_empty() {
+				f.write_char('?')?;
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn last_lines(lines: usize) -> String {
+		let mut out = String::new();
+		write_last_lines(&mut out, lines).unwrap();
+		out
+	}
+
+	/// The ring is global so this is one test, it has to run before the ring
+	/// wraps and after
+	#[test]
+	fn last_lines_across_the_wrap() {
+		record(b"one\ntwo\nthree\n");
+		assert_eq!(last_lines(2), "two\nthree\n");
+		assert_eq!(last_lines(10), "one\ntwo\nthree\n");
+		// An unfinished line still counts as the last one
+		record(b"four");
+		assert_eq!(last_lines(2), "three\nfour");
+		record(b"\n");
+
+		// Wrap the ring part way through a line, the cut line is skipped and
+		// the rest comes out in order across the end of the buffer
+		let line = [b'x'; 99];
+		for _ in 0..=LOG_SIZE / 100 {
+			record(&line);
+			record(b"\n");
+		}
+		record(b"last\n");
+		assert!(unsafe { LOG_HEAD } > LOG_SIZE);
+		assert_eq!(last_lines(1), "last\n");
+		let x = core::str::from_utf8(&line).unwrap();
+		assert_eq!(last_lines(2), format!("{}\nlast\n", x));
+		// The first x line is the one the wrap cut
+		let whole = format!("{}\n", x).repeat(LOG_SIZE / 100);
+		assert_eq!(last_lines(usize::MAX), whole + "last\n");
+
+		freeze();
+		record(b"ignored\n");
+		assert_eq!(last_lines(1), "last\n");
+	}
+}
@@ -0,0 +1,202 @@
+//! This crate takes the BSP from 32-bit protected mode into 64-bit long mode
+//! and hands it to a 64-bit payload, so the OS can grow past what the i586
+//! bootstrap can address. [`init`] builds 4-level PAE page tables that
+//! identity map all RAM (at least the first 4GiB so MMIO and 32-bit BARs
+//! still work) with 2MiB pages, and [`enter`] turns off 32-bit paging,
+//! enables PAE and EFER.LME, loads a 64-bit GDT and jumps to the payload.
+//! There is no coming back, the APs are left parked in protected mode
+//! `<https://wiki.osdev.org/Setting_Up_Long_Mode>`
+use core::arch::{asm, global_asm};
+
+use crate::cpu;
+use crate::cpuid;
+use crate::error::{Error, Result};
+use crate::memory::{map, regions};
+
+const PAGE_SIZE: u64 = 0x1000;
+const LARGE_PAGE_SIZE: u64 = 0x200000;
+const GIB: u64 = 0x4000_0000;
+/// Entries in every level of the page tables
+const ENTRIES: u64 = 512;
+/// We always map at least this much so MMIO below 4GiB is reachable
+const MIN_MAPPED_GIB: u64 = 4;
+/// Most memory we map, one page directory per GiB
+const MAX_MAPPED_GIB: u64 = 64;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_WRITABLE: u64 = 1 << 1;
+const PAGE_UNCACHED: u64 = (1 << 3) | (1 << 4);
+/// Page directory entry: Maps a 2MiB page
+const PAGE_LARGE: u64 = 1 << 7;
+
+/// Once we `init()` we keep a copy here for [`enter`]
+static mut LONG_MODE: Option<LongMode> = None;
+
+// Jumped to by [`enter`] with the PML4 in eax, the argument in ecx and the
+// entry point in edx:edi, we never return so we use whatever registers we
+// like. We stay identity mapped the whole way so the code keeps running as we
+// switch the paging mode under it. Registers written in 32-bit code have
+// undefined upper halves in 64-bit code so everything is zero extended after
+// the jump
+#[cfg(not(test))]
+global_asm!(
+	".code32",
+	".globl longmode_trampoline",
+	"longmode_trampoline:",
+	"cli",
+	"mov esi, eax",
+	"mov ebx, edi",
+	"mov ebp, edx",
+	"mov edi, ecx",
+	// Long mode can only be enabled with paging off
+	"mov eax, cr0",
+	"and eax, 0x7FFFFFFF",
+	"mov cr0, eax",
+	"mov eax, cr4",
+	"or eax, {cr4_pae}",
+	"mov cr4, eax",
+	"mov cr3, esi",
+	"mov ecx, {efer}",
+	"rdmsr",
+	"or eax, {efer_lme}",
+	"wrmsr",
+	// Paging on with LME set activates long mode, we are in compatibility
+	// mode until we load a 64-bit code segment
+	"mov eax, cr0",
+	"or eax, 0x80000000",
+	"mov cr0, eax",
+	"lgdt [longmode_gdtr]",
+	// jmp 0x08:longmode_64
+	".byte 0xEA",
+	".long longmode_64",
+	".word 0x08",
+	".code64",
+	"longmode_64:",
+	"mov ax, 0x10",
+	"mov ds, ax",
+	"mov es, ax",
+	"mov fs, ax",
+	"mov gs, ax",
+	"mov ss, ax",
+	"mov esp, esp",
+	"and rsp, -16",
+	"mov edi, edi",
+	"mov eax, ebx",
+	"mov edx, ebp",
+	"shl rdx, 32",
+	"or rax, rdx",
+	// System V, the argument is in rdi
+	"call rax",
+	"2:",
+	"cli",
+	"hlt",
+	"jmp 2b",
+	".code32",
+	// Null, 64-bit code and data, the accessed bits are preset as this is
+	// mapped read only
+	".balign 8",
+	"longmode_gdt:",
+	".quad 0x0000000000000000",
+	".quad 0x00AF9B000000FFFF",
+	".quad 0x00CF93000000FFFF",
+	"longmode_gdtr:",
+	".word longmode_gdtr - longmode_gdt - 1",
+	".long longmode_gdt",
+	cr4_pae = const 1 << 5,
+	efer = const cpu::IA32_EFER,
+	efer_lme = const 1 << 8,
+);
+
+/// The long mode page tables we built
+#[derive(Debug, Clone, Copy)]
+pub struct LongMode {
+	pml4: u64,
+	mapped_gib: u64,
+}
+
+impl core::fmt::Display for LongMode {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"Long mode: PML4 at {:#X}, {} GiB identity mapped",
+			self.pml4, self.mapped_gib
+		)
+	}
+}
+
+/// True if the processor can run in long mode
+pub fn supported() -> bool {
+	let features = cpuid::features();
+	features.pae && features.long_mode
+}
+
+/// Write the 64-bit entry `val` at `index` of the table at `table`
+fn write_entry(table: u64, index: u64, val: u64) {
+	let entry = (table + index * 8) as usize as *mut u64;
+	unsafe { entry.write_volatile(val) };
+}
+
+/// Build the long mode page tables in a region we claim below 4GiB, RAM is
+/// mapped cached and everything else uncached like the 32-bit tables
+pub fn init() -> Result<LongMode> {
+	if !supported() {
+		return Err(Error::LongModeUnsupported);
+	}
+	let memory_map = map::get().ok_or(Error::NoMemoryMap)?;
+	let mapped_gib = memory_map
+		.top()
+		.div_ceil(GIB)
+		.clamp(MIN_MAPPED_GIB, MAX_MAPPED_GIB);
+
+	// One PML4, one PDPT and a page directory per GiB
+	let length = (2 + mapped_gib) * PAGE_SIZE;
+	let pml4 =
+		regions::find_free(length, PAGE_SIZE).ok_or(Error::NoPageTableRegion)?;
+	regions::claim("Long mode page tables", pml4, length);
+	let pdpt = pml4 + PAGE_SIZE;
+
+	for index in 0..ENTRIES {
+		write_entry(pml4, index, 0);
+		write_entry(pdpt, index, 0);
+	}
+	write_entry(pml4, 0, pdpt | PAGE_PRESENT | PAGE_WRITABLE);
+
+	for gib in 0..mapped_gib {
+		let directory = pdpt + (1 + gib) * PAGE_SIZE;
+		write_entry(pdpt, gib, directory | PAGE_PRESENT | PAGE_WRITABLE);
+		for index in 0..ENTRIES {
+			let addr = gib * GIB + index * LARGE_PAGE_SIZE;
+			let mut entry = addr | PAGE_LARGE | PAGE_PRESENT | PAGE_WRITABLE;
+			if memory_map.ram_bytes(addr, LARGE_PAGE_SIZE) < LARGE_PAGE_SIZE {
+				entry |= PAGE_UNCACHED;
+			}
+			write_entry(directory, index, entry);
+		}
+	}
+
+	let long_mode = LongMode { pml4, mapped_gib };
+	unsafe { LONG_MODE = Some(long_mode) };
+	Ok(long_mode)
+}
+
+/// Switch to long mode and call the 64-bit code at `entry` with `arg` as its
+/// first argument, we halt if it ever returns. Panics if [`init`] has not
+/// built the page tables
+pub fn enter(entry: u64, arg: u32) -> ! {
+	let long_mode = get().expect("Long mode page tables are not set up");
+	unsafe {
+		asm!(
+			"jmp longmode_trampoline",
+			in("eax") long_mode.pml4 as u32,
+			in("ecx") arg,
+			in("edx") (entry >> 32) as u32,
+			in("edi") entry as u32,
+			options(noreturn),
+		)
+	}
+}
+
+/// The long mode page tables if [`init`] built them
+pub fn get() -> Option<LongMode> {
+	unsafe { LONG_MODE }
+}
@@ -1,43 +1,133 @@
 #![doc = include_str!("../../README.md")]
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Host unit tests do not have `entry` so most of the kernel is unused in them
+#![cfg_attr(test, allow(unused))]
 #![allow(rustdoc::bare_urls)]
+#![cfg_attr(feature = "heap", feature(alloc_error_handler))]
 // #![deny(rustdoc::all)]
 
+#[cfg(feature = "heap")]
+extern crate alloc;
+
 #[macro_use]
 mod serial;
 
+mod acpi;
+mod apic;
 mod core_reqs;
-// mod display;
 mod cpu;
+mod cpuid;
+#[cfg(feature = "panic-vga")]
+#[rustfmt::skip]
+mod display;
 mod error;
+mod hpet;
+mod log;
+mod longmode;
+mod memory;
 mod net;
+mod panic;
 mod pci;
+mod pit;
+mod random;
+mod smp;
+mod stack;
 mod time;
 
-/// Custom panic handler for our OS
-#[panic_handler]
-fn panic(info: &core::panic::PanicInfo) -> ! {
-	print!("{}", info);
-	cpu::halt();
-}
-
 /// This function is called by `stage0.asm` after setting up 32bit mode and a
-/// stack at ~~0x7c00~~ 0x2000000
+/// stack at ~~0x7c00~~ 0x2000000, with the E820 map it collected in real mode
+/// the end of our image in memory, the span of its read only sections and the
+/// 64-bit payload appended after it, both 0 when built without one
 /// ```x86asm
+/// push payload_end
+/// push payload_start
+/// push readonly_end
+/// push readonly_start
+/// push image_end
+/// push E820_MAP
+/// push entry_point
 /// call entry_point
 /// ```
+#[cfg(not(test))]
 #[no_mangle]
-fn entry(entry_point: u64) {
+extern "C" fn entry(
+	entry_point: u32,
+	memory_map: *const memory::map::E820Map,
+	image_end: u32,
+	readonly_start: u32,
+	readonly_end: u32,
+	payload_start: u32,
+	payload_end: u32,
+) {
 	//clear!();
 	print!("We entered at: {:#X}\n", entry_point);
+	print!("{}\n", cpuid::init());
 	print!("Time is: {}\n", time::DateTime::now());
+	print!("{}\n", memory::map::init(memory_map));
+	memory::regions::init(image_end);
+	if payload_end > payload_start {
+		memory::regions::claim(
+			"64-bit payload",
+			payload_start as u64,
+			(payload_end - payload_start) as u64,
+		);
+	}
+	stack::init();
+	#[cfg(feature = "heap")]
+	match memory::heap::init() {
+		Ok(heap) => print!("{}\n", heap),
+		Err(e) => print!("Cannot init heap: {:X?}\n", e),
+	}
+	match memory::paging::init(readonly_start, readonly_end) {
+		Ok(paging) => print!("{}\n", paging),
+		Err(e) => print!("Cannot enable paging: {:X?}\n", e),
+	}
+	match longmode::init() {
+		Ok(long_mode) => print!("{}\n", long_mode),
+		Err(e) => print!("Cannot set up long mode: {:X?}\n", e),
+	}
+
+	match acpi::init() {
+		Ok(acpi) => print!("{}\n", acpi),
+		Err(e) => print!("Cannot init ACPI: {:X?}\n", e),
+	}
+	match apic::init() {
+		Ok(apic) => print!("{}\n", apic),
+		Err(e) => print!("Cannot init APIC: {:X?}\n", e),
+	}
+	match smp::init() {
+		Ok(cpus) => print!("CPUs online: {}\n", cpus),
+		Err(e) => print!("Cannot start APs: {:X?}\n", e),
+	}
+	print!("{}\n", time::init());
+	random::init();
+	print!("{}\n", pci::config::init());
+	match pci::init() {
+		Ok(devices) => print!("{}\n", devices),
+		Err(e) => print!("Cannot enumerate all PCI devices: {:X?}\n", e),
+	}
+
+	// Hand off to the 64-bit payload if we were built with one, it gets the
+	// E820 map as its argument
+	if payload_end > payload_start {
+		match longmode::get() {
+			Some(_) => {
+				print!("Entering long mode at: {:#X}\n", payload_start);
+				longmode::enter(payload_start as u64, memory_map as u32);
+			}
+			None => print!("Cannot enter the 64-bit payload without long mode\n"),
+		}
+	}
 
 	// Try to initialise network, dont continue if we fail
 	let mut net = net::NetworkStack::init().unwrap();
+	memory::regions::dump();
+	stack::report();
 
 	// Main OS loop
 	loop {
 		net.update();
+		stack::check();
 	}
 }
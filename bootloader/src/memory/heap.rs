@@ -0,0 +1,235 @@
+//! An optional `#[global_allocator]` behind the `heap` cargo feature so code
+//! that is much simpler with `alloc` can use it, the default build stays
+//! allocationless. It is a first fit linked list allocator over a region we
+//! find free in the memory map, the free list is kept sorted by address so
+//! neighbouring blocks merge back together when freed
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::regions;
+use crate::error::{Error, Result};
+
+/// How much memory we claim for the heap
+const HEAP_SIZE: u64 = 0x400000;
+const HEAP_ALIGN: u64 = 0x1000;
+/// Every block is a multiple of this so a free block always fits in one
+const UNIT: usize = size_of::<FreeBlock>();
+
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+/// The header we write at the start of each free block
+struct FreeBlock {
+	size: usize,
+	next: *mut FreeBlock,
+}
+
+/// Counters we print when an allocation fails
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStats {
+	pub base: usize,
+	pub size: usize,
+	pub used: usize,
+	pub peak: usize,
+	pub allocations: usize,
+	pub frees: usize,
+	pub failures: usize,
+}
+
+impl core::fmt::Display for HeapStats {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"Heap at {:#X}: {}/{} KiB used, peak {} KiB, {} allocs, {} frees, \
+			 {} failed",
+			self.base,
+			self.used / 1024,
+			self.size / 1024,
+			self.peak / 1024,
+			self.allocations,
+			self.frees,
+			self.failures,
+		)
+	}
+}
+
+struct Inner {
+	head: *mut FreeBlock,
+	stats: HeapStats,
+}
+
+/// The allocator, a spin lock guards the free list as the APs can allocate
+pub struct Heap {
+	lock: AtomicBool,
+	inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Heap {}
+
+impl Heap {
+	const fn new() -> Self {
+		Self {
+			lock: AtomicBool::new(false),
+			inner: UnsafeCell::new(Inner {
+				head: ptr::null_mut(),
+				stats: HeapStats {
+					base: 0,
+					size: 0,
+					used: 0,
+					peak: 0,
+					allocations: 0,
+					frees: 0,
+					failures: 0,
+				},
+			}),
+		}
+	}
+	/// Run `f` with the free list locked
+	fn with<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> T {
+		while self
+			.lock
+			.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+			.is_err()
+		{
+			core::hint::spin_loop();
+		}
+		let ret = f(unsafe { &mut *self.inner.get() });
+		self.lock.store(false, Ordering::Release);
+		ret
+	}
+}
+
+/// Round `val` up to a multiple of `align`, a power of two
+fn align_up(val: usize, align: usize) -> usize {
+	(val + align - 1) & !(align - 1)
+}
+
+/// The size we actually hand out for `layout`, dealloc must agree
+fn block_size(layout: &Layout) -> usize {
+	align_up(layout.size().max(1), UNIT)
+}
+
+impl Inner {
+	/// First fit, any space in front of the aligned start or after the end of
+	/// the allocation stays in the free list
+	unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+		let size = block_size(&layout);
+		let align = layout.align().max(UNIT);
+		let mut link: *mut *mut FreeBlock = &mut self.head;
+		while !(*link).is_null() {
+			let block = *link;
+			let start = block as usize;
+			let end = start + (*block).size;
+			let alloc_start = align_up(start, align);
+			let alloc_end = alloc_start.saturating_add(size);
+			if alloc_end > end {
+				link = &mut (*block).next;
+				continue;
+			}
+
+			let mut rest = (*block).next;
+			if alloc_end < end {
+				let tail = alloc_end as *mut FreeBlock;
+				tail.write(FreeBlock {
+					size: end - alloc_end,
+					next: rest,
+				});
+				rest = tail;
+			}
+			if alloc_start > start {
+				(*block).size = alloc_start - start;
+				(*block).next = rest;
+			} else {
+				*link = rest;
+			}
+
+			self.stats.used += size;
+			self.stats.peak = self.stats.peak.max(self.stats.used);
+			self.stats.allocations += 1;
+			return alloc_start as *mut u8;
+		}
+		self.stats.failures += 1;
+		ptr::null_mut()
+	}
+	/// Insert the block back in address order and merge it with the free
+	/// blocks either side of it
+	unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+		let size = block_size(&layout);
+		let start = ptr as usize;
+
+		let mut prev: *mut FreeBlock = ptr::null_mut();
+		let mut next = self.head;
+		while !next.is_null() && (next as usize) < start {
+			prev = next;
+			next = (*next).next;
+		}
+
+		let block = ptr as *mut FreeBlock;
+		block.write(FreeBlock { size, next });
+		if !next.is_null() && start + size == next as usize {
+			(*block).size += (*next).size;
+			(*block).next = (*next).next;
+		}
+		if prev.is_null() {
+			self.head = block;
+		} else if prev as usize + (*prev).size == start {
+			(*prev).size += (*block).size;
+			(*prev).next = (*block).next;
+		} else {
+			(*prev).next = block;
+		}
+
+		self.stats.used -= size;
+		self.stats.frees += 1;
+	}
+}
+
+unsafe impl GlobalAlloc for Heap {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		self.with(|inner| inner.alloc(layout))
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		self.with(|inner| inner.dealloc(ptr, layout))
+	}
+}
+
+/// Called when an allocation fails, print where the heap is at so we know if
+/// it was fragmentation or just too small
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+	panic!("Out of heap memory allocating {:?}\n{}", layout, stats());
+}
+
+/// Find [`HEAP_SIZE`] bytes of free usable RAM, claim it and hand it to the
+/// allocator as one free block
+pub fn init() -> Result<HeapStats> {
+	let base = regions::find_free(HEAP_SIZE, HEAP_ALIGN)
+		.ok_or(Error::NoHeapRegion(HEAP_SIZE))?;
+	regions::claim("Heap", base, HEAP_SIZE);
+
+	HEAP.with(|inner| {
+		let head = base as usize as *mut FreeBlock;
+		unsafe {
+			head.write(FreeBlock {
+				size: HEAP_SIZE as usize,
+				next: ptr::null_mut(),
+			})
+		};
+		inner.head = head;
+		inner.stats = HeapStats {
+			base: base as usize,
+			size: HEAP_SIZE as usize,
+			..Default::default()
+		};
+		inner.stats
+	});
+	Ok(stats())
+}
+
+/// The current heap counters
+pub fn stats() -> HeapStats {
+	HEAP.with(|inner| inner.stats)
+}
@@ -0,0 +1,187 @@
+//! The BIOS E820 memory map `stage0.asm` collects for us before it leaves real
+//! mode `<https://wiki.osdev.org/Detecting_Memory_(x86)>`
+
+/// Most entries `stage0.asm` will store, must match `E820_MAX`
+pub const E820_MAX: usize = 128;
+
+/// Once we `init()` we keep a copy here so anything can look up memory
+static mut MEMORY_MAP: Option<MemoryMap> = None;
+
+/// An entry as the BIOS returns it
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct E820Entry {
+	base: u64,
+	length: u64,
+	kind: u32,
+	/// ACPI 3 extended attributes, bit 0 clear means ignore this entry
+	attributes: u32,
+}
+
+/// What `stage0.asm` leaves at `E820_MAP`, the entries follow the count
+#[repr(C)]
+pub struct E820Map {
+	count: u32,
+	_reserved: u32,
+	entries: [E820Entry; E820_MAX],
+}
+
+/// E820 extended attributes: The entry is valid
+const E820_ATTRIBUTE_VALID: u32 = 1 << 0;
+
+/// The type of a region of physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+	/// Free for us to use
+	Usable,
+	/// In use by the firmware or hardware, never touch
+	Reserved,
+	/// Holds ACPI tables, usable once we are done with them
+	AcpiReclaimable,
+	/// ACPI non-volatile storage, must be preserved
+	AcpiNvs,
+	/// Memory the firmware found errors in
+	Bad,
+	/// A type we do not know, treated as reserved
+	Unknown(u32),
+}
+
+impl MemoryKind {
+	/// RAM is anything the firmware gave us or that holds ACPI data, as
+	/// opposed to MMIO and firmware holes, this is what we map cached
+	pub fn is_ram(&self) -> bool {
+		matches!(
+			self,
+			MemoryKind::Usable | MemoryKind::AcpiReclaimable | MemoryKind::AcpiNvs
+		)
+	}
+}
+
+impl From<u32> for MemoryKind {
+	fn from(kind: u32) -> Self {
+		match kind {
+			1 => MemoryKind::Usable,
+			2 => MemoryKind::Reserved,
+			3 => MemoryKind::AcpiReclaimable,
+			4 => MemoryKind::AcpiNvs,
+			5 => MemoryKind::Bad,
+			kind => MemoryKind::Unknown(kind),
+		}
+	}
+}
+
+/// A region of physical memory from the E820 map
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+	pub base: u64,
+	pub length: u64,
+	pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+	/// The first address past this region
+	pub fn end(&self) -> u64 {
+		self.base.saturating_add(self.length)
+	}
+}
+
+impl core::fmt::Display for MemoryRegion {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"{:#012X}-{:#012X} {:?}",
+			self.base,
+			self.end(),
+			self.kind
+		)
+	}
+}
+
+/// The E820 map `stage0.asm` handed to `entry()`
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMap {
+	entries: &'static [E820Entry],
+}
+
+impl MemoryMap {
+	/// Iterate over the regions, skipping empty and invalid entries. The
+	/// BIOS does not promise the entries are sorted or do not overlap
+	pub fn regions(&self) -> impl Iterator<Item = MemoryRegion> {
+		self
+			.entries
+			.iter()
+			.filter(|entry| {
+				entry.length != 0 && entry.attributes & E820_ATTRIBUTE_VALID != 0
+			})
+			.map(|entry| MemoryRegion {
+				base: entry.base,
+				length: entry.length,
+				kind: MemoryKind::from(entry.kind),
+			})
+	}
+	/// Only the regions we are free to use
+	pub fn usable(&self) -> impl Iterator<Item = MemoryRegion> {
+		self
+			.regions()
+			.filter(|region| region.kind == MemoryKind::Usable)
+	}
+	/// Total bytes of usable memory
+	pub fn usable_bytes(&self) -> u64 {
+		self.usable().map(|region| region.length).sum()
+	}
+	/// How many bytes of `base..base + length` are RAM, see
+	/// [`MemoryKind::is_ram`]
+	pub fn ram_bytes(&self, base: u64, length: u64) -> u64 {
+		let end = base.saturating_add(length);
+		self
+			.regions()
+			.filter(|region| region.kind.is_ram())
+			.map(|region| {
+				end.min(region.end()).saturating_sub(base.max(region.base))
+			})
+			.sum()
+	}
+	/// The end of the highest RAM region
+	pub fn top(&self) -> u64 {
+		self
+			.regions()
+			.filter(|region| region.kind.is_ram())
+			.map(|region| region.end())
+			.max()
+			.unwrap_or(0)
+	}
+	/// True if all of `base..base + length` is inside a single usable region
+	pub fn is_usable(&self, base: u64, length: u64) -> bool {
+		let end = base.saturating_add(length);
+		self
+			.usable()
+			.any(|region| base >= region.base && end <= region.end())
+	}
+}
+
+impl core::fmt::Display for MemoryMap {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "Memory map: {} KiB usable", self.usable_bytes() / 1024)?;
+		for region in self.regions() {
+			write!(f, "\n  {}", region)?;
+		}
+		Ok(())
+	}
+}
+
+/// Take the E820 map `stage0.asm` left at `map`, the count is clamped in case
+/// it is garbage. An empty map means the BIOS has no E820 support
+pub fn init(map: *const E820Map) -> MemoryMap {
+	let map = unsafe { &*map };
+	let count = (map.count as usize).min(E820_MAX);
+	let memory_map = MemoryMap {
+		entries: &map.entries[..count],
+	};
+	unsafe { MEMORY_MAP = Some(memory_map) };
+	memory_map
+}
+
+/// The memory map if [`init`] has been called
+pub fn get() -> Option<MemoryMap> {
+	unsafe { MEMORY_MAP }
+}
@@ -0,0 +1,10 @@
+//! This crate keeps track of physical memory, starting with the BIOS E820 map
+//! `stage0.asm` collects for us, the regions each subsystem claims from it and
+//! fixed-size buffer pools over those regions. With the `heap` feature it also
+//! provides a global allocator
+#[cfg(feature = "heap")]
+pub mod heap;
+pub mod map;
+pub mod paging;
+pub mod pool;
+pub mod regions;
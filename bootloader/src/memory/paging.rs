@@ -0,0 +1,229 @@
+//! This crate turns on 32-bit paging with an identity map of the whole 4GiB
+//! address space. RAM from the memory map is cached and everything else is
+//! mapped uncached as it can only be MMIO or firmware. We use 4MiB pages
+//! wherever we can and give a 4MiB chunk a 4KiB page table when it needs
+//! finer control: the kernel image so `.text`/`.rdata` can be read only, the
+//! stacks so the page at the bottom of each can be left unmapped as a guard,
+//! and chunks that are part RAM and part MMIO. We have no IDT so running into
+//! a guard page triple faults and resets the machine, which beats silently
+//! overwriting whatever is below the stack
+//! `<https://wiki.osdev.org/Paging>`
+use super::map::{self, MemoryMap};
+use super::regions;
+use crate::cpu;
+use crate::cpuid;
+use crate::error::{Error, Result};
+use crate::smp;
+
+const PAGE_SIZE: usize = 0x1000;
+const LARGE_PAGE_SIZE: usize = 0x400000;
+/// Entries in the page directory and in each page table
+const ENTRIES: usize = 1024;
+/// Most 4KiB page tables we build, chunks past this that wanted one fall back
+/// to an uncached 4MiB page
+const MAX_TABLES: usize = 16;
+
+/// Page directory and page table entry bits
+const PAGE_PRESENT: u32 = 1 << 0;
+const PAGE_WRITABLE: u32 = 1 << 1;
+const PAGE_WRITE_THROUGH: u32 = 1 << 3;
+const PAGE_CACHE_DISABLE: u32 = 1 << 4;
+const PAGE_UNCACHED: u32 = PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE;
+/// Page directory entry: Maps a 4MiB page instead of pointing at a table
+const PAGE_LARGE: u32 = 1 << 7;
+const PAGE_ADDR_MASK: u32 = !(PAGE_SIZE as u32 - 1);
+
+/// CR0: Read only pages are read only in ring 0 too
+const CR0_WP: usize = 1 << 16;
+/// CR0: Enable paging
+const CR0_PG: usize = 1 << 31;
+/// CR4: Allow 4MiB pages in the page directory
+const CR4_PSE: usize = 1 << 4;
+
+/// Once we `init()` we keep a copy here so the APs can use the same tables
+static mut PAGING: Option<Paging> = None;
+
+/// How much RAM the memory map says is in a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backing {
+	Ram,
+	Mmio,
+	Mixed,
+}
+
+/// The page tables we built
+#[derive(Debug, Clone, Copy)]
+pub struct Paging {
+	directory: usize,
+	tables: usize,
+	large_pages: usize,
+	guard_pages: usize,
+	/// Page aligned range we map read only
+	readonly: (usize, usize),
+}
+
+impl core::fmt::Display for Paging {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"Paging: directory at {:#X}, {} 4MiB pages, {} page tables, read \
+			 only {:#X}-{:#X}, {} guard pages",
+			self.directory,
+			self.large_pages,
+			self.tables,
+			self.readonly.0,
+			self.readonly.1,
+			self.guard_pages,
+		)
+	}
+}
+
+/// Work out how much of `base..base + length` is RAM, the last chunk ends at
+/// 4GiB which does not fit in a usize so we work in u64
+fn backing(memory_map: &MemoryMap, base: usize, length: usize) -> Backing {
+	match memory_map.ram_bytes(base as u64, length as u64) {
+		0 => Backing::Mmio,
+		ram if ram >= length as u64 => Backing::Ram,
+		_ => Backing::Mixed,
+	}
+}
+
+/// The page at the bottom of each stack we leave unmapped
+fn guard_pages() -> [usize; smp::MAX_CPUS] {
+	core::array::from_fn(smp::stack_guard)
+}
+
+/// Build the page directory and tables in a region we claim from the memory
+/// map and turn paging on. `readonly_start..readonly_end` comes from the
+/// section permissions in the image, only whole pages inside it are made read
+/// only as the last one may share a page with `.data`
+pub fn init(readonly_start: u32, readonly_end: u32) -> Result<Paging> {
+	if !cpuid::features().pse {
+		return Err(Error::NoLargePages);
+	}
+	let memory_map = map::get().ok_or(Error::NoMemoryMap)?;
+	let length = (1 + MAX_TABLES) * PAGE_SIZE;
+	let base = regions::find_free(length as u64, PAGE_SIZE as u64)
+		.ok_or(Error::NoPageTableRegion)?;
+	regions::claim("Page tables", base, length as u64);
+
+	let readonly_start = readonly_start as usize;
+	let readonly_end = readonly_end as usize;
+	let mut paging = Paging {
+		directory: base as usize,
+		tables: 0,
+		large_pages: 0,
+		guard_pages: 0,
+		readonly: (
+			(readonly_start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+			readonly_end & !(PAGE_SIZE - 1),
+		),
+	};
+	let guards = guard_pages();
+
+	let directory = paging.directory as *mut u32;
+	for chunk in 0..ENTRIES {
+		let chunk_base = chunk * LARGE_PAGE_SIZE;
+		let chunk_end = chunk_base + (LARGE_PAGE_SIZE - 1);
+		let backing = backing(&memory_map, chunk_base, LARGE_PAGE_SIZE);
+		let needs_table = backing == Backing::Mixed
+			|| (paging.readonly.0 <= chunk_end && chunk_base < paging.readonly.1)
+			|| guards
+				.iter()
+				.any(|&guard| (chunk_base..=chunk_end).contains(&guard));
+
+		let entry = if needs_table && paging.tables < MAX_TABLES {
+			paging.tables += 1;
+			let table = paging.directory + paging.tables * PAGE_SIZE;
+			for page in 0..ENTRIES {
+				let addr = chunk_base + page * PAGE_SIZE;
+				let entry = paging.page_entry(&memory_map, &guards, addr);
+				unsafe { (table as *mut u32).add(page).write_volatile(entry) };
+			}
+			table as u32 | PAGE_PRESENT | PAGE_WRITABLE
+		} else {
+			paging.large_pages += 1;
+			let mut entry =
+				chunk_base as u32 | PAGE_LARGE | PAGE_PRESENT | PAGE_WRITABLE;
+			if backing != Backing::Ram {
+				entry |= PAGE_UNCACHED;
+			}
+			entry
+		};
+		unsafe { directory.add(chunk).write_volatile(entry) };
+	}
+
+	enable(paging.directory);
+	unsafe { PAGING = Some(paging) };
+	Ok(paging)
+}
+
+impl Paging {
+	/// The page table entry that identity maps the page at `addr`
+	fn page_entry(
+		&mut self,
+		memory_map: &MemoryMap,
+		guards: &[usize],
+		addr: usize,
+	) -> u32 {
+		if guards.contains(&addr) {
+			self.guard_pages += 1;
+			return 0;
+		}
+		let mut entry = addr as u32 | PAGE_PRESENT;
+		// The bounds are page aligned so a page starting inside is all inside
+		if !(self.readonly.0..self.readonly.1).contains(&addr) {
+			entry |= PAGE_WRITABLE;
+		}
+		if backing(memory_map, addr, PAGE_SIZE) != Backing::Ram {
+			entry |= PAGE_UNCACHED;
+		}
+		entry
+	}
+}
+
+/// Load the page directory and turn on paging for this processor
+fn enable(directory: usize) {
+	cpu::write_cr3(directory);
+	cpu::write_cr4(cpu::read_cr4() | CR4_PSE);
+	cpu::write_cr0(cpu::read_cr0() | CR0_PG | CR0_WP);
+}
+
+/// Turn on paging on an AP with the tables the BSP built, if it has any
+pub fn init_ap() {
+	if let Some(paging) = get() {
+		enable(paging.directory);
+	}
+}
+
+/// Make sure the device memory at `base..base + length` is mapped uncached,
+/// everything outside RAM already is so this is for drivers to be explicit
+/// about their BARs. Does nothing until paging is on
+pub fn map_mmio(base: usize, length: usize) {
+	let paging = match get() {
+		Some(paging) => paging,
+		None => return,
+	};
+	let directory = paging.directory as *mut u32;
+	let start = base & !(PAGE_SIZE - 1);
+	for addr in (start..base + length).step_by(PAGE_SIZE) {
+		unsafe {
+			let pde = directory.add(addr / LARGE_PAGE_SIZE);
+			let entry = pde.read_volatile();
+			if entry & PAGE_LARGE != 0 {
+				pde.write_volatile(entry | PAGE_PRESENT | PAGE_UNCACHED);
+			} else {
+				let table = (entry & PAGE_ADDR_MASK) as usize as *mut u32;
+				table.add((addr / PAGE_SIZE) % ENTRIES).write_volatile(
+					addr as u32 | PAGE_PRESENT | PAGE_WRITABLE | PAGE_UNCACHED,
+				);
+			}
+		}
+		cpu::invlpg(addr);
+	}
+}
+
+/// The page tables if [`init`] has turned paging on
+pub fn get() -> Option<Paging> {
+	unsafe { PAGING }
+}
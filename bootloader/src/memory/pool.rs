@@ -0,0 +1,114 @@
+//! A fixed-size buffer pool over a claimed region of physical memory, in
+//! keeping with the allocationless OS. Buffers are handed out as [`Buffer`]
+//! handles that go back to the pool when dropped, and as we identity map
+//! memory their address can be given straight to a device for DMA
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::regions;
+
+/// `COUNT` buffers of `SIZE` bytes, it is empty until [`Pool::init`] gives it
+/// a region so it can live in a `static`
+pub struct Pool<const SIZE: usize, const COUNT: usize> {
+	base: AtomicUsize,
+	/// Set while the buffer at that index is handed out
+	used: [AtomicBool; COUNT],
+}
+
+impl<const SIZE: usize, const COUNT: usize> Pool<SIZE, COUNT> {
+	pub const fn new() -> Self {
+		Self {
+			base: AtomicUsize::new(0),
+			used: [const { AtomicBool::new(false) }; COUNT],
+		}
+	}
+	/// Claim `COUNT * SIZE` bytes at `base` for `name` and start handing out
+	/// buffers from it. Every buffer is aligned to `align` so it must divide
+	/// both `base` and `SIZE`, this panics otherwise
+	pub fn init(&self, name: &'static str, base: usize, align: usize) {
+		if !align.is_power_of_two()
+			|| !base.is_multiple_of(align)
+			|| !SIZE.is_multiple_of(align)
+		{
+			panic!(
+				"Pool {} at {:#X} cannot give {} byte buffers aligned to {}",
+				name, base, SIZE, align
+			);
+		}
+		regions::claim(name, base as u64, (SIZE * COUNT) as u64);
+		self.base.store(base, Ordering::Release);
+	}
+	/// Take a buffer from the pool, it holds whatever was last written to it.
+	/// [`None`] if they are all in use or the pool has not been given a region
+	/// yet
+	pub fn alloc(&self) -> Option<Buffer<'_>> {
+		let base = self.base.load(Ordering::Acquire);
+		if base == 0 {
+			return None;
+		}
+		let (index, used) = self.used.iter().enumerate().find(|(_, used)| {
+			used
+				.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+				.is_ok()
+		})?;
+		Some(Buffer {
+			addr: base + index * SIZE,
+			len: SIZE,
+			used,
+		})
+	}
+	/// [`Pool::alloc`] a buffer and zero it, for when stale bytes past what
+	/// the caller writes could leak out such as the padding of a short frame
+	pub fn alloc_zeroed(&self) -> Option<Buffer<'_>> {
+		let mut buffer = self.alloc()?;
+		buffer.fill(0);
+		Some(buffer)
+	}
+	/// How many buffers are free
+	#[allow(dead_code)]
+	pub fn available(&self) -> usize {
+		self
+			.used
+			.iter()
+			.filter(|used| !used.load(Ordering::Relaxed))
+			.count()
+	}
+}
+
+/// A buffer from a [`Pool`], it goes back to the pool when dropped
+pub struct Buffer<'a> {
+	addr: usize,
+	len: usize,
+	used: &'a AtomicBool,
+}
+
+impl Buffer<'_> {
+	/// The physical address of the buffer to give to a device
+	pub fn addr(&self) -> u64 {
+		self.addr as u64
+	}
+}
+
+impl core::ops::Deref for Buffer<'_> {
+	type Target = [u8];
+	fn deref(&self) -> &[u8] {
+		unsafe { core::slice::from_raw_parts(self.addr as *const u8, self.len) }
+	}
+}
+
+impl core::ops::DerefMut for Buffer<'_> {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		unsafe { core::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+	}
+}
+
+impl Drop for Buffer<'_> {
+	fn drop(&mut self) {
+		self.used.store(false, Ordering::Release);
+	}
+}
+
+impl core::fmt::Debug for Buffer<'_> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "Buffer({:#X}, {})", self.addr, self.len)
+	}
+}
@@ -0,0 +1,218 @@
+//! A boot time registry of the physical memory each subsystem uses. We have no
+//! allocator so drivers still pick their own addresses, but they must
+//! [`claim`] them here first. A claim that overlaps another, falls outside
+//! usable RAM or lands on the kernel image or stack panics so a bad hardcoded
+//! address is caught at boot instead of corrupting memory
+use super::map;
+use crate::smp;
+
+/// Most claims we can track
+const MAX_REGIONS: usize = 64;
+/// Where the BIOS loads `stage0.asm`, the image follows it at 0x7e00
+const STAGE0_BASE: u64 = 0x7C00;
+/// Where `stage0.asm` leaves the E820 map, a count then the entries
+const E820_MAP_BASE: u64 = 0x5000;
+const E820_MAP_LEN: u64 = 8 + 24 * map::E820_MAX as u64;
+
+/// Every claim made so far, in the order they were made
+static mut REGIONS: [Option<Region>; MAX_REGIONS] = [None; MAX_REGIONS];
+
+/// A named range of physical memory claimed by a subsystem
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+	pub name: &'static str,
+	pub base: u64,
+	pub length: u64,
+}
+
+impl Region {
+	/// The first address past this region
+	pub fn end(&self) -> u64 {
+		self.base.saturating_add(self.length)
+	}
+	/// True if any byte is in both regions
+	fn overlaps(&self, other: &Region) -> bool {
+		self.base < other.end() && other.base < self.end()
+	}
+}
+
+impl core::fmt::Display for Region {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "{:#010X}-{:#010X} {}", self.base, self.end(), self.name)
+	}
+}
+
+/// Claim the memory we are already running in: the real mode bits
+/// `stage0.asm` left us, the kernel image which ends at `image_end` and the
+/// BSP stack
+pub fn init(image_end: u32) {
+	claim("E820 map", E820_MAP_BASE, E820_MAP_LEN);
+	claim("Kernel image", STAGE0_BASE, image_end as u64 - STAGE0_BASE);
+	claim(
+		"BSP stack",
+		(smp::BSP_STACK_TOP - smp::BSP_STACK_SIZE) as u64,
+		smp::BSP_STACK_SIZE as u64,
+	);
+}
+
+/// Claim `base..base + length` for `name`, panics if the range overlaps an
+/// earlier claim or is not inside usable RAM. Without an E820 map we cannot
+/// check the latter so only overlaps are caught
+pub fn claim(name: &'static str, base: u64, length: u64) {
+	let region = Region { name, base, length };
+	if length == 0 {
+		panic!("Memory claim is empty: {}", region);
+	}
+
+	if let Some(memory_map) = map::get() {
+		if memory_map.usable().next().is_some()
+			&& !memory_map.is_usable(base, length)
+		{
+			panic!("Memory claim is outside usable RAM: {}", region);
+		}
+	}
+
+	let regions = unsafe { &mut *core::ptr::addr_of_mut!(REGIONS) };
+	for other in regions.iter().flatten() {
+		if region.overlaps(other) {
+			panic!("Memory claim {} overlaps {}", region, other);
+		}
+	}
+	match regions.iter_mut().find(|slot| slot.is_none()) {
+		Some(slot) => *slot = Some(region),
+		None => panic!("Too many memory claims: {}", region),
+	}
+}
+
+/// The highest `align` aligned range of `length` bytes below 4GiB that is in
+/// usable RAM and not claimed yet, for subsystems that do not care where they
+/// live. Searching from the top keeps clear of the hardcoded low addresses
+/// drivers claim later in boot. `align` must be a power of two, this panics
+/// otherwise
+pub fn find_free(length: u64, align: u64) -> Option<u64> {
+	assert!(
+		align.is_power_of_two(),
+		"Alignment {:#X} is not a power of two",
+		align
+	);
+	let memory_map = map::get()?;
+	let align_down = |addr: u64| addr & !(align - 1);
+	let mut best = None;
+	for region in memory_map.usable() {
+		let end = region.end().min(1 << 32);
+		let mut base = match end.checked_sub(length) {
+			Some(base) => align_down(base),
+			None => continue,
+		};
+		while base >= region.base {
+			let candidate = Region {
+				name: "",
+				base,
+				length,
+			};
+			match regions().find(|other| candidate.overlaps(other)) {
+				None => {
+					best = best.max(Some(base));
+					break;
+				}
+				Some(other) => match other.base.checked_sub(length) {
+					Some(below) => base = align_down(below),
+					None => break,
+				},
+			}
+		}
+	}
+	best
+}
+
+/// Every claim made so far
+pub fn regions() -> impl Iterator<Item = Region> {
+	let regions = unsafe { REGIONS };
+	regions.into_iter().flatten()
+}
+
+/// Print every claim over serial, sorted by address
+pub fn dump() {
+	let mut sorted = unsafe { REGIONS };
+	sorted.sort_unstable_by_key(|region| region.map_or(u64::MAX, |r| r.base));
+	print!("Memory regions:\n");
+	for region in sorted.iter().flatten() {
+		print!("  {}\n", region);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn region(base: u64, length: u64) -> Region {
+		Region {
+			name: "test",
+			base,
+			length,
+		}
+	}
+
+	#[test]
+	fn overlaps() {
+		let a = region(0x1000, 0x1000);
+		assert!(a.overlaps(&region(0x1800, 0x1000)));
+		assert!(a.overlaps(&region(0x0800, 0x1000)));
+		assert!(a.overlaps(&region(0x1100, 0x10)));
+		assert!(region(0x1100, 0x10).overlaps(&a));
+		assert!(a.overlaps(&a));
+	}
+
+	#[test]
+	fn touching_regions_do_not_overlap() {
+		let a = region(0x1000, 0x1000);
+		assert!(!a.overlaps(&region(0x2000, 0x1000)));
+		assert!(!a.overlaps(&region(0x0000, 0x1000)));
+	}
+
+	#[test]
+	fn end_saturates() {
+		assert_eq!(region(0x1000, 0x1000).end(), 0x2000);
+		assert_eq!(region(u64::MAX - 1, 0x10).end(), u64::MAX);
+		assert!(region(u64::MAX - 1, 0x10).overlaps(&region(u64::MAX - 1, 1)));
+	}
+
+	/// Claims share one registry so the tests that make them take turns, and
+	/// each uses its own addresses
+	static REGISTRY: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+	fn lock() -> std::sync::MutexGuard<'static, ()> {
+		REGISTRY
+			.lock()
+			.unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+	#[test]
+	fn claim_adjacent() {
+		let _lock = lock();
+		claim("below", 0x10_0000, 0x1000);
+		claim("above", 0x10_1000, 0x1000);
+		assert!(regions().any(|r| r.name == "below" && r.base == 0x10_0000));
+		assert!(regions().any(|r| r.name == "above" && r.base == 0x10_1000));
+	}
+
+	#[test]
+	#[should_panic(expected = "overlaps")]
+	fn claim_overlap_panics() {
+		let _lock = lock();
+		claim("first", 0x20_0000, 0x2000);
+		claim("second", 0x20_1000, 0x2000);
+	}
+
+	#[test]
+	#[should_panic(expected = "empty")]
+	fn claim_empty_panics() {
+		let _lock = lock();
+		claim("empty", 0x30_0000, 0);
+	}
+	#[test]
+	#[should_panic(expected = "power of two")]
+	fn find_free_bad_align_panics() {
+		find_free(0x1000, 0x3000);
+	}
+}
@@ -2,7 +2,7 @@
 
 use super::NetworkStack;
 use super::Serialise;
-use super::MTU;
+use super::{Error, Result};
 
 pub const ARP_LEN: usize = 28;
 pub const ETHERNET_LEN: usize = 14;
@@ -53,23 +53,24 @@ impl Arp {
 	}
 
 	/// This function sends an arp request to find the Target MAC for a given IP
-	fn who_has(ns: &NetworkStack, target_ipv4: [u8; 4]) {
-		let mut buf = [0u8; MTU];
+	fn who_has(ns: &mut NetworkStack, target_ipv4: [u8; 4]) -> Result<()> {
+		let mut buf = ns.nic.transmit_buffer().ok_or(Error::NoTransmitBuffer)?;
 
 		let arp =
-			Arp::new([0, 1], ns.nic.mac, [0xFFu8; 6], ns.ip_addr, target_ipv4);
+			Arp::new([0, 1], ns.nic.mac(), [0xFFu8; 6], ns.ip_addr, target_ipv4);
 		let len = arp.serialise(&mut buf);
 
-		ns.nic.send(&buf, len)
+		ns.nic.send(buf, len).map_err(Error::Nic)
 	}
 	/// This function sends out an ARP saying we own an IP when asked
-	fn reply(&self, ns: &NetworkStack) {
-		let mut buf = [0u8; MTU];
+	fn reply(&self, ns: &mut NetworkStack) -> Result<()> {
+		let mut buf = ns.nic.transmit_buffer().ok_or(Error::NoTransmitBuffer)?;
 
-		let reply = Arp::new([0, 2], ns.nic.mac, self.sha, ns.ip_addr, self.spa);
+		let reply =
+			Arp::new([0, 2], ns.nic.mac(), self.sha, ns.ip_addr, self.spa);
 		let len = reply.serialise(&mut buf);
 
-		ns.nic.send(&buf, len)
+		ns.nic.send(buf, len).map_err(Error::Nic)
 	}
 	/// This function updates the arp table when we recieve ARP packets
 	fn update_arp_table(&self, ns: &mut NetworkStack) {
@@ -99,14 +100,15 @@ impl Arp {
 	}
 
 	/// This function deals with any arp work required
-	pub fn update(self, ns: &mut NetworkStack) {
+	pub fn update(self, ns: &mut NetworkStack) -> Result<()> {
 		// Update our arp table with any new information
 		self.update_arp_table(ns);
 
 		// If we see a request for our IP, reply
 		if self.tpa == ns.ip_addr {
-			self.reply(ns);
+			self.reply(ns)?;
 		}
+		Ok(())
 	}
 }
 
@@ -7,8 +7,6 @@ use super::Serialise;
 
 /// DHCP Magic number to signal this is a DHCP packet
 const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
-/// Hard coded transaction ID - Should randomise
-const TRANSACTION_ID: [u8; 4] = [0x13, 0x37, 0x13, 0x37];
 /// The opcode for a boot request
 const BOOT_REQUEST: u8 = 1;
 /// Hardware type ethernet
@@ -42,14 +40,14 @@ struct Dhcp<'a> {
 }
 
 impl<'a> Dhcp<'a> {
-	/// Allows the user to create a new [Dhcp] packet
-	fn new(src_mac: [u8; 6], msg_type: MessageType) -> Self {
+	/// Allows the user to create a new [Dhcp] packet for the exchange `xid`
+	fn new(src_mac: [u8; 6], xid: [u8; 4], msg_type: MessageType) -> Self {
 		Self {
 			op: BOOT_REQUEST,
 			htype: ETHERNET,
 			hlen: 6,
 			hops: 0,
-			xid: TRANSACTION_ID,
+			xid,
 			secs: [0u8; 2],
 			flags: [0u8; 2],
 			ciaddr: [0u8; 4],
@@ -236,9 +234,8 @@ impl<'a> Dhcp<'a> {
 		})
 	}
 	/// This function performs and DHCP Request
-	fn request(&self, ns: &mut super::NetworkStack) {
-		let mut request = Dhcp::new(ns.nic.mac, MessageType::Request);
-		request.xid = self.xid;
+	fn request(&self, ns: &mut super::NetworkStack) -> Result<()> {
+		let mut request = Dhcp::new(ns.nic.mac(), self.xid, MessageType::Request);
 
 		// Add on our options
 		let opts = [
@@ -257,11 +254,11 @@ impl<'a> Dhcp<'a> {
 			DHCP_PORT_SERVER,
 			&buf[..len],
 		)
-		.unwrap();
 	}
 	/// Broadcasts out a DHCP discover to everyone asking for an IP
-	fn discover(ns: &mut super::NetworkStack) {
-		let mut discover = Dhcp::new(ns.nic.mac, MessageType::Discover);
+	fn discover(ns: &mut super::NetworkStack) -> Result<()> {
+		let mut discover =
+			Dhcp::new(ns.nic.mac(), ns.dhcp_xid, MessageType::Discover);
 
 		let opts = [
 			Some(Options::MessageType(MessageType::Discover)),
@@ -280,7 +277,6 @@ impl<'a> Dhcp<'a> {
 			DHCP_PORT_SERVER,
 			&buf[..len],
 		)
-		.unwrap();
 	}
 }
 
@@ -473,10 +469,12 @@ pub enum Status {
 /// process DHCP packets
 #[inline(always)]
 pub fn update(ns: &mut super::NetworkStack, data: Option<&[u8]>) {
-	// If need an IP send a discover
+	// If need an IP send a discover, if it cannot go out we stay in NeedIP
+	// and try again on the next update
 	if ns.dhcp_status == Status::NeedIP {
-		Dhcp::discover(ns);
-		ns.dhcp_status = Status::DiscoverSent;
+		if Dhcp::discover(ns).is_ok() {
+			ns.dhcp_status = Status::DiscoverSent;
+		}
 		return;
 	}
 	// If we get a UDP packet on port [DHCP_PORT] lets check if any data
@@ -487,11 +485,17 @@ pub fn update(ns: &mut super::NetworkStack, data: Option<&[u8]>) {
 		// No UDP data provided
 		return;
 	};
+	// Replies to other clients booting alongside us
+	if dhcp.xid != ns.dhcp_xid {
+		return;
+	}
 	match dhcp.msg_type {
-		MessageType::Offer => {
-			dhcp.request(ns);
-			ns.dhcp_status = Status::RequestSent
-		}
+		// The offer is gone if our request cannot go out, so start again
+		// from a discover on the next update
+		MessageType::Offer => match dhcp.request(ns) {
+			Ok(()) => ns.dhcp_status = Status::RequestSent,
+			Err(_) => ns.dhcp_status = Status::NeedIP,
+		},
 		MessageType::Ack => {
 			ns.ip_addr = dhcp.yiaddr;
 			ns.dhcp_status = Status::Acquired;
@@ -4,7 +4,6 @@ pub mod dhcp;
 use super::NetworkStack;
 use super::Packet;
 use super::Serialise;
-use super::MTU;
 use super::{Error, Result};
 // Get rid of this TODO
 use super::Ethernet;
@@ -5,4 +5,11 @@ pub enum Error {
 	InvalidDhcpPacket,
 	BadDhcpMessageType(u8),
 	DestIPNotInArpTable([u8; 4]),
+	/// The IPv4 packet would be bigger than the MTU
+	PacketTooLarge(usize),
+	/// Every transmit buffer is waiting to be sent
+	NoTransmitBuffer,
+	/// The NIC would not take the frame, only read by `Debug`
+	#[allow(dead_code)]
+	Nic(crate::error::Error),
 }
@@ -28,7 +28,7 @@ impl IPv4 {
 			version_ihl: 0x45,
 			dcp_ecn: 0x00,
 			total_len: (IPV4_HEADER_LEN as u16 + len),
-			identification: (0x0100u16),
+			identification: crate::random::u32() as u16,
 			flags_fragmentoffset: 0x00,
 			ttl: 0x40,
 			protocol_type: 0x11,
@@ -17,11 +17,14 @@ use ip::{IPv4, Protocol, IPV4_HEADER_LEN};
 use packet::{EtherType, Packet};
 use udp::{Udp, UDP_HEADER_LEN};
 
-/// Maximum packet size we deal with, this is a mut ref to a buffer we pass
-/// around to create our raw packet for sending to the NIC
+/// Maximum IPv4 packet size we deal with, the packet buffers from the NIC are
+/// big enough for this plus the Ethernet header
 const MTU: usize = 1500;
 /// DHCP UDP Port number we listen on
 const DHCP_PORT_CLIENT: u16 = 68;
+/// Start of the IANA dynamic port range we pick our source port from
+const EPHEMERAL_PORT_BASE: u16 = 49152;
+const EPHEMERAL_PORT_COUNT: u32 = 16384;
 /// DHCP UDP Port number we listen on
 const MAC_LEN: usize = 6;
 /// Ethernet Ether Type Identifier
@@ -30,13 +33,17 @@ pub const ETH_ETHER_TYPE: [u8; 2] = [0x08, 0x06];
 pub const IPV4_ETHER_TYPE: [u8; 2] = [0x08, 0x00];
 
 pub struct NetworkStack {
-	nic: nic::NetworkCard,
+	nic: &'static mut dyn nic::NetDevice,
 	arp_table: [([u8; MAC_LEN], [u8; 4]); 10],
 	// IPs to send to ARP
 	requested_ips: [[u8; 4]; 5],
 	ip_addr: [u8; 4],
+	/// Random UDP source port we send from
+	src_port: u16,
 	/// State machine for DHCP
 	dhcp_status: dhcp::Status,
+	/// Random transaction ID of our DHCP exchange
+	dhcp_xid: [u8; 4],
 }
 
 impl NetworkStack {
@@ -45,6 +52,13 @@ impl NetworkStack {
 	pub fn init() -> Option<Self> {
 		match nic::init() {
 			Ok(nic) => {
+				// Machines that boot together without RDRAND still differ in
+				// their MAC address
+				crate::random::mix(&nic.mac());
+				let src_port = EPHEMERAL_PORT_BASE
+					+ (crate::random::u32() % EPHEMERAL_PORT_COUNT) as u16;
+				let mut dhcp_xid = [0u8; 4];
+				crate::random::fill_bytes(&mut dhcp_xid);
 				// Once we have a NIC we can use, we need an IPv4 Address
 				let mut requested_ips = [[0u8; 4]; 5];
 				requested_ips[0] = [0xff; 4];
@@ -53,7 +67,9 @@ impl NetworkStack {
 					arp_table: Default::default(),
 					requested_ips: [[0u8; 4]; 5],
 					ip_addr: [0, 0, 0, 0],
+					src_port,
 					dhcp_status: dhcp::Status::NeedIP,
+					dhcp_xid,
 				})
 			}
 			Err(e) => {
@@ -70,13 +86,18 @@ impl NetworkStack {
 		// Get the packets from the NIC and handle them before actioning
 		// any required packets
 		let packets = self.nic.receive();
-		for packet in packets.iter().flatten() {
+		let packets = packets
+			.into_iter()
+			.flatten()
+			.filter_map(|(buf, len)| Packet::parse(buf, len));
+		for packet in packets {
 			match packet.ether_type {
 				// Handle Arp packets
 				EtherType::Arp(ref arp) => {
 					// If we recieve an Arp we process it, replying to
-					// requests and updating the arp table
-					arp.update(self);
+					// requests and updating the arp table. If our reply
+					// cannot go out the asker will ask again
+					let _ = arp.update(self);
 				}
 				// Handle IPv4 packets
 				EtherType::IPv4(ref ipv4) => match ipv4.protocol {
@@ -84,10 +105,7 @@ impl NetworkStack {
 						if udp.dst_port == DHCP_PORT_CLIENT {
 							// If we recieve a DHCP packet, send it off to the
 							// DHCP Agent to handle
-							dhcp::update(
-								self,
-								Some(&packet.data.unwrap()[..udp.len as usize]),
-							);
+							dhcp::update(self, packet.data());
 						}
 					}
 				},
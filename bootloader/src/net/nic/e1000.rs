@@ -1,17 +1,18 @@
-//! This crate will manage the finding of network cards in a
-//! [`crate::pci::Device`] and initialising them and exposing to the rest of the
-//! OS our main entry points from our OS to our nic are [NetworkCard::send] and
-//! [NetworkCard::receive]
-use super::Packet;
-use super::MTU;
+//! This crate is the driver for the Intel E1000 QEMU emulates, it receives
+//! into a ring of [`RECEIVE_DESC_BUF_LENGTH`] buffers and sends from a single
+//! transmit descriptor
+use core::time::Duration;
+
+use super::{Capabilities, NetDevice, PacketBuffer, Received, RECEIVE_BATCH};
 use crate::error::{Error, Result};
+use crate::memory::pool::Pool;
+use crate::memory::{paging, regions};
+use crate::time::Instant;
 
-// Supported Nics
-// E1000 Qemu Versions
-const E1000: (u16, u16) = (0x100E, 0x8086);
 const PACKET_SIZE: u64 = 2048;
 
 // Register offsets of the E1000
+const REG_STATUS: u32 = 0x0008;
 const REG_RCTL: u32 = 0x0100;
 const REG_RDBAL: u32 = 0x2800;
 const REG_RDBAH: u32 = 0x2804;
@@ -27,6 +28,15 @@ const REG_TCTL: u32 = 0x0400;
 const REG_RAL: u32 = 0x5400;
 const REG_RAH: u32 = 0x5404;
 
+/// Status register: Link up
+const STATUS_LU: u32 = 1 << 1;
+/// Transmit descriptor status: Descriptor Done, the NIC has finished with the
+/// packet and its buffer
+const TDESC_STATUS_DD: u8 = 1 << 0;
+/// How long we wait for the NIC to finish the last packet before giving up on
+/// the next one, a full frame takes ~1.2ms at 10Mb/s
+const TRANSMIT_TIMEOUT: Duration = Duration::from_millis(10);
+
 const RECEIVE_DESC_BASE_ADDRESS: u64 = 0x800000;
 const RECEIVE_DESC_BUF_LENGTH: u32 = 8;
 const RECEIVE_BASE_BUFFER_ADDRESS: u64 = 0x880000;
@@ -40,6 +50,26 @@ const TRANSMIT_BASE_BUFFER_ADDRESS: u64 = 0x980000;
 const TRANSMIT_QUEUE_HEAD_START: u32 = 0;
 const TRANSMIT_QUEUE_TAIL_START: u32 = 0;
 
+/// The E1000 wants its packet buffers aligned to their size
+const PACKET_ALIGN: usize = PACKET_SIZE as usize;
+/// Twice the ring so every descriptor can be refilled while the packets we
+/// just received are still being handled
+const RECEIVE_POOL_LEN: usize = 2 * RECEIVE_DESC_BUF_LENGTH as usize;
+const TRANSMIT_POOL_LEN: usize = TRANSMIT_DESC_BUF_LENGTH as usize;
+
+/// Buffers the NIC receives into, each is handed up inside a [`Packet`]
+/// instead of being copied out
+static RECEIVE_POOL: Pool<{ PACKET_SIZE as usize }, RECEIVE_POOL_LEN> =
+	Pool::new();
+/// Buffers we build packets in, the NIC reads them straight from here
+static TRANSMIT_POOL: Pool<{ PACKET_SIZE as usize }, TRANSMIT_POOL_LEN> =
+	Pool::new();
+
+/// The driver once [`init`] has set the card up
+static mut NIC: Option<E1000> = None;
+
+const _: () = assert!(RECEIVE_DESC_BUF_LENGTH as usize <= RECEIVE_BATCH);
+
 /// This struct is the receive descriptor format that stores the packet metadata
 /// and the buffer points to the packet location in memory
 #[derive(Debug, Default)]
@@ -58,10 +88,21 @@ impl Rdesc {
 	/// such as the memory base address, tail/head, and size of buffer
 	/// Sets up a buffer of [`Rdesc`]'s with [RECEIVE_DESC_BUF_LENGTH] length
 	/// and writes them to [RECEIVE_DESC_BASE_ADDRESS]
-	/// We set the [`Rdesc.buffer`] field to the address where we want the raw
-	/// packet to be, this packet size is determined by [PACKET_SIZE] this
-	/// allocation is YOLO as we dont check ANYTHING
-	pub fn init(nic: &NetworkCard) {
+	/// We set the [`Rdesc.buffer`] field to a buffer from [`RECEIVE_POOL`]
+	/// which the NIC keeps until a packet arrives in it, both regions are
+	/// claimed with [`regions::claim`] so a bad address panics at boot
+	pub fn init(nic: &mut E1000) {
+		regions::claim(
+			"E1000 RX descriptors",
+			RECEIVE_DESC_BASE_ADDRESS,
+			(RECEIVE_DESC_BUF_LENGTH as u64) << 8,
+		);
+		RECEIVE_POOL.init(
+			"E1000 RX buffers",
+			RECEIVE_BASE_BUFFER_ADDRESS as usize,
+			PACKET_ALIGN,
+		);
+
 		// Set the Receive Descriptor Length
 		nic.write(REG_RDLEN, RECEIVE_DESC_BUF_LENGTH << 8);
 
@@ -81,15 +122,17 @@ impl Rdesc {
 			(1 << 0) | (1 << 1) | (1 << 3) | (1 << 4) | (1 << 15) | (1 << 26),
 		);
 
-		// Zero out the chosen memory location and place the memory location for
-		// the raw packets in the Recieve buffer field in the [`Rdesc`]
-		// struct
+		// Zero out the chosen memory location and place a buffer from the pool
+		// in the Recieve buffer field in the [`Rdesc`] struct, the pool is
+		// bigger than the ring so this cannot run out
 		let rdesc_base_ptr = RECEIVE_DESC_BASE_ADDRESS as *mut Rdesc;
 		for offset in 0..RECEIVE_DESC_BUF_LENGTH as isize {
+			let buffer = RECEIVE_POOL.alloc().unwrap();
 			let rdesc = Self {
-				buffer: RECEIVE_BASE_BUFFER_ADDRESS + (offset as u64 * PACKET_SIZE),
+				buffer: buffer.addr(),
 				..Default::default()
 			};
+			nic.rx_buffers[offset as usize] = Some(buffer);
 			unsafe {
 				core::ptr::write(rdesc_base_ptr.offset(offset), rdesc);
 			}
@@ -112,7 +155,18 @@ struct Tdesc {
 }
 
 impl Tdesc {
-	pub fn init(nic: &NetworkCard) {
+	pub fn init(nic: &E1000) {
+		regions::claim(
+			"E1000 TX descriptors",
+			TRANSMIT_DESC_BASE_ADDRESS,
+			(TRANSMIT_DESC_BUF_LENGTH as u64) << 8,
+		);
+		TRANSMIT_POOL.init(
+			"E1000 TX buffers",
+			TRANSMIT_BASE_BUFFER_ADDRESS as usize,
+			PACKET_ALIGN,
+		);
+
 		// Set the Transmit Descriptor Length
 		nic.write(REG_TDLEN, TRANSMIT_DESC_BUF_LENGTH << 8);
 
@@ -128,38 +182,45 @@ impl Tdesc {
 		//serial_print!("TX CTRL: {:#b}\n",nic.read(0x400));
 		nic.write(REG_TCTL, 1 << 1);
 
-		// Zero out the chosen memory location and place the memory location for
-		// the raw packets in the Transmit buffer field in the [`Rdesc`]
-		// struct
+		// Zero out the chosen memory location, [`NetDevice::send`] fills in
+		// the buffer of a [`Tdesc`] with the one it is sending
 		let tdesc_base_ptr = TRANSMIT_DESC_BASE_ADDRESS as *mut Tdesc;
 		for offset in 0..TRANSMIT_DESC_BUF_LENGTH as isize {
-			let tdesc = Self {
-				buffer: TRANSMIT_BASE_BUFFER_ADDRESS
-					+ (offset as u64 * PACKET_SIZE),
-				..Default::default()
-			};
 			unsafe {
-				core::ptr::write(tdesc_base_ptr.offset(offset), tdesc);
+				core::ptr::write(tdesc_base_ptr.offset(offset), Self::default());
 			}
 		}
 	}
 }
 /// This struct finds the network card and stores information we need from it
-#[derive(Default, Debug, Clone, Copy)]
-pub struct NetworkCard {
+#[derive(Default, Debug)]
+pub struct E1000 {
 	mmio_base: u32,
-	pub mac: [u8; 6],
+	mac: [u8; 6],
+	/// The buffer each receive descriptor points at
+	rx_buffers: [Option<PacketBuffer>; RECEIVE_DESC_BUF_LENGTH as usize],
+	/// The last buffer we sent, the NIC may still be reading it
+	tx_buffer: Option<PacketBuffer>,
 }
 
-impl NetworkCard {
-	/// Create new instance of Network card and get MAC
-	fn new(device: crate::pci::Device) -> Self {
+impl E1000 {
+	/// Create new instance of Network card and get MAC, the registers are
+	/// behind BAR0 which has to be below 4GiB for us to reach it
+	fn new(device: crate::pci::Device) -> Result<Self> {
+		let (base, size) = match device.bar(0).and_then(|bar| bar.mmio()) {
+			Some((base, size)) if base + size <= 1 << 32 => (base, size),
+			_ => return Err(Error::NoMmioBar(device.did_vid())),
+		};
+		// We need the registers mapped and the NIC must be able to DMA our
+		// descriptors and buffers
+		device.enable(true, true, false);
 		let mut nic = Self {
-			mmio_base: device.base_mem_addrs()[0],
+			mmio_base: base as u32,
 			..Default::default()
 		};
+		paging::map_mmio(base as usize, size as usize);
 		nic.get_mac();
-		nic
+		Ok(nic)
 	}
 	/// Read from a register offset in the MMIO buffer
 	fn read(&self, reg_offset: u32) -> u32 {
@@ -185,9 +246,28 @@ impl NetworkCard {
 			(lower16 >> 8) as u8,
 		]
 	}
+}
+
+impl NetDevice for E1000 {
+	fn mac(&self) -> [u8; 6] {
+		self.mac
+	}
+	fn link_up(&self) -> bool {
+		self.read(REG_STATUS) & STATUS_LU != 0
+	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			mtu: crate::net::MTU,
+		}
+	}
+	fn transmit_buffer(&mut self) -> Option<PacketBuffer> {
+		TRANSMIT_POOL.alloc_zeroed()
+	}
 	/// This function will be able to send packets and will be exposed
-	/// We currently only support one descriptor in the buffer
-	pub fn send(&self, buf: &[u8; MTU], len: usize) {
+	/// We currently only support one descriptor in the buffer, if the NIC
+	/// has not finished with the last packet we gave it within
+	/// [TRANSMIT_TIMEOUT] the packet is dropped
+	fn send(&mut self, buf: PacketBuffer, len: usize) -> Result<()> {
 		// 48 is the minimum packet size
 		let len = if len < 48 { 48 } else { len };
 
@@ -196,14 +276,25 @@ impl NetworkCard {
 		unsafe {
 			// Get the current tdesc (index 0)
 			let mut tdesc: Tdesc = core::ptr::read(tdesc_base_ptr.offset(0));
+			// The NIC may still be reading the last buffer, it is not ours to
+			// release until Descriptor Done is set
+			if self.tx_buffer.is_some() {
+				let start = Instant::now();
+				while tdesc.status & TDESC_STATUS_DD == 0 {
+					if start.elapsed() > TRANSMIT_TIMEOUT {
+						return Err(Error::TransmitBusy);
+					}
+					tdesc = core::ptr::read_volatile(tdesc_base_ptr.offset(0));
+				}
+			}
 			// If the status indicates it has been procesed, move the tail down
 			// again
-			if tdesc.status == 1 {
+			if tdesc.status & TDESC_STATUS_DD != 0 {
 				self.write(REG_TDT, (self.read(REG_TDT) - 1) % 32);
 				tdesc.status = 0;
 			}
-			// Write the packet to the buffer
-			core::ptr::write(tdesc.buffer as *mut [u8; MTU], *buf);
+			// Point the descriptor at our buffer, the NIC reads it directly
+			tdesc.buffer = buf.addr();
 
 			// serial_print!("Sent Packet! H: {}, T: {}, Pos: {}, {:X?}\n",
 			//     self.read(REG_TDH),
@@ -223,13 +314,15 @@ impl NetworkCard {
 			// Moves the Tail up to request the NIC to process the packet
 			self.write(REG_TDT, (self.read(REG_TDT) + 1) % 32);
 		}
+		// Hold on to the buffer until the next send as the NIC is reading it,
+		// the one we replace has been sent and goes back to the pool
+		self.tx_buffer = Some(buf);
+		Ok(())
 	}
 	/// This function processes the emails in buffer of buffer size
 	/// [RECEIVE_DESC_BUF_LENGTH]
-	pub fn receive(&self) -> [Option<Packet>; RECEIVE_DESC_BUF_LENGTH as usize] {
-		let mut received_packets: [Option<Packet>;
-			RECEIVE_DESC_BUF_LENGTH as usize] =
-			[Default::default(); RECEIVE_DESC_BUF_LENGTH as usize];
+	fn receive(&mut self) -> Received {
+		let mut received_packets: Received = Default::default();
 		let mut packet_counter = 0;
 		let rdesc_base_ptr = RECEIVE_DESC_BASE_ADDRESS as *mut Rdesc;
 
@@ -245,17 +338,18 @@ impl NetworkCard {
 				if rdesc.status != 0 {
 					print!("Packed Recieved!\n");
 
-					// Read the data from the packet
-					let buf = &*core::ptr::slice_from_raw_parts(
-						rdesc.buffer as *const u8,
-						rdesc.len as usize,
-					);
-					// Try to parse the packet and add it to the array to hand
-					// back to the OS
-					let packet = Packet::parse(buf, rdesc.len as usize);
-
-					received_packets[packet_counter] = packet;
-					packet_counter += 1;
+					// Swap a fresh buffer into the descriptor and hand the
+					// one holding the packet up, if the pool is empty the
+					// packet is dropped and its buffer reused
+					if let Some(fresh) = RECEIVE_POOL.alloc() {
+						rdesc.buffer = fresh.addr();
+						let buf =
+							self.rx_buffers[offset as usize].replace(fresh).unwrap();
+						// Add it to the array to hand back to the OS
+						received_packets[packet_counter] =
+							Some((buf, rdesc.len as usize));
+						packet_counter += 1;
+					}
 
 					// We have processed the packet and set status to 0 to
 					// indicate the buffer can overwrite
@@ -276,31 +370,22 @@ impl NetworkCard {
 		received_packets
 	}
 }
-/// Main entry point to net that sets up the drivers
-pub fn init() -> Result<NetworkCard> {
-	// This will get us the first device that is an Ethernet Network Card or
-	// return an Error
-	let device = match crate::pci::init().get_nic() {
-		Some(device) => {
-			// Error if we dont recongise NIC
-			let did_vid = device.did_vid();
-			if did_vid != E1000 {
-				return Err(Error::UnsupportedNIC(did_vid));
-			}
-			device
-		}
-		None => return Err(Error::NoNICFound),
-	};
-
+/// Sets up the E1000 at `device`, its entry in [`super::DRIVERS`] makes sure
+/// it is one
+pub fn init(device: crate::pci::Device) -> Result<&'static mut dyn NetDevice> {
 	// Create a new NIC
-	let nic = NetworkCard::new(device);
+	let mut nic = E1000::new(device)?;
 
 	// Puts the Recieve registers into our desired state and Allocates all the
 	// buffers and memory
-	Rdesc::init(&nic);
+	Rdesc::init(&mut nic);
 
 	// Puts the Transmit registers into our desired state
 	Tdesc::init(&nic);
 
+	let nic = unsafe {
+		NIC = Some(nic);
+		(*core::ptr::addr_of_mut!(NIC)).as_mut().unwrap()
+	};
 	Ok(nic)
 }
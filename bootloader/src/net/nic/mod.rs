@@ -0,0 +1,153 @@
+//! This crate will manage the finding of network cards in a
+//! [`crate::pci::Device`] and initialising them and exposing them to the rest
+//! of the OS. Every driver implements [`NetDevice`] and has an entry in
+//! [`DRIVERS`] saying which devices it handles, so adding a NIC is one driver
+//! module and one entry there. Our main entry points from our OS to our nic
+//! are [`NetDevice::send`] and [`NetDevice::receive`]
+use crate::error::{Error, Result};
+use crate::memory::pool::Buffer;
+use crate::pci::Device;
+
+mod e1000;
+mod rtl8139;
+mod virtio;
+
+/// Most packets one call to [`NetDevice::receive`] hands back
+pub const RECEIVE_BATCH: usize = 8;
+
+/// A packet sized buffer the NIC can DMA to or from
+pub type PacketBuffer = Buffer<'static>;
+
+/// The buffers [`NetDevice::receive`] hands back with the length of the frame
+/// in each
+pub type Received = [Option<(PacketBuffer, usize)>; RECEIVE_BATCH];
+
+/// What a NIC can do for the stack above it
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+	/// Largest IPv4 packet we can send, without the Ethernet header
+	pub mtu: usize,
+}
+
+/// A network card driver as [`crate::net::NetworkStack`] sees it
+pub trait NetDevice {
+	/// The MAC address the NIC receives unicast frames for
+	fn mac(&self) -> [u8; 6];
+	/// True if the NIC has a link to the network
+	fn link_up(&self) -> bool;
+	fn capabilities(&self) -> Capabilities;
+	/// Take a zeroed buffer to build a packet in for [`NetDevice::send`],
+	/// [`None`] if they are all in use
+	fn transmit_buffer(&mut self) -> Option<PacketBuffer>;
+	/// Send the first `len` bytes of `buf` as one Ethernet frame, the driver
+	/// holds on to the buffer until the NIC is done with it. If the NIC has
+	/// nowhere to put it the frame is dropped with [`Error::TransmitBusy`]
+	fn send(&mut self, buf: PacketBuffer, len: usize) -> Result<()>;
+	/// Take every frame that has arrived since the last call, up to
+	/// [`RECEIVE_BATCH`]
+	fn receive(&mut self) -> Received;
+}
+
+/// How a [`Driver`] recognises a device
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+	Id {
+		vendor: u16,
+		device: u16,
+	},
+	/// For drivers that handle any device of a kind, none of ours do yet
+	#[allow(dead_code)]
+	Class {
+		class: u8,
+		subclass: u8,
+	},
+}
+
+impl Match {
+	fn matches(&self, device: &Device) -> bool {
+		match *self {
+			Match::Id { vendor, device: id } => device.did_vid() == (id, vendor),
+			Match::Class { class, subclass } => {
+				device.class() == (class, subclass)
+			}
+		}
+	}
+}
+
+/// A NIC driver, `init` sets up the device and hands back the driver which
+/// lives in a static of its module
+pub struct Driver {
+	pub name: &'static str,
+	/// The devices the driver handles, any one of them matching is enough
+	pub matches: &'static [Match],
+	pub init: fn(Device) -> Result<&'static mut dyn NetDevice>,
+}
+
+/// Every NIC driver we have, the first one matching a device gets it
+static DRIVERS: &[Driver] = &[
+	Driver {
+		name: "E1000",
+		// E1000 Qemu Versions
+		matches: &[Match::Id {
+			vendor: 0x8086,
+			device: 0x100E,
+		}],
+		init: e1000::init,
+	},
+	Driver {
+		name: "virtio-net",
+		// The transitional device with both transports and the modern only one
+		matches: &[
+			Match::Id {
+				vendor: 0x1AF4,
+				device: 0x1000,
+			},
+			Match::Id {
+				vendor: 0x1AF4,
+				device: 0x1041,
+			},
+		],
+		init: virtio::init,
+	},
+	Driver {
+		name: "RTL8139",
+		matches: &[Match::Id {
+			vendor: 0x10EC,
+			device: 0x8139,
+		}],
+		init: rtl8139::init,
+	},
+];
+
+/// Main entry point to net that sets up the drivers. We use the first PCI
+/// device a driver in [`DRIVERS`] matches and initialises, an Ethernet
+/// controller without a driver is an [`Error::UnsupportedNIC`]
+pub fn init() -> Result<&'static mut dyn NetDevice> {
+	let devices = crate::pci::get().ok_or(Error::NoNICFound)?;
+	let mut result = Err(Error::NoNICFound);
+	for device in devices.iter() {
+		let driver = DRIVERS
+			.iter()
+			.find(|driver| driver.matches.iter().any(|m| m.matches(device)));
+		if let Some(driver) = driver {
+			match (driver.init)(*device) {
+				Ok(nic) => {
+					print!(
+						"NIC: {} {:04X?}, link {}\n",
+						driver.name,
+						device.did_vid(),
+						if nic.link_up() { "up" } else { "down" }
+					);
+					return Ok(nic);
+				}
+				Err(e) => result = Err(e),
+			}
+		}
+	}
+	// Error if we dont recongise the NIC
+	if let (Err(Error::NoNICFound), Some(device)) = (&result, devices.get_nic())
+	{
+		result = Err(Error::UnsupportedNIC(device.did_vid()));
+	}
+	result
+}
@@ -0,0 +1,255 @@
+//! This crate is the driver for the Realtek RTL8139, found on older machines
+//! and emulated by QEMU with `-device rtl8139`. Its registers are in I/O space
+//! behind BAR0. It receives into one contiguous ring which we copy each packet
+//! out of, and sends from four transmit descriptors used round robin
+//! `<https://wiki.osdev.org/RTL8139>`
+use super::{Capabilities, NetDevice, PacketBuffer, Received, RECEIVE_BATCH};
+use crate::cpu;
+use crate::error::{Error, Result};
+use crate::memory::pool::Pool;
+use crate::memory::regions;
+use crate::pci::{Bar, Device};
+
+const PACKET_SIZE: usize = 2048;
+
+// Register offsets of the RTL8139
+const REG_IDR0: u16 = 0x00;
+const REG_TSD0: u16 = 0x10;
+const REG_TSAD0: u16 = 0x20;
+const REG_RBSTART: u16 = 0x30;
+const REG_CR: u16 = 0x37;
+const REG_CAPR: u16 = 0x38;
+const REG_IMR: u16 = 0x3C;
+const REG_ISR: u16 = 0x3E;
+const REG_TCR: u16 = 0x40;
+const REG_RCR: u16 = 0x44;
+const REG_CONFIG1: u16 = 0x52;
+const REG_MSR: u16 = 0x58;
+
+/// Command register: Reset, receiver and transmitter enable, and the ring is
+/// empty
+const CR_RST: u8 = 1 << 4;
+const CR_RE: u8 = 1 << 3;
+const CR_TE: u8 = 1 << 2;
+const CR_BUFE: u8 = 1 << 0;
+/// Interrupt status bits for receive, written back to clear them. An overflow
+/// stops the receiver until it is cleared
+const ISR_RECEIVE: u16 = (1 << 0) | (1 << 1) | (1 << 4) | (1 << 6);
+/// Transmit status: Size in the low bits, OWN once the chip has copied the
+/// packet out of our buffer
+const TSD_OWN: u32 = 1 << 13;
+/// Transmit config: Normal interframe gap and unlimited DMA burst
+const TCR_DEFAULT: u32 = (0x3 << 24) | (0x7 << 8);
+/// Receive config: Accept physical match, multicast and broadcast, unlimited
+/// DMA burst, an 8KiB ring and let a packet run past the end of the ring
+/// instead of wrapping it
+const RCR_DEFAULT: u32 = (1 << 1) | (1 << 2) | (1 << 3) | (0x7 << 8) | (1 << 7);
+/// Media status: Set while the link is down
+const MSR_LINKB: u8 = 1 << 2;
+/// Receive packet header status: Received OK
+const RX_ROK: u16 = 1 << 0;
+/// Smallest and biggest frame the receive header can hold, with the CRC
+const RX_MIN_LEN: usize = 64;
+const RX_MAX_LEN: usize = 1518;
+/// The chip reports each frame with its CRC on the end
+const CRC_LEN: usize = 4;
+/// We pad frames shorter than the Ethernet minimum, without the CRC
+const TX_MIN_LEN: usize = 60;
+
+/// Transmit descriptors, the chip uses them in order
+const TRANSMIT_DESC_LEN: usize = 4;
+/// The receive ring, plus 16 bytes the chip may write past it and room for a
+/// whole packet to run over the end
+const RECEIVE_RING_LEN: usize = 0x2000;
+const RECEIVE_RING_SIZE: u64 = (RECEIVE_RING_LEN + 16 + PACKET_SIZE) as u64;
+const RECEIVE_RING_ADDRESS: u64 = 0xC00000;
+const RECEIVE_BASE_BUFFER_ADDRESS: u64 = 0xC80000;
+const TRANSMIT_BASE_BUFFER_ADDRESS: u64 = 0xD00000;
+
+/// Buffers we copy received packets into, twice a batch so one can be handed
+/// up while the last is still being handled
+static RECEIVE_POOL: Pool<PACKET_SIZE, { 2 * RECEIVE_BATCH }> = Pool::new();
+/// Buffers we build packets in, the chip reads them straight from here
+static TRANSMIT_POOL: Pool<PACKET_SIZE, TRANSMIT_DESC_LEN> = Pool::new();
+
+/// The driver once [`init`] has set the card up
+static mut NIC: Option<Rtl8139> = None;
+
+/// This struct holds the RTL8139 and where we are in its rings
+#[derive(Debug)]
+pub struct Rtl8139 {
+	port: u16,
+	mac: [u8; 6],
+	/// Where the next packet starts in the receive ring
+	rx_offset: usize,
+	/// The transmit descriptor we use next
+	tx_next: usize,
+	/// The buffer each transmit descriptor was given, the chip may still be
+	/// reading it
+	tx_buffers: [Option<PacketBuffer>; TRANSMIT_DESC_LEN],
+}
+
+impl Rtl8139 {
+	/// Turn the receiver off and on again, which empties the ring, after it
+	/// has given us a header that makes no sense
+	fn reset_receive(&mut self) {
+		cpu::out8(self.port + REG_CR, CR_TE);
+		cpu::out8(self.port + REG_CR, CR_RE | CR_TE);
+		cpu::out32(self.port + REG_RCR, RCR_DEFAULT);
+		self.rx_offset = 0;
+	}
+	/// Give the buffers of every descriptor the chip has finished sending back
+	/// to the pool
+	fn reclaim(&mut self) {
+		for (slot, buf) in self.tx_buffers.iter_mut().enumerate() {
+			let tsd = self.port + REG_TSD0 + 4 * slot as u16;
+			if buf.is_some() && cpu::in32(tsd) & TSD_OWN != 0 {
+				*buf = None;
+			}
+		}
+	}
+}
+
+impl NetDevice for Rtl8139 {
+	fn mac(&self) -> [u8; 6] {
+		self.mac
+	}
+	fn link_up(&self) -> bool {
+		cpu::in8(self.port + REG_MSR) & MSR_LINKB == 0
+	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			mtu: crate::net::MTU,
+		}
+	}
+	/// Every buffer may still be parked on a descriptor, so take back the ones
+	/// that have been sent first
+	fn transmit_buffer(&mut self) -> Option<PacketBuffer> {
+		self.reclaim();
+		TRANSMIT_POOL.alloc_zeroed()
+	}
+	/// Give `buf` to the next descriptor in turn, if the chip has not finished
+	/// with the last packet we gave it the packet is dropped
+	fn send(&mut self, buf: PacketBuffer, len: usize) -> Result<()> {
+		let slot = self.tx_next;
+		let tsd = self.port + REG_TSD0 + 4 * slot as u16;
+		self.reclaim();
+		if self.tx_buffers[slot].is_some() {
+			return Err(Error::TransmitBusy);
+		}
+		// Transmit buffers come zeroed so the padding is already there
+		let len = len.clamp(TX_MIN_LEN, PACKET_SIZE);
+		cpu::out32(self.port + REG_TSAD0 + 4 * slot as u16, buf.addr() as u32);
+		// Writing the size clears OWN and starts the transmit
+		cpu::out32(tsd, len as u32);
+		// Held until the chip is done with it and `reclaim` frees it
+		self.tx_buffers[slot] = Some(buf);
+		self.tx_next = (slot + 1) % TRANSMIT_DESC_LEN;
+		Ok(())
+	}
+	/// Copy each packet in the ring into a buffer to hand up. Each starts with
+	/// a status and a length, and the next one starts on the next dword. If
+	/// the pool is empty the packet is dropped
+	fn receive(&mut self) -> Received {
+		let mut received_packets: Received = Default::default();
+		let mut packet_counter = 0;
+		cpu::out16(self.port + REG_ISR, ISR_RECEIVE);
+
+		while packet_counter < RECEIVE_BATCH
+			&& cpu::in8(self.port + REG_CR) & CR_BUFE == 0
+		{
+			let header = RECEIVE_RING_ADDRESS as usize + self.rx_offset;
+			let (status, len) = unsafe {
+				(
+					(header as *const u16).read_volatile(),
+					((header + 2) as *const u16).read_volatile() as usize,
+				)
+			};
+			if status & RX_ROK == 0 || !(RX_MIN_LEN..=RX_MAX_LEN).contains(&len) {
+				self.reset_receive();
+				break;
+			}
+
+			if let Some(mut buf) = RECEIVE_POOL.alloc() {
+				let frame = unsafe {
+					core::slice::from_raw_parts(
+						(header + 4) as *const u8,
+						len - CRC_LEN,
+					)
+				};
+				buf[..frame.len()].copy_from_slice(frame);
+				received_packets[packet_counter] = Some((buf, frame.len()));
+				packet_counter += 1;
+			}
+
+			self.rx_offset = (self.rx_offset + 4 + len + 3) & !3;
+			self.rx_offset %= RECEIVE_RING_LEN;
+			// The chip wants the read pointer 16 bytes behind where we are
+			cpu::out16(
+				self.port + REG_CAPR,
+				(self.rx_offset as u16).wrapping_sub(16),
+			);
+		}
+		received_packets
+	}
+}
+
+/// Sets up the RTL8139 at `device`: power it on, reset it, point it at the
+/// receive ring and turn on the receiver and transmitter. We poll so every
+/// interrupt stays masked
+pub fn init(device: Device) -> Result<&'static mut dyn NetDevice> {
+	let port = match device.bar(0) {
+		Some(Bar::Io { port, .. }) => port,
+		_ => return Err(Error::NoIoBar(device.did_vid())),
+	};
+	// The chip DMAs the ring and our transmit buffers
+	device.enable(true, false, true);
+
+	cpu::out8(port + REG_CONFIG1, 0);
+	cpu::out8(port + REG_CR, CR_RST);
+	while cpu::in8(port + REG_CR) & CR_RST != 0 {
+		core::hint::spin_loop();
+	}
+
+	regions::claim("RTL8139 RX ring", RECEIVE_RING_ADDRESS, RECEIVE_RING_SIZE);
+	unsafe {
+		core::ptr::write_bytes(
+			RECEIVE_RING_ADDRESS as usize as *mut u8,
+			0,
+			RECEIVE_RING_SIZE as usize,
+		)
+	};
+	RECEIVE_POOL.init(
+		"RTL8139 RX buffers",
+		RECEIVE_BASE_BUFFER_ADDRESS as usize,
+		PACKET_SIZE,
+	);
+	TRANSMIT_POOL.init(
+		"RTL8139 TX buffers",
+		TRANSMIT_BASE_BUFFER_ADDRESS as usize,
+		PACKET_SIZE,
+	);
+
+	cpu::out32(port + REG_RBSTART, RECEIVE_RING_ADDRESS as u32);
+	cpu::out16(port + REG_IMR, 0);
+	cpu::out8(port + REG_CR, CR_RE | CR_TE);
+	cpu::out32(port + REG_TCR, TCR_DEFAULT);
+	cpu::out32(port + REG_RCR, RCR_DEFAULT);
+
+	let mut mac = [0u8; 6];
+	for (i, byte) in mac.iter_mut().enumerate() {
+		*byte = cpu::in8(port + REG_IDR0 + i as u16);
+	}
+
+	let nic = unsafe {
+		NIC = Some(Rtl8139 {
+			port,
+			mac,
+			rx_offset: 0,
+			tx_next: 0,
+			tx_buffers: Default::default(),
+		});
+		(*core::ptr::addr_of_mut!(NIC)).as_mut().unwrap()
+	};
+	Ok(nic)
+}
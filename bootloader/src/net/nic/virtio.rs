@@ -0,0 +1,613 @@
+//! This crate is the driver for virtio-net, the NIC QEMU/KVM and most clouds
+//! give a VM. It talks to the device through the modern PCI transport, the
+//! structures its vendor specific capabilities point at, and falls back to the
+//! legacy registers in I/O BAR0 when there are none we can reach. Packets go
+//! through two split virtqueues, one to receive and one to transmit, in memory
+//! claimed at fixed addresses like the E1000 rings. Every slot in a queue is
+//! two chained descriptors, the virtio-net header then a [`PacketBuffer`], so
+//! the Ethernet frame starts at the beginning of the buffer we hand up
+//! `<https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html>`
+use core::sync::atomic::{fence, Ordering};
+
+use super::{Capabilities, NetDevice, PacketBuffer, Received, RECEIVE_BATCH};
+use crate::cpu;
+use crate::error::{Error, Result};
+use crate::memory::pool::Pool;
+use crate::memory::{paging, regions};
+use crate::pci::capability::Capability;
+use crate::pci::{Bar, Device};
+
+const PACKET_SIZE: usize = 2048;
+
+/// Device status bits, set in order as we bring the device up
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Feature bits: The device config has its MAC, and its link status
+const NET_F_MAC: u64 = 1 << 5;
+const NET_F_STATUS: u64 = 1 << 16;
+/// Feature bits: The device follows the virtio 1.0 spec, required by the
+/// modern transport
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Offsets in the device config of the MAC and the status, link up is bit 0
+const NET_CONFIG_MAC: u16 = 0;
+const NET_CONFIG_STATUS: u16 = 6;
+const NET_STATUS_LINK_UP: u16 = 1 << 0;
+/// The virtio-net header in front of every packet, the modern one has
+/// `num_buffers` on the end
+const NET_HEADER_LEN_LEGACY: u32 = 10;
+const NET_HEADER_LEN: u32 = 12;
+
+/// Legacy registers, offsets into I/O BAR0. The device config follows them
+/// while MSI-X is off
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_CONFIG: u16 = 0x14;
+
+/// `cfg_type` of the vendor specific capabilities we use
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+/// Offsets into the modern common config structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// Descriptor flags: Another descriptor follows in `next`, the device writes
+/// this buffer instead of reading it
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// Biggest queue we have room for, a legacy device picks its own size
+const MAX_QUEUE_SIZE: u16 = 256;
+/// Slots we use in each queue, each takes two descriptors
+const SLOTS: usize = RECEIVE_BATCH;
+/// Memory for each queue: the descriptor table and available ring, then the
+/// used ring on the next page as the legacy layout wants
+const QUEUE_REGION_SIZE: u64 = 0x4000;
+const QUEUES_BASE_ADDRESS: u64 = 0xA00000;
+/// The headers of every slot follow the queues, each in its own 16 bytes
+const HEADERS_BASE_ADDRESS: u64 = QUEUES_BASE_ADDRESS + 2 * QUEUE_REGION_SIZE;
+const HEADER_STRIDE: u64 = 16;
+const RECEIVE_BASE_BUFFER_ADDRESS: u64 = 0xA80000;
+const TRANSMIT_BASE_BUFFER_ADDRESS: u64 = 0xB00000;
+
+/// Twice the slots so every one can be refilled while the packets we just
+/// received are still being handled
+static RECEIVE_POOL: Pool<PACKET_SIZE, { 2 * SLOTS }> = Pool::new();
+static TRANSMIT_POOL: Pool<PACKET_SIZE, SLOTS> = Pool::new();
+
+/// The driver once [`init`] has set the device up
+static mut NIC: Option<VirtioNet> = None;
+
+fn mmio_read<T>(addr: usize) -> T {
+	unsafe { (addr as *const T).read_volatile() }
+}
+
+fn mmio_write<T>(addr: usize, val: T) {
+	unsafe { (addr as *mut T).write_volatile(val) }
+}
+
+/// How we reach the device's registers
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+	/// The legacy registers at `port`
+	Legacy { port: u16 },
+	/// Where the capabilities say the common config, the notify area and the
+	/// device config are, all mapped below 4GiB
+	Modern {
+		common: usize,
+		notify: usize,
+		notify_multiplier: u32,
+		device: usize,
+	},
+}
+
+impl Transport {
+	/// Use the modern transport if the device has one we can reach, otherwise
+	/// the legacy registers if BAR0 is I/O space
+	fn find(device: &Device) -> Result<Self> {
+		if let Some(modern) = Self::modern(device) {
+			return Ok(modern);
+		}
+		match device.bar(0) {
+			Some(Bar::Io { port, .. }) => Ok(Transport::Legacy { port }),
+			_ => Err(Error::NoVirtioTransport(device.did_vid())),
+		}
+	}
+	/// Follow the vendor specific capabilities to the structures we need, the
+	/// first of each type is the one to use
+	fn modern(device: &Device) -> Option<Self> {
+		let bars = device.bars();
+		let (mut common, mut notify, mut config) = (None, None, None);
+		for cap in device.capabilities() {
+			let offset = match cap {
+				Capability::VendorSpecific { offset, .. } => offset,
+				_ => continue,
+			};
+			let bar = bars.get(device.read_8(offset + 4) as usize);
+			let base = match bar.copied().flatten().and_then(|bar| bar.mmio()) {
+				Some((base, _)) => base + device.read_32(offset + 8) as u64,
+				None => continue,
+			};
+			let len = device.read_32(offset + 12) as u64;
+			if base + len > 1 << 32 {
+				continue;
+			}
+			let slot = match device.read_8(offset + 3) {
+				CFG_COMMON => &mut common,
+				CFG_NOTIFY => &mut notify,
+				CFG_DEVICE => &mut config,
+				_ => continue,
+			};
+			if slot.is_none() {
+				paging::map_mmio(base as usize, len as usize);
+				*slot = Some((base as usize, offset));
+			}
+		}
+		let (notify, notify_cap) = notify?;
+		Some(Transport::Modern {
+			common: common?.0,
+			notify,
+			notify_multiplier: device.read_32(notify_cap + 16),
+			device: config?.0,
+		})
+	}
+	fn is_modern(&self) -> bool {
+		matches!(self, Transport::Modern { .. })
+	}
+	fn status(&self) -> u8 {
+		match *self {
+			Transport::Legacy { port } => cpu::in8(port + LEGACY_STATUS),
+			Transport::Modern { common, .. } => mmio_read(common + COMMON_STATUS),
+		}
+	}
+	fn set_status(&self, status: u8) {
+		match *self {
+			Transport::Legacy { port } => cpu::out8(port + LEGACY_STATUS, status),
+			Transport::Modern { common, .. } => {
+				mmio_write(common + COMMON_STATUS, status)
+			}
+		}
+	}
+	/// Writing 0 to the status resets the device, a modern device reads 0
+	/// once it is done
+	fn reset(&self) {
+		self.set_status(0);
+		while self.status() != 0 {
+			core::hint::spin_loop();
+		}
+	}
+	/// Every feature bit the device offers, a legacy device only has 32
+	fn device_features(&self) -> u64 {
+		match *self {
+			Transport::Legacy { port } => {
+				cpu::in32(port + LEGACY_DEVICE_FEATURES) as u64
+			}
+			Transport::Modern { common, .. } => {
+				mmio_write(common + COMMON_DEVICE_FEATURE_SELECT, 0u32);
+				let low: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+				mmio_write(common + COMMON_DEVICE_FEATURE_SELECT, 1u32);
+				let high: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+				(high as u64) << 32 | low as u64
+			}
+		}
+	}
+	fn set_driver_features(&self, features: u64) {
+		match *self {
+			Transport::Legacy { port } => {
+				cpu::out32(port + LEGACY_DRIVER_FEATURES, features as u32)
+			}
+			Transport::Modern { common, .. } => {
+				mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 0u32);
+				mmio_write(common + COMMON_DRIVER_FEATURE, features as u32);
+				mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 1u32);
+				mmio_write(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+			}
+		}
+	}
+	/// Set up the queue `index` in the memory at `base` and hand it to the
+	/// device. A legacy device dictates the size, a modern one lets us pick
+	/// anything up to its maximum
+	fn setup_queue(&self, index: u16, base: u64, headers: u64) -> Result<Queue> {
+		let max = match *self {
+			Transport::Legacy { port } => {
+				cpu::out16(port + LEGACY_QUEUE_SELECT, index);
+				cpu::in16(port + LEGACY_QUEUE_SIZE)
+			}
+			Transport::Modern { common, .. } => {
+				mmio_write(common + COMMON_QUEUE_SELECT, index);
+				mmio_read(common + COMMON_QUEUE_SIZE)
+			}
+		};
+		let size = match *self {
+			Transport::Legacy { .. } => max,
+			Transport::Modern { .. } => max.min(MAX_QUEUE_SIZE),
+		};
+		// Sizes are powers of two, we need two descriptors per slot
+		if size > MAX_QUEUE_SIZE || (size as usize) < 2 * SLOTS {
+			return Err(Error::BadVirtqueue(index));
+		}
+		let mut queue = Queue::new(index, size, base as usize, headers);
+		match *self {
+			Transport::Legacy { port } => {
+				cpu::out32(port + LEGACY_QUEUE_ADDRESS, (base >> 12) as u32)
+			}
+			Transport::Modern { common, .. } => {
+				mmio_write(common + COMMON_QUEUE_SIZE, size);
+				for (reg, addr) in [
+					(COMMON_QUEUE_DESC, queue.base),
+					(COMMON_QUEUE_DRIVER, queue.avail()),
+					(COMMON_QUEUE_DEVICE, queue.used()),
+				] {
+					mmio_write(common + reg, addr as u32);
+					mmio_write(common + reg + 4, 0u32);
+				}
+				queue.notify_off = mmio_read(common + COMMON_QUEUE_NOTIFY_OFF);
+				mmio_write(common + COMMON_QUEUE_ENABLE, 1u16);
+			}
+		}
+		Ok(queue)
+	}
+	/// Tell the device there are new buffers in `queue`
+	fn notify(&self, queue: &Queue) {
+		fence(Ordering::SeqCst);
+		match *self {
+			Transport::Legacy { port } => {
+				cpu::out16(port + LEGACY_QUEUE_NOTIFY, queue.index)
+			}
+			Transport::Modern {
+				notify,
+				notify_multiplier,
+				..
+			} => mmio_write(
+				notify + queue.notify_off as usize * notify_multiplier as usize,
+				queue.index,
+			),
+		}
+	}
+	/// Read a byte of the virtio-net config
+	fn config_8(&self, offset: u16) -> u8 {
+		match *self {
+			Transport::Legacy { port } => cpu::in8(port + LEGACY_CONFIG + offset),
+			Transport::Modern { device, .. } => {
+				mmio_read(device + offset as usize)
+			}
+		}
+	}
+	fn config_16(&self, offset: u16) -> u16 {
+		match *self {
+			Transport::Legacy { port } => cpu::in16(port + LEGACY_CONFIG + offset),
+			Transport::Modern { device, .. } => {
+				mmio_read(device + offset as usize)
+			}
+		}
+	}
+}
+
+/// A virtqueue descriptor, a buffer the device reads or writes
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct Desc {
+	addr: u64,
+	len: u32,
+	flags: u16,
+	next: u16,
+}
+
+/// A split virtqueue: the descriptor table, the available ring we put slots
+/// in and the used ring the device gives them back in
+#[derive(Debug)]
+struct Queue {
+	index: u16,
+	size: u16,
+	base: usize,
+	/// Where the header of slot 0 is, the rest follow [`HEADER_STRIDE`] apart
+	headers: u64,
+	/// Offset into the notify area, modern transport only
+	notify_off: u16,
+	/// Our copy of the available ring index
+	avail_idx: u16,
+	/// The next used ring entry we have not looked at
+	used_idx: u16,
+	/// The buffer in each slot while the device has it
+	buffers: [Option<PacketBuffer>; SLOTS],
+}
+
+impl Queue {
+	/// Zero the queue memory so both rings start empty
+	fn new(index: u16, size: u16, base: usize, headers: u64) -> Self {
+		unsafe {
+			core::ptr::write_bytes(base as *mut u8, 0, QUEUE_REGION_SIZE as usize)
+		};
+		Self {
+			index,
+			size,
+			base,
+			headers,
+			notify_off: 0,
+			avail_idx: 0,
+			used_idx: 0,
+			buffers: Default::default(),
+		}
+	}
+	/// The available ring: flags, index then a slot per entry
+	fn avail(&self) -> usize {
+		self.base + 16 * self.size as usize
+	}
+	/// The used ring: flags, index then an id and length per entry, page
+	/// aligned after the available ring
+	fn used(&self) -> usize {
+		(self.avail() + 6 + 2 * self.size as usize + 0xFFF) & !0xFFF
+	}
+	fn write_desc(&self, index: usize, desc: Desc) {
+		mmio_write(self.base + index * core::mem::size_of::<Desc>(), desc);
+	}
+	/// Give `buf` to the device in `slot` behind a header of `header_len`,
+	/// `len` bytes of it to send or all of it to receive into
+	fn post(
+		&mut self,
+		slot: usize,
+		buf: PacketBuffer,
+		len: usize,
+		header_len: u32,
+		receive: bool,
+	) {
+		let header = self.headers + slot as u64 * HEADER_STRIDE;
+		let write = if receive { DESC_F_WRITE } else { 0 };
+		// We ask for no offloads so a zero header is right for every send
+		unsafe {
+			core::ptr::write_bytes(
+				header as usize as *mut u8,
+				0,
+				HEADER_STRIDE as usize,
+			)
+		};
+		self.write_desc(
+			2 * slot,
+			Desc {
+				addr: header,
+				len: header_len,
+				flags: DESC_F_NEXT | write,
+				next: 2 * slot as u16 + 1,
+			},
+		);
+		self.write_desc(
+			2 * slot + 1,
+			Desc {
+				addr: buf.addr(),
+				len: len as u32,
+				flags: write,
+				next: 0,
+			},
+		);
+		self.buffers[slot] = Some(buf);
+
+		let entry = (self.avail_idx % self.size) as usize;
+		mmio_write(self.avail() + 4 + 2 * entry, 2 * slot as u16);
+		// The entry must be visible before the index that publishes it
+		fence(Ordering::SeqCst);
+		self.avail_idx = self.avail_idx.wrapping_add(1);
+		mmio_write(self.avail() + 2, self.avail_idx);
+	}
+	/// The next slot the device has finished with and how many bytes it wrote
+	fn pop_used(&mut self) -> Option<(usize, usize)> {
+		let used_idx: u16 = mmio_read(self.used() + 2);
+		if used_idx == self.used_idx {
+			return None;
+		}
+		fence(Ordering::SeqCst);
+		let entry = self.used() + 4 + 8 * (self.used_idx % self.size) as usize;
+		self.used_idx = self.used_idx.wrapping_add(1);
+		let id: u32 = mmio_read(entry);
+		let len: u32 = mmio_read(entry + 4);
+		Some((id as usize / 2, len as usize))
+	}
+	/// Give the buffers of every slot the device has finished sending back to
+	/// the pool
+	fn reclaim(&mut self) {
+		while let Some((slot, _)) = self.pop_used() {
+			self.buffers[slot] = None;
+		}
+	}
+}
+
+/// This struct holds the virtio-net device and its two queues
+#[derive(Debug)]
+pub struct VirtioNet {
+	transport: Transport,
+	mac: [u8; 6],
+	/// What we agreed with the device
+	features: u64,
+	header_len: u32,
+	rx: Queue,
+	tx: Queue,
+}
+
+impl NetDevice for VirtioNet {
+	fn mac(&self) -> [u8; 6] {
+		self.mac
+	}
+	/// Without the status feature the device has no way to say so we assume
+	/// the link is up
+	fn link_up(&self) -> bool {
+		self.features & NET_F_STATUS == 0
+			|| self.transport.config_16(NET_CONFIG_STATUS) & NET_STATUS_LINK_UP
+				!= 0
+	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			mtu: crate::net::MTU,
+		}
+	}
+	/// Every buffer may still be in a slot, so take back the ones the device
+	/// has sent first
+	fn transmit_buffer(&mut self) -> Option<PacketBuffer> {
+		self.tx.reclaim();
+		TRANSMIT_POOL.alloc_zeroed()
+	}
+	/// Take back the slots the device has sent and put `buf` in a free one, if
+	/// every slot is still in flight the packet is dropped
+	fn send(&mut self, buf: PacketBuffer, len: usize) -> Result<()> {
+		self.tx.reclaim();
+		let slot = match self.tx.buffers.iter().position(Option::is_none) {
+			Some(slot) => slot,
+			None => return Err(Error::TransmitBusy),
+		};
+		self.tx.post(slot, buf, len, self.header_len, false);
+		self.transport.notify(&self.tx);
+		Ok(())
+	}
+	/// Hand up every received buffer without its header and put a fresh one
+	/// in its slot, if the pool is empty the packet is dropped and its buffer
+	/// reused
+	fn receive(&mut self) -> Received {
+		let mut received_packets: Received = Default::default();
+		let mut packet_counter = 0;
+		// Any slot we post back has to be notified, even when the pool was empty
+		// and we dropped its packet
+		let mut reposted = false;
+		while packet_counter < RECEIVE_BATCH {
+			let (slot, len) = match self.rx.pop_used() {
+				Some(used) => used,
+				None => break,
+			};
+			let buf = self.rx.buffers[slot].take().unwrap();
+			let len = len.saturating_sub(self.header_len as usize);
+			match RECEIVE_POOL.alloc() {
+				Some(fresh) => {
+					self
+						.rx
+						.post(slot, fresh, PACKET_SIZE, self.header_len, true);
+					received_packets[packet_counter] = Some((buf, len));
+					packet_counter += 1;
+				}
+				None => self.rx.post(slot, buf, PACKET_SIZE, self.header_len, true),
+			}
+			reposted = true;
+		}
+		if reposted {
+			self.transport.notify(&self.rx);
+		}
+		received_packets
+	}
+}
+
+/// Sets up the virtio-net device at `device`: reset it, agree on features,
+/// give it the queues with every receive slot filled and read the MAC. A
+/// device that does not give us a MAC gets a random locally administered one
+pub fn init(device: Device) -> Result<&'static mut dyn NetDevice> {
+	let transport = Transport::find(&device)?;
+	device.enable(true, true, true);
+
+	transport.reset();
+	transport.set_status(STATUS_ACKNOWLEDGE);
+	transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+	let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+
+	let offered = transport.device_features();
+	let mut wanted = NET_F_MAC | NET_F_STATUS;
+	if transport.is_modern() {
+		wanted |= F_VERSION_1;
+	}
+	let features = offered & wanted;
+	transport.set_driver_features(features);
+	// Only the modern transport has to accept the features, and must agree
+	// to virtio 1.0
+	if transport.is_modern() {
+		status |= STATUS_FEATURES_OK;
+		transport.set_status(status);
+		if features & F_VERSION_1 == 0
+			|| transport.status() & STATUS_FEATURES_OK == 0
+		{
+			transport.set_status(status | STATUS_FAILED);
+			return Err(Error::VirtioFeaturesRejected(features));
+		}
+	}
+
+	regions::claim(
+		"virtio-net queues",
+		QUEUES_BASE_ADDRESS,
+		2 * QUEUE_REGION_SIZE + 2 * SLOTS as u64 * HEADER_STRIDE,
+	);
+	RECEIVE_POOL.init(
+		"virtio-net RX buffers",
+		RECEIVE_BASE_BUFFER_ADDRESS as usize,
+		PACKET_SIZE,
+	);
+	TRANSMIT_POOL.init(
+		"virtio-net TX buffers",
+		TRANSMIT_BASE_BUFFER_ADDRESS as usize,
+		PACKET_SIZE,
+	);
+	let setup = |index, base, headers| {
+		transport
+			.setup_queue(index, base, headers)
+			.inspect_err(|_| transport.set_status(status | STATUS_FAILED))
+	};
+	let mut rx =
+		setup(RECEIVE_QUEUE, QUEUES_BASE_ADDRESS, HEADERS_BASE_ADDRESS)?;
+	let tx = setup(
+		TRANSMIT_QUEUE,
+		QUEUES_BASE_ADDRESS + QUEUE_REGION_SIZE,
+		HEADERS_BASE_ADDRESS + SLOTS as u64 * HEADER_STRIDE,
+	)?;
+
+	let header_len = if transport.is_modern() {
+		NET_HEADER_LEN
+	} else {
+		NET_HEADER_LEN_LEGACY
+	};
+	// The pool is bigger than the slots so this cannot run out
+	for slot in 0..SLOTS {
+		let buf = RECEIVE_POOL.alloc().unwrap();
+		rx.post(slot, buf, PACKET_SIZE, header_len, true);
+	}
+
+	let mut mac = [0u8; 6];
+	if features & NET_F_MAC != 0 {
+		for (i, byte) in mac.iter_mut().enumerate() {
+			*byte = transport.config_8(NET_CONFIG_MAC + i as u16);
+		}
+	} else {
+		crate::random::fill_bytes(&mut mac);
+		mac[0] = (mac[0] & !0x1) | 0x2;
+	}
+
+	transport.set_status(status | STATUS_DRIVER_OK);
+	transport.notify(&rx);
+
+	let nic = unsafe {
+		NIC = Some(VirtioNet {
+			transport,
+			mac,
+			features,
+			header_len,
+			rx,
+			tx,
+		});
+		(*core::ptr::addr_of_mut!(NIC)).as_mut().unwrap()
+	};
+	Ok(nic)
+}
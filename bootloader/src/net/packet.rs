@@ -1,8 +1,8 @@
 use super::arp::{Arp, ARP_LEN};
+use super::nic::PacketBuffer;
 use super::IPv4;
 use super::NetworkStack;
 use super::Serialise;
-use super::MTU;
 use super::{Error, Result};
 use super::{
 	Ethernet, Protocol, ETHERNET_LEN, IPV4_HEADER_LEN, UDP_HEADER_LEN,
@@ -10,19 +10,23 @@ use super::{
 use super::{ETH_ETHER_TYPE, IPV4_ETHER_TYPE};
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Debug)]
 pub struct Packet {
 	ethernet: Ethernet,
 	pub ether_type: EtherType,
 	len: usize,
-	pub data: Option<[u8; 1458]>,
+	/// The buffer the NIC received into, we keep it instead of copying out
+	buf: PacketBuffer,
+	/// Where the UDP payload is in `buf`, if there is one
+	data: Option<core::ops::Range<usize>>,
 }
 
 impl Packet {
 	/// Takes raw buffer from recv of NIC and turns into human readable packet
-	pub fn parse(buf: &[u8], len: usize) -> Option<Self> {
+	pub fn parse(buf: PacketBuffer, len: usize) -> Option<Self> {
 		//crate::serial_print!("Recieved Packet, Len: {}, Data: {:?}\n", len,
 		// &buf[..len]);
+		let len = len.min(buf.len());
 		let ethernet = Ethernet::deserialise(&buf[..ETHERNET_LEN]);
 		// Initialise data as None
 		let mut data = None;
@@ -36,11 +40,16 @@ impl Packet {
 				let ip = IPv4::deserialise(&buf[ETHERNET_LEN..]);
 				match ip.protocol {
 					Protocol::Udp(udp) => {
-						let mut tmp = [0u8; 1458];
-						tmp[..(udp.len as usize - 8)].copy_from_slice(
-							&buf[ETHERNET_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN..len],
-						);
-						data = Some(tmp)
+						// The UDP length includes its header, anything past it
+						// is Ethernet padding
+						let start = ETHERNET_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN;
+						// Too short to hold the headers we just read
+						if len < start {
+							return None;
+						}
+						let end = (start - UDP_HEADER_LEN + udp.len as usize)
+							.clamp(start, len);
+						data = Some(start..end)
 					}
 				}
 				EtherType::IPv4(ip)
@@ -52,9 +61,14 @@ impl Packet {
 			ethernet,
 			ether_type,
 			len,
+			buf,
 			data,
 		})
 	}
+	/// The UDP payload, read straight out of the receive buffer
+	pub fn data(&self) -> Option<&[u8]> {
+		self.data.clone().map(|range| &self.buf[range])
+	}
 	/// Creates a new [Packet] up to and including L3
 	pub fn send(
 		ns: &mut NetworkStack,
@@ -77,19 +91,24 @@ impl Packet {
 
 		// Track the size of our packet
 		let mut packet_size = 0;
-		// Creat buffer that we serialise too
-		let mut buf = [0u8; MTU];
+		let ip_len = IPV4_HEADER_LEN + UDP_HEADER_LEN + data.len();
+		if ip_len > ns.nic.capabilities().mtu {
+			return Err(Error::PacketTooLarge(ip_len));
+		}
+
+		// Take a buffer from the NIC that we serialise too
+		let mut buf = ns.nic.transmit_buffer().ok_or(Error::NoTransmitBuffer)?;
 
 		// ETHENET SERIALISE
 		// Create out ethernet header with the given opcode
 		let ethernet =
-			Ethernet::new(dst_mac.unwrap(), ns.nic.mac, ether_type_opcode);
+			Ethernet::new(dst_mac.unwrap(), ns.nic.mac(), ether_type_opcode);
 		let ethernet_len = ethernet.serialise(&mut buf);
 		packet_size += ethernet_len;
 
 		// Create the UDP struct so we can pass to IPv4, IPv4 needs to know
 		// total packet len
-		let udp = super::Udp::new(data.len(), 30000, dst_port);
+		let udp = super::Udp::new(data.len(), ns.src_port, dst_port);
 
 		// Create an IPv4 header
 		let ipv4 = super::IPv4::new(super::Protocol::Udp(udp), dst_ip);
@@ -105,8 +124,7 @@ impl Packet {
 			.copy_from_slice(&data[..data.len()]);
 		packet_size += data.len();
 
-		ns.nic.send(&buf, packet_size);
-		Ok(())
+		ns.nic.send(buf, packet_size).map_err(Error::Nic)
 	}
 }
 
@@ -25,13 +25,13 @@ impl Udp {
 
 impl Serialise for Udp {
 	fn serialise(&self, buf: &mut [u8]) -> usize {
-		buf[0] = (self.src_port << 8) as u8;
+		buf[0] = (self.src_port >> 8) as u8;
 		buf[1] = self.src_port as u8;
-		buf[2] = (self.dst_port << 8) as u8;
+		buf[2] = (self.dst_port >> 8) as u8;
 		buf[3] = self.dst_port as u8;
-		buf[4] = (self.len << 8) as u8;
+		buf[4] = (self.len >> 8) as u8;
 		buf[5] = self.len as u8;
-		buf[6] = (self.checksum << 8) as u8;
+		buf[6] = (self.checksum >> 8) as u8;
 		buf[7] = self.checksum as u8;
 
 		UDP_HEADER_LEN
@@ -0,0 +1,129 @@
+//! Our `#[panic_handler]`, on top of the [`core::panic::PanicInfo`] it dumps
+//! the registers we can still see, walks the frame pointers for a backtrace
+//! of return addresses and repeats the last lines we printed from
+//! [`crate::log`]. Everything goes to serial and, with the `panic-vga`
+//! feature, to the VGA screen too. Then it halts, reboots or powers off
+//! depending on the `panic-reboot` and `panic-poweroff` features. Look the
+//! return addresses up with `addr2line` or `llvm-symbolizer` against the PE
+//! built in `bootloader/target`
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::acpi;
+use crate::apic;
+use crate::cpu;
+use crate::log;
+use crate::serial::SerialWriter;
+use crate::stack;
+
+/// Most frames we print in the backtrace
+const MAX_FRAMES: usize = 32;
+/// How many lines of the log we repeat
+const LOG_LINES: usize = 16;
+
+/// Set by the first panic so a panic inside the handler does not recurse
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// What the panic handler does once it has printed the panic
+#[allow(dead_code)]
+enum PanicPolicy {
+	Halt,
+	Reboot,
+	PowerOff,
+}
+
+/// Picked at compile time with the `panic-reboot` and `panic-poweroff` cargo
+/// features so automated QEMU runs can exit instead of hanging
+const PANIC_POLICY: PanicPolicy = if cfg!(feature = "panic-poweroff") {
+	PanicPolicy::PowerOff
+} else if cfg!(feature = "panic-reboot") {
+	PanicPolicy::Reboot
+} else {
+	PanicPolicy::Halt
+};
+
+/// Writes to serial and the screen if it is enabled
+struct PanicWriter;
+
+impl core::fmt::Write for PanicWriter {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		SerialWriter.write_str(s)?;
+		#[cfg(feature = "panic-vga")]
+		crate::display::VgaWriter.write_str(s)?;
+		Ok(())
+	}
+}
+
+/// Print the registers that still mean something once we are in the handler
+fn dump_registers(w: &mut PanicWriter) -> core::fmt::Result {
+	let apic_id = apic::get().map(|apic| apic.local.id());
+	writeln!(
+		w,
+		"APIC ID: {:?}\nESP: {:#010X} EBP: {:#010X} EFLAGS: {:#010X}\nCR0: \
+		 {:#010X} CR2: {:#010X} CR3: {:#010X} CR4: {:#010X}",
+		apic_id,
+		cpu::esp(),
+		cpu::ebp(),
+		cpu::eflags(),
+		cpu::read_cr0(),
+		cpu::read_cr2(),
+		cpu::read_cr3(),
+		cpu::read_cr4(),
+	)
+}
+
+/// Follow the saved frame pointers up the stack, each frame starts with the
+/// caller's EBP followed by the return address into the caller. We stop at the
+/// first frame that is not inside the stack we are on so a bad EBP cannot
+/// fault in the handler
+fn backtrace(w: &mut PanicWriter) -> core::fmt::Result {
+	writeln!(w, "Backtrace:")?;
+	let mut ebp = cpu::ebp() as usize;
+	let (bottom, top) = match stack::bounds(ebp) {
+		Some(bounds) => bounds,
+		None => {
+			return writeln!(w, "  EBP {:#010X} is not on a known stack", ebp)
+		}
+	};
+	for frame in 0..MAX_FRAMES {
+		if ebp % 4 != 0 || ebp < bottom || ebp + 8 > top {
+			break;
+		}
+		let (next, ret) =
+			unsafe { (*(ebp as *const usize), *((ebp + 4) as *const usize)) };
+		if ret == 0 {
+			break;
+		}
+		writeln!(w, "  #{:<2} {:#010X}", frame, ret)?;
+		// The stack grows down so callers' frames are always higher
+		if next <= ebp {
+			break;
+		}
+		ebp = next;
+	}
+	Ok(())
+}
+
+/// Custom panic handler for our OS
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+	let mut w = PanicWriter;
+	if PANICKING.swap(true, Ordering::Relaxed) {
+		let _ = writeln!(w, "Panic while panicking: {}", info);
+	} else {
+		log::freeze();
+		#[cfg(feature = "panic-vga")]
+		crate::display::VgaWriter::clear();
+		let _ = writeln!(w, "Last {} lines of output:", LOG_LINES);
+		let _ = log::write_last_lines(&mut w, LOG_LINES);
+		let _ = writeln!(w, "\n{}", info);
+		let _ = dump_registers(&mut w);
+		let _ = backtrace(&mut w);
+	}
+	match PANIC_POLICY {
+		PanicPolicy::Halt => cpu::halt(),
+		PanicPolicy::Reboot => acpi::reboot(),
+		PanicPolicy::PowerOff => acpi::shutdown(),
+	}
+}
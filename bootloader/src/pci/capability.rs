@@ -0,0 +1,373 @@
+//! The capability list in config space, a linked list starting at the
+//! capabilities pointer when the status register says there is one. We decode
+//! the capabilities we know and program MSI and MSI-X so a device can send its
+//! interrupts straight to a Local APIC without legacy INTx routing
+//! `<https://wiki.osdev.org/PCI#Message_Signaled_Interrupts>`. PCI Express
+//! devices have a second list of extended capabilities from offset 0x100,
+//! which we can only reach through ECAM
+use super::config;
+use super::{Bar, Device, COMMAND_INTERRUPT_DISABLE, PCI_COMMAND};
+use crate::error::{Error, Result};
+use crate::memory::paging;
+
+/// Config space offset of the status register and the capabilities pointer
+const PCI_STATUS: u16 = 0x06;
+const PCI_CAPABILITIES_PTR: u16 = 0x34;
+/// Status register: The device has a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+/// A list longer than this can only be a loop, config space holds at most 48
+/// capabilities after the 64 byte header
+const MAX_CAPABILITIES: usize = 48;
+/// Where the extended capability list starts, and the most it can hold in the
+/// rest of the 4KiB
+const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
+/// Capability IDs
+const CAP_POWER_MANAGEMENT: u8 = 0x01;
+const CAP_MSI: u8 = 0x05;
+const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+const CAP_PCI_EXPRESS: u8 = 0x10;
+const CAP_MSIX: u8 = 0x11;
+/// Extended capability IDs
+const EXT_CAP_AER: u16 = 0x0001;
+const EXT_CAP_SR_IOV: u16 = 0x0010;
+
+/// MSI message control bits
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE_MASK: u16 = 0x7 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+/// MSI-X message control bits
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The BAR index in the low bits of the MSI-X table and PBA dwords
+const MSIX_BIR_MASK: u32 = 0x7;
+/// Bytes per MSI-X table entry: address low, address high, data, control
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// Messages to this window are interrupts for the Local APIC whose ID is in
+/// bits 12-19, fixed delivery and physical destination with the other bits 0
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u32 = 12;
+
+/// A capability we found, `offset` is where it starts in config space
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+	PowerManagement {
+		offset: u16,
+		version: u8,
+	},
+	Msi {
+		offset: u16,
+		/// Vectors the device can use, a power of two up to 32
+		vectors: u8,
+		is_64_bit: bool,
+	},
+	MsiX {
+		offset: u16,
+		/// Entries in the MSI-X table
+		table_size: u16,
+		/// BAR index and offset into it of the table and the pending bits
+		table: (u8, u32),
+		pending: (u8, u32),
+	},
+	PciExpress {
+		offset: u16,
+		version: u8,
+		/// Endpoint, root port, switch port etc
+		device_type: u8,
+	},
+	VendorSpecific {
+		offset: u16,
+		len: u8,
+	},
+	Other {
+		id: u8,
+		offset: u16,
+	},
+}
+
+impl Capability {
+	/// Decode the capability with `id` at `offset`
+	fn decode(device: &Device, id: u8, offset: u16) -> Self {
+		match id {
+			CAP_POWER_MANAGEMENT => Capability::PowerManagement {
+				offset,
+				version: (device.read_16(offset + 2) & 0x7) as u8,
+			},
+			CAP_MSI => {
+				let control = device.read_16(offset + 2);
+				Capability::Msi {
+					offset,
+					vectors: 1 << ((control >> 1) & 0x7),
+					is_64_bit: control & MSI_64_BIT != 0,
+				}
+			}
+			CAP_MSIX => {
+				let control = device.read_16(offset + 2);
+				let table = device.read_32(offset + 4);
+				let pending = device.read_32(offset + 8);
+				Capability::MsiX {
+					offset,
+					table_size: (control & MSIX_TABLE_SIZE_MASK) + 1,
+					table: ((table & MSIX_BIR_MASK) as u8, table & !MSIX_BIR_MASK),
+					pending: (
+						(pending & MSIX_BIR_MASK) as u8,
+						pending & !MSIX_BIR_MASK,
+					),
+				}
+			}
+			CAP_PCI_EXPRESS => {
+				let caps = device.read_16(offset + 2);
+				Capability::PciExpress {
+					offset,
+					version: (caps & 0xF) as u8,
+					device_type: ((caps >> 4) & 0xF) as u8,
+				}
+			}
+			CAP_VENDOR_SPECIFIC => Capability::VendorSpecific {
+				offset,
+				len: device.read_8(offset + 2),
+			},
+			id => Capability::Other { id, offset },
+		}
+	}
+}
+
+/// Walks the capability list of a [`Device`], see [`Device::capabilities`]
+pub struct Capabilities<'a> {
+	device: &'a Device,
+	next: u16,
+	seen: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+	type Item = Capability;
+	fn next(&mut self) -> Option<Capability> {
+		// The bottom two bits of every pointer are reserved
+		let offset = self.next & !0x3;
+		if offset < 0x40 || self.seen == MAX_CAPABILITIES {
+			return None;
+		}
+		self.seen += 1;
+		let header = self.device.read_16(offset);
+		self.next = header >> 8;
+		Some(Capability::decode(self.device, header as u8, offset))
+	}
+}
+
+/// A capability from the extended list, `offset` is where it starts in config
+/// space
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum ExtendedCapability {
+	/// Advanced Error Reporting with the error status registers as we found
+	/// them, a set bit is an error the device has logged
+	AdvancedErrorReporting {
+		offset: u16,
+		version: u8,
+		uncorrectable_status: u32,
+		correctable_status: u32,
+	},
+	/// Single Root I/O Virtualization, the device can present virtual
+	/// functions with `vf_device_id`
+	SrIov {
+		offset: u16,
+		version: u8,
+		initial_vfs: u16,
+		total_vfs: u16,
+		vf_device_id: u16,
+	},
+	Other {
+		id: u16,
+		version: u8,
+		offset: u16,
+	},
+}
+
+impl ExtendedCapability {
+	/// Decode the extended capability with `id` and `version` at `offset`
+	fn decode(device: &Device, id: u16, version: u8, offset: u16) -> Self {
+		match id {
+			EXT_CAP_AER => ExtendedCapability::AdvancedErrorReporting {
+				offset,
+				version,
+				uncorrectable_status: device.read_32(offset + 0x4),
+				correctable_status: device.read_32(offset + 0x10),
+			},
+			EXT_CAP_SR_IOV => ExtendedCapability::SrIov {
+				offset,
+				version,
+				initial_vfs: device.read_16(offset + 0xC),
+				total_vfs: device.read_16(offset + 0xE),
+				vf_device_id: device.read_16(offset + 0x1A),
+			},
+			id => ExtendedCapability::Other {
+				id,
+				version,
+				offset,
+			},
+		}
+	}
+}
+
+/// Walks the extended capability list of a [`Device`], see
+/// [`Device::extended_capabilities`]
+pub struct ExtendedCapabilities<'a> {
+	device: &'a Device,
+	next: u16,
+	seen: usize,
+}
+
+impl Iterator for ExtendedCapabilities<'_> {
+	type Item = ExtendedCapability;
+	fn next(&mut self) -> Option<ExtendedCapability> {
+		let offset = self.next & !0x3;
+		if offset < EXTENDED_CAPABILITIES_START
+			|| self.seen == MAX_EXTENDED_CAPABILITIES
+		{
+			return None;
+		}
+		self.seen += 1;
+		// The ID in bits 0-15, the version in 16-19 and the next pointer in
+		// 20-31. A function without any reads as 0, all ones if it is absent
+		let header = self.device.read_32(offset);
+		if header == 0 || header == !0 {
+			return None;
+		}
+		self.next = (header >> 20) as u16;
+		Some(ExtendedCapability::decode(
+			self.device,
+			header as u16,
+			((header >> 16) & 0xF) as u8,
+			offset,
+		))
+	}
+}
+
+/// The MSI address that targets the Local APIC with `apic_id`
+fn msi_address(apic_id: u8) -> u32 {
+	MSI_ADDRESS_BASE | (apic_id as u32) << MSI_ADDRESS_DEST_SHIFT
+}
+
+impl Device {
+	/// Iterate over the capability list, empty if the device has none
+	pub fn capabilities(&self) -> Capabilities<'_> {
+		let next = if self.read_16(PCI_STATUS) & STATUS_CAPABILITIES != 0 {
+			self.read_8(PCI_CAPABILITIES_PTR) as u16
+		} else {
+			0
+		};
+		Capabilities {
+			device: self,
+			next,
+			seen: 0,
+		}
+	}
+	/// Iterate over the extended capability list, empty unless we reach config
+	/// space through ECAM and the device is PCI Express
+	#[allow(dead_code)]
+	pub fn extended_capabilities(&self) -> ExtendedCapabilities<'_> {
+		let is_express = self
+			.capabilities()
+			.any(|cap| matches!(cap, Capability::PciExpress { .. }));
+		let next = if is_express && config::size() > EXTENDED_CAPABILITIES_START {
+			EXTENDED_CAPABILITIES_START
+		} else {
+			0
+		};
+		ExtendedCapabilities {
+			device: self,
+			next,
+			seen: 0,
+		}
+	}
+	/// Send a single MSI with `vector` to the Local APIC with `apic_id` and
+	/// turn off INTx, edge triggered with fixed delivery
+	#[allow(dead_code)]
+	pub fn enable_msi(&self, apic_id: u8, vector: u8) -> Result<()> {
+		let (offset, is_64_bit) = self
+			.capabilities()
+			.find_map(|cap| match cap {
+				Capability::Msi {
+					offset, is_64_bit, ..
+				} => Some((offset, is_64_bit)),
+				_ => None,
+			})
+			.ok_or(Error::NoMsi(self.did_vid()))?;
+
+		self.write_32(offset + 4, msi_address(apic_id));
+		let data_offset = if is_64_bit {
+			self.write_32(offset + 8, 0);
+			offset + 0xC
+		} else {
+			offset + 8
+		};
+		self.write_16(data_offset, vector as u16);
+
+		let control = self.read_16(offset + 2) & !MSI_MULTIPLE_ENABLE_MASK;
+		self.write_16(offset + 2, control | MSI_ENABLE);
+		self.disable_intx();
+		Ok(())
+	}
+	/// Point MSI-X table `entry` at the Local APIC with `apic_id` with
+	/// `vector` and unmask it, then turn on MSI-X and turn off INTx. The table
+	/// must be in a memory BAR below 4GiB
+	#[allow(dead_code)]
+	pub fn enable_msix(
+		&self,
+		entry: u16,
+		apic_id: u8,
+		vector: u8,
+	) -> Result<()> {
+		let (offset, table_size, (bir, table_offset)) = self
+			.capabilities()
+			.find_map(|cap| match cap {
+				Capability::MsiX {
+					offset,
+					table_size,
+					table,
+					..
+				} => Some((offset, table_size, table)),
+				_ => None,
+			})
+			.ok_or(Error::NoMsi(self.did_vid()))?;
+		if entry >= table_size {
+			return Err(Error::BadMsixEntry(entry));
+		}
+		let base = match self.bar(bir as usize).as_ref().and_then(Bar::mmio) {
+			Some((base, _)) => base + table_offset as u64,
+			None => return Err(Error::NoMsi(self.did_vid())),
+		};
+		let table_end = base + table_size as u64 * MSIX_ENTRY_SIZE;
+		if table_end > 1 << 32 {
+			return Err(Error::NoMsi(self.did_vid()));
+		}
+		paging::map_mmio(base as usize, (table_end - base) as usize);
+
+		// Mask the whole function while we change the entry
+		let control = self.read_16(offset + 2);
+		self.write_16(offset + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+		let entry = (base + entry as u64 * MSIX_ENTRY_SIZE) as usize as *mut u32;
+		unsafe {
+			entry.write_volatile(msi_address(apic_id));
+			entry.add(1).write_volatile(0);
+			entry.add(2).write_volatile(vector as u32);
+			let vector_control = entry.add(3).read_volatile();
+			entry
+				.add(3)
+				.write_volatile(vector_control & !MSIX_VECTOR_MASKED);
+		}
+		self.write_16(offset + 2, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+		self.disable_intx();
+		Ok(())
+	}
+	/// Stop the device asserting its legacy interrupt pin
+	fn disable_intx(&self) {
+		let command = self.read_16(PCI_COMMAND);
+		self.write_16(PCI_COMMAND, command | COMMAND_INTERRUPT_DISABLE);
+	}
+}
@@ -0,0 +1,191 @@
+//! This crate is how we reach PCI config space. The legacy ports 0xCF8/0xCFC
+//! only reach the first 256 bytes of each function, PCI Express adds ECAM
+//! where every function gets 4KiB of memory mapped config space at a fixed
+//! address inside a region the ACPI MCFG table describes. [`init`] picks ECAM
+//! when MCFG has a region for segment 0 we can reach and everything else goes
+//! through the functions here so callers do not care which one is in use
+//! `<https://wiki.osdev.org/PCI_Express>`
+use crate::acpi;
+use crate::cpu;
+use crate::memory::paging;
+
+const PCI_ENABLE_BIT: u32 = 1 << 31;
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+/// Bytes of config space per function through the ports and through ECAM
+const PORT_CONFIG_SIZE: u16 = 0x100;
+pub const ECAM_CONFIG_SIZE: u16 = 0x1000;
+/// Most MCFG regions for segment 0 we use
+const MAX_ECAM_REGIONS: usize = 4;
+
+/// The ECAM regions [`init`] found, empty means we use the ports
+static mut ECAM: [Option<Ecam>; MAX_ECAM_REGIONS] = [None; MAX_ECAM_REGIONS];
+
+/// An ECAM region covering `start_bus..=end_bus` of segment 0
+#[derive(Debug, Clone, Copy)]
+pub struct Ecam {
+	base: usize,
+	start_bus: u8,
+	end_bus: u8,
+}
+
+impl Ecam {
+	/// The address of `offset` in the config space of a function, if this
+	/// region covers its bus and the offset is inside the function's 4KiB
+	fn address(
+		&self,
+		bus: u8,
+		device: u8,
+		function: u8,
+		offset: u16,
+	) -> Option<usize> {
+		if !(self.start_bus..=self.end_bus).contains(&bus)
+			|| offset >= ECAM_CONFIG_SIZE
+		{
+			return None;
+		}
+		let function_offset = ((bus - self.start_bus) as usize) << 20
+			| (device as usize) << 15
+			| (function as usize) << 12
+			| offset as usize;
+		Some(self.base + function_offset)
+	}
+}
+
+/// Which mechanism [`init`] picked
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+	Ports,
+	/// How many ECAM regions and the first one
+	Ecam(usize, Ecam),
+}
+
+impl core::fmt::Display for Access {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Access::Ports => write!(f, "PCI config: ports 0xCF8/0xCFC"),
+			Access::Ecam(regions, ecam) => write!(
+				f,
+				"PCI config: ECAM at {:#X} for buses {}-{}, {} regions",
+				ecam.base, ecam.start_bus, ecam.end_bus, regions
+			),
+		}
+	}
+}
+
+/// Use ECAM for every segment 0 region in the MCFG that is below 4GiB, we
+/// map each one uncached. Without ACPI or an MCFG we stay on the ports
+pub fn init() -> Access {
+	let mcfg = match acpi::get().and_then(|acpi| acpi.mcfg()) {
+		Some(mcfg) => mcfg,
+		None => return Access::Ports,
+	};
+	let ecam = unsafe { &mut *core::ptr::addr_of_mut!(ECAM) };
+	let mut regions = 0;
+	for entry in mcfg.entries() {
+		let (base, segment) = (entry.base_address, entry.segment);
+		let (start_bus, end_bus) = (entry.start_bus, entry.end_bus);
+		if end_bus < start_bus {
+			print!(
+				"Ignoring ECAM region at {:#X} with buses {}-{}\n",
+				base, start_bus, end_bus
+			);
+			continue;
+		}
+		let len = (end_bus as u64 - start_bus as u64 + 1) << 20;
+		if segment != 0 || base + len > 1 << 32 || regions == MAX_ECAM_REGIONS {
+			print!(
+				"Ignoring ECAM region at {:#X} on segment {}\n",
+				base, segment
+			);
+			continue;
+		}
+		paging::map_mmio(base as usize, len as usize);
+		ecam[regions] = Some(Ecam {
+			base: base as usize,
+			start_bus,
+			end_bus,
+		});
+		regions += 1;
+	}
+	match ecam[0] {
+		Some(first) => Access::Ecam(regions, first),
+		None => Access::Ports,
+	}
+}
+
+/// The size of config space per function with the mechanism in use
+pub fn size() -> u16 {
+	if unsafe { ECAM[0].is_some() } {
+		ECAM_CONFIG_SIZE
+	} else {
+		PORT_CONFIG_SIZE
+	}
+}
+
+/// The ECAM address of `offset` for a function if a region covers its bus
+fn ecam_address(
+	bus: u8,
+	device: u8,
+	function: u8,
+	offset: u16,
+) -> Option<usize> {
+	let ecam = unsafe { &*core::ptr::addr_of!(ECAM) };
+	ecam
+		.iter()
+		.flatten()
+		.find_map(|region| region.address(bus, device, function, offset))
+}
+
+/// Select the dword holding `offset` in a device's config space, the low two
+/// bits of the offset pick the byte lane in [`PCI_CONFIG_DATA`] instead
+fn select(bus: u8, device: u8, function: u8, offset: u16) {
+	let address: u32 = PCI_ENABLE_BIT
+		| (bus as u32) << 16
+		| (device as u32) << 11
+		| (function as u32) << 8
+		| (offset as u32) & 0xFC;
+	cpu::out32(PCI_CONFIG_ADDRESS, address);
+}
+
+/// Read `offset` with ECAM if it covers the function, then the ports if the
+/// offset is in their range, otherwise what an absent register reads as
+macro_rules! config_read {
+	($name:ident, $ty:ty, $in:path, $lane:expr) => {
+		pub fn $name(bus: u8, device: u8, function: u8, offset: u16) -> $ty {
+			if let Some(addr) = ecam_address(bus, device, function, offset) {
+				return unsafe { (addr as *const $ty).read_volatile() };
+			}
+			if offset >= PORT_CONFIG_SIZE {
+				return <$ty>::MAX;
+			}
+			select(bus, device, function, offset);
+			$in(PCI_CONFIG_DATA + (offset & $lane))
+		}
+	};
+}
+
+/// Write `offset` the same way as the reads, writes nothing can reach are
+/// dropped. Narrow port writes only touch their byte lane
+macro_rules! config_write {
+	($name:ident, $ty:ty, $out:path, $lane:expr) => {
+		pub fn $name(bus: u8, device: u8, function: u8, offset: u16, val: $ty) {
+			if let Some(addr) = ecam_address(bus, device, function, offset) {
+				unsafe { (addr as *mut $ty).write_volatile(val) };
+				return;
+			}
+			if offset >= PORT_CONFIG_SIZE {
+				return;
+			}
+			select(bus, device, function, offset);
+			$out(PCI_CONFIG_DATA + (offset & $lane), val);
+		}
+	};
+}
+
+config_read!(read_32, u32, cpu::in32, 0);
+config_read!(read_16, u16, cpu::in16, 2);
+config_read!(read_8, u8, cpu::in8, 3);
+config_write!(write_32, u32, cpu::out32, 0);
+config_write!(write_16, u16, cpu::out16, 2);
+config_write!(write_8, u8, cpu::out8, 3);
@@ -0,0 +1,187 @@
+//! This crate prints the devices [`super::init`] found one per line like
+//! `lspci` does, with names for the classes and vendors we are likely to see
+//! in QEMU and on real hardware. It is only built with the `lspci` feature so
+//! the name tables stay out of minimal builds
+use core::fmt::{Display, Formatter, Result};
+
+use super::{Bar, Device};
+
+/// The most specific name we have for a class, subclass and programming
+/// interface, falling back to the class alone
+fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+	match (class, subclass, prog_if) {
+		(0x01, 0x01, _) => "IDE controller",
+		(0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+		(0x01, 0x06, _) => "SATA controller",
+		(0x01, 0x08, 0x02) => "NVM controller (NVMe)",
+		(0x01, 0x00, _) => "SCSI controller",
+		(0x01, _, _) => "Mass storage controller",
+		(0x02, 0x00, _) => "Ethernet controller",
+		(0x02, _, _) => "Network controller",
+		(0x03, 0x00, _) => "VGA compatible controller",
+		(0x03, _, _) => "Display controller",
+		(0x04, 0x03, _) => "Audio device",
+		(0x04, _, _) => "Multimedia controller",
+		(0x05, _, _) => "Memory controller",
+		(0x06, 0x00, _) => "Host bridge",
+		(0x06, 0x01, _) => "ISA bridge",
+		(0x06, 0x04, _) => "PCI bridge",
+		(0x06, _, _) => "Bridge",
+		(0x07, 0x00, _) => "Serial controller",
+		(0x07, _, _) => "Communication controller",
+		(0x08, _, _) => "System peripheral",
+		(0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+		(0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+		(0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+		(0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+		(0x0C, 0x03, _) => "USB controller",
+		(0x0C, 0x05, _) => "SMBus",
+		(0x0C, _, _) => "Serial bus controller",
+		(0xFF, _, _) => "Unassigned class",
+		_ => "Unknown class",
+	}
+}
+
+/// The name of a vendor we know
+fn vendor_name(vendor: u16) -> Option<&'static str> {
+	match vendor {
+		0x8086 => Some("Intel"),
+		0x1AF4 => Some("Red Hat (virtio)"),
+		0x1B36 => Some("Red Hat (QEMU)"),
+		0x1234 => Some("QEMU"),
+		0x10EC => Some("Realtek"),
+		0x1022 => Some("AMD"),
+		0x10DE => Some("NVIDIA"),
+		0x15AD => Some("VMware"),
+		0x80EE => Some("VirtualBox"),
+		_ => None,
+	}
+}
+
+/// A size in the biggest unit it is a whole number of
+struct Size(u64);
+
+impl Display for Size {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match self.0 {
+			size if size >= 1 << 30 && size % (1 << 30) == 0 => {
+				write!(f, "{}G", size >> 30)
+			}
+			size if size >= 1 << 20 && size % (1 << 20) == 0 => {
+				write!(f, "{}M", size >> 20)
+			}
+			size if size >= 1 << 10 && size % (1 << 10) == 0 => {
+				write!(f, "{}K", size >> 10)
+			}
+			size => write!(f, "{}", size),
+		}
+	}
+}
+
+impl Display for Bar {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match *self {
+			Bar::Io { port, size } => {
+				write!(f, "io {:#x}+{}", port, Size(size as u64))
+			}
+			Bar::Mem32 {
+				addr,
+				size,
+				prefetchable,
+			} => write!(
+				f,
+				"mem32 {:#x}+{}{}",
+				addr,
+				Size(size as u64),
+				if prefetchable { " pf" } else { "" }
+			),
+			Bar::Mem64 {
+				addr,
+				size,
+				prefetchable,
+			} => write!(
+				f,
+				"mem64 {:#x}+{}{}",
+				addr,
+				Size(size),
+				if prefetchable { " pf" } else { "" }
+			),
+		}
+	}
+}
+
+/// `bus:dev.fn vendor:device class vendor-name`, then the interrupt pin with
+/// the IRQ line firmware routed it to and the BARs
+impl Display for Device {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		let header = &self.header;
+		write!(
+			f,
+			"{:02x}:{:02x}.{} {:04x}:{:04x} {}",
+			self.bus,
+			self.device,
+			self.function,
+			header.vendor_id,
+			header.device_id,
+			class_name(header.class_code, header.subclass, header.prog_if),
+		)?;
+		if let Some(vendor) = vendor_name(header.vendor_id) {
+			write!(f, ", {}", vendor)?;
+		}
+		// Pin 1-4 is INTA-INTD, 0 means the function does not use one
+		if (1..=4).contains(&header.interrupt_pin) {
+			write!(
+				f,
+				" INT{} IRQ {}",
+				(b'A' + header.interrupt_pin - 1) as char,
+				header.interrupt_line
+			)?;
+		}
+		for (index, bar) in self.bars().iter().enumerate() {
+			if let Some(bar) = bar {
+				write!(f, " BAR{} {}", index, bar)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn size_units() {
+		assert_eq!(Size(0).to_string(), "0");
+		assert_eq!(Size(32).to_string(), "32");
+		assert_eq!(Size(0x400).to_string(), "1K");
+		assert_eq!(Size(0x20000).to_string(), "128K");
+		assert_eq!(Size(0x100000).to_string(), "1M");
+		assert_eq!(Size(0x4000_0000).to_string(), "1G");
+		assert_eq!(Size(0x4_0000_0000).to_string(), "16G");
+	}
+
+	/// A size that is not a whole number of a unit drops to the next one down
+	#[test]
+	fn size_partial_units() {
+		assert_eq!(Size(0x600).to_string(), "1536");
+		assert_eq!(Size(0x180000).to_string(), "1536K");
+		assert_eq!(Size(0x6000_0000).to_string(), "1536M");
+		assert_eq!(Size(0x401).to_string(), "1025");
+	}
+
+	#[test]
+	fn bar_display() {
+		let bar = Bar::Mem64 {
+			addr: 0xFE00_0000,
+			size: 0x4000,
+			prefetchable: true,
+		};
+		assert!(bar.to_string().contains("16K"));
+		let io = Bar::Io {
+			port: 0xC000,
+			size: 0x20,
+		};
+		assert_eq!(io.to_string(), "io 0xc000+32");
+	}
+}
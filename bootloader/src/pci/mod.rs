@@ -0,0 +1,457 @@
+//! This is where we enumerate all the PCI devices and find the ones we want to
+//! use to expose to other parts of the OS. We start at bus 0 and follow each
+//! PCI-to-PCI bridge to the bus behind it instead of probing every possible
+//! address, and only look past function 0 of multi-function devices
+//! `<https://wiki.osdev.org/PCI#Recursive_Scan>`
+pub mod capability;
+pub mod config;
+#[cfg(feature = "lspci")]
+mod lspci;
+
+use core::mem::size_of;
+
+use crate::error::{Error, Result};
+
+/// PCI Magic numbers
+const PCI_CLASS_CODE_NETWORK: u8 = 0x2;
+const PCI_SUBCLASS_CODE_ETHERNET: u8 = 0x0;
+const PCI_CLASS_CODE_BRIDGE: u8 = 0x6;
+const PCI_SUBCLASS_CODE_PCI_BRIDGE: u8 = 0x4;
+const PCI_BUS_LEN: usize = 256;
+const PCI_DEVICE_LEN: u8 = 32;
+const PCI_FUNCTION_LEN: u8 = 8;
+/// Header type: The layout is in the low bits, the top bit marks a
+/// multi-function device
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_BRIDGE: u8 = 0x1;
+const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+/// Config space offset of the command register
+const PCI_COMMAND: u16 = 0x04;
+/// Command register: Respond to I/O space accesses
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Command register: Respond to memory space accesses
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Command register: Allow the device to do DMA
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Command register: Stop the device asserting INTx, for MSI/MSI-X
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+/// Config space offset of BAR0, the rest follow it a dword apart
+const PCI_BAR0: u16 = 0x10;
+/// BARs in a type 0 header and a type 1 (bridge) header
+const BARS_TYPE_0: usize = 6;
+const BARS_TYPE_1: usize = 2;
+/// BAR bits: Set for I/O space, memory space otherwise
+const BAR_IO: u32 = 1 << 0;
+const BAR_IO_ADDR_MASK: u32 = !0x3;
+const BAR_MEM_TYPE_MASK: u32 = 0x3 << 1;
+const BAR_MEM_TYPE_64: u32 = 0x2 << 1;
+const BAR_MEM_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEM_ADDR_MASK: u32 = !0xF;
+/// Most devices we keep, any more and [`init`] errors
+pub const MAX_DEVICES: usize = 64;
+
+/// The devices found by [`init`]
+static mut DEVICES: Option<Devices> = None;
+
+/// This struct holds the data for a header type 0x0 PCI Device
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+#[allow(dead_code)]
+struct Header {
+	vendor_id: u16,
+	device_id: u16,
+	command: u16,
+	status: u16,
+	revision_id: u8,
+	prog_if: u8,
+	subclass: u8,
+	class_code: u8,
+	cache_line_size: u8,
+	latency_timer: u8,
+	header_type: u8,
+	bist: u8,
+	base_addr_0: u32,
+	base_addr_1: u32,
+	base_addr_2: u32,
+	base_addr_3: u32,
+	base_addr_4: u32,
+	base_addr_5: u32,
+	cardbus_cis_ptr: u32,
+	subsystem_vendor_id: u16,
+	subsystem_id: u16,
+	expansion_rom_base_addr: u32,
+	capabilities_ptr: u8,
+	_reserved: [u8; 7],
+	interrupt_line: u8,
+	interrupt_pin: u8,
+	min_grant: u8,
+	max_latency: u8,
+}
+
+impl Header {
+	// Goes through the PCI 128-bits and parses out the data
+	fn new(bus: u8, slot: u8, function: u8) -> Self {
+		let mut buffer = [0u32; size_of::<Self>() / size_of::<u32>()];
+
+		for (i, bytes) in buffer.iter_mut().enumerate() {
+			*bytes = config::read_32(bus, slot, function, i as u16 * 4);
+		}
+
+		let header = unsafe { *(buffer.as_ptr() as *const Header) };
+
+		header
+	}
+}
+
+/// A decoded Base Address Register with the size of the region behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+	Io {
+		port: u16,
+		size: u32,
+	},
+	Mem32 {
+		addr: u32,
+		size: u32,
+		prefetchable: bool,
+	},
+	/// Uses this BAR and the next one for the top half of the address
+	Mem64 {
+		addr: u64,
+		size: u64,
+		prefetchable: bool,
+	},
+}
+
+impl Bar {
+	/// The physical address and length if this is a memory BAR
+	pub fn mmio(&self) -> Option<(u64, u64)> {
+		match *self {
+			Bar::Io { .. } => None,
+			Bar::Mem32 { addr, size, .. } => Some((addr as u64, size as u64)),
+			Bar::Mem64 { addr, size, .. } => Some((addr, size)),
+		}
+	}
+	/// The first port and number of ports if this is an I/O BAR
+	#[allow(dead_code)]
+	pub fn io(&self) -> Option<(u16, u32)> {
+		match *self {
+			Bar::Io { port, size } => Some((port, size)),
+			_ => None,
+		}
+	}
+}
+
+/// Struct that holds an Pci [`Device`] that we can expose to other modules
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct Device {
+	header: Header,
+	bus: u8,
+	device: u8,
+	function: u8,
+	/// Sized when we find the device, before any driver turns it on
+	bars: [Option<Bar>; BARS_TYPE_0],
+}
+
+impl Device {
+	/// Creates a new Device when scanned in the PCI memory
+	fn new(bus: u8, device: u8, function: u8) -> Self {
+		let mut found = Self {
+			header: Header::new(bus, device, function),
+			bus,
+			device,
+			function,
+			bars: [None; BARS_TYPE_0],
+		};
+		found.bars = found.probe_bars();
+		found
+	}
+	/// Returns an array of the BAR addresses to a driver
+	#[allow(dead_code)]
+	pub fn base_mem_addrs(&self) -> [u32; 6] {
+		[
+			self.header.base_addr_0,
+			self.header.base_addr_1,
+			self.header.base_addr_2,
+			self.header.base_addr_3,
+			self.header.base_addr_4,
+			self.header.base_addr_5,
+		]
+	}
+	/// How many BARs this kind of header has
+	fn bar_count(&self) -> usize {
+		match self.header.header_type & HEADER_TYPE_MASK {
+			0 => BARS_TYPE_0,
+			HEADER_TYPE_BRIDGE => BARS_TYPE_1,
+			_ => 0,
+		}
+	}
+	/// Write all ones to the BAR at `offset` and read back which address bits
+	/// stick, the BAR is restored after. Decoding must be off while we do this
+	/// so the device does not claim whatever the all ones address hits
+	fn probe_bar(&self, offset: u16) -> u32 {
+		let original = self.read_32(offset);
+		self.write_32(offset, !0);
+		let mask = self.read_32(offset);
+		self.write_32(offset, original);
+		mask
+	}
+	/// Decode and size every BAR, a 64-bit BAR takes two slots so the slot
+	/// after it is always [`None`] as are unimplemented BARs. This turns off
+	/// decoding while it runs so it is only done once from [`Device::new`]
+	fn probe_bars(&self) -> [Option<Bar>; BARS_TYPE_0] {
+		let mut bars = [None; BARS_TYPE_0];
+		let command = self.read_16(PCI_COMMAND);
+		self.write_16(
+			PCI_COMMAND,
+			command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+		);
+
+		let mut index = 0;
+		while index < self.bar_count() {
+			let offset = PCI_BAR0 + index as u16 * 4;
+			let raw = self.read_32(offset);
+			let mask = self.probe_bar(offset);
+			if raw & BAR_IO != 0 {
+				// Some devices only decode 16 bits of I/O address
+				let mut mask = mask & BAR_IO_ADDR_MASK;
+				if mask & 0xFFFF_0000 == 0 {
+					mask |= 0xFFFF_0000;
+				}
+				if mask & 0xFFFF != 0 {
+					bars[index] = Some(Bar::Io {
+						port: (raw & BAR_IO_ADDR_MASK) as u16,
+						size: (!mask).wrapping_add(1),
+					});
+				}
+			} else if raw & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64
+				&& index + 1 < self.bar_count()
+			{
+				let high = self.read_32(offset + 4);
+				let high_mask = self.probe_bar(offset + 4);
+				let mask =
+					(high_mask as u64) << 32 | (mask & BAR_MEM_ADDR_MASK) as u64;
+				if mask != 0 {
+					bars[index] = Some(Bar::Mem64 {
+						addr: (high as u64) << 32 | (raw & BAR_MEM_ADDR_MASK) as u64,
+						size: (!mask).wrapping_add(1),
+						prefetchable: raw & BAR_MEM_PREFETCHABLE != 0,
+					});
+				}
+				index += 1;
+			} else {
+				let mask = mask & BAR_MEM_ADDR_MASK;
+				if mask != 0 {
+					bars[index] = Some(Bar::Mem32 {
+						addr: raw & BAR_MEM_ADDR_MASK,
+						size: (!mask).wrapping_add(1),
+						prefetchable: raw & BAR_MEM_PREFETCHABLE != 0,
+					});
+				}
+			}
+			index += 1;
+		}
+
+		self.write_16(PCI_COMMAND, command);
+		bars
+	}
+	/// Every BAR decoded and sized, see [`Device::probe_bars`]
+	pub fn bars(&self) -> [Option<Bar>; BARS_TYPE_0] {
+		self.bars
+	}
+	/// The BAR at `index` decoded and sized, see [`Device::probe_bars`]
+	pub fn bar(&self, index: usize) -> Option<Bar> {
+		self.bars.get(index).copied().flatten()
+	}
+	/// Returns the [Device.header.device_id] and [Device.header.vendor_id] for
+	/// validation that we support this device
+	pub fn did_vid(&self) -> (u16, u16) {
+		(self.header.device_id, self.header.vendor_id)
+	}
+	/// The class and subclass codes, for drivers that handle a kind of device
+	/// instead of particular IDs
+	pub fn class(&self) -> (u8, u8) {
+		(self.header.class_code, self.header.subclass)
+	}
+	/// Read a dword from this device's config space
+	pub fn read_32(&self, offset: u16) -> u32 {
+		config::read_32(self.bus, self.device, self.function, offset)
+	}
+	pub fn read_16(&self, offset: u16) -> u16 {
+		config::read_16(self.bus, self.device, self.function, offset)
+	}
+	pub fn read_8(&self, offset: u16) -> u8 {
+		config::read_8(self.bus, self.device, self.function, offset)
+	}
+	/// Write a dword to this device's config space
+	pub fn write_32(&self, offset: u16, val: u32) {
+		config::write_32(self.bus, self.device, self.function, offset, val)
+	}
+	pub fn write_16(&self, offset: u16, val: u16) {
+		config::write_16(self.bus, self.device, self.function, offset, val)
+	}
+	#[allow(dead_code)]
+	pub fn write_8(&self, offset: u16, val: u8) {
+		config::write_8(self.bus, self.device, self.function, offset, val)
+	}
+	/// Turn on DMA, memory and I/O decoding in the command register, firmware
+	/// does not have to leave them on for us. Returns the new command value,
+	/// bits that are already set stay set
+	pub fn enable(&self, bus_master: bool, memory: bool, io: bool) -> u16 {
+		let mut command = self.read_16(PCI_COMMAND);
+		if bus_master {
+			command |= COMMAND_BUS_MASTER;
+		}
+		if memory {
+			command |= COMMAND_MEMORY_SPACE;
+		}
+		if io {
+			command |= COMMAND_IO_SPACE;
+		}
+		self.write_16(PCI_COMMAND, command);
+		command
+	}
+	/// True if functions 1-7 of this device may exist too
+	fn is_multifunction(&self) -> bool {
+		self.header.header_type & HEADER_TYPE_MULTIFUNCTION != 0
+	}
+	/// The bus behind this device if it is a PCI-to-PCI bridge. In a type 1
+	/// header the primary, secondary and subordinate bus numbers sit where
+	/// BAR2 is in a type 0 header
+	fn secondary_bus(&self) -> Option<u8> {
+		let is_bridge = self.header.header_type & HEADER_TYPE_MASK
+			== HEADER_TYPE_BRIDGE
+			&& self.header.class_code == PCI_CLASS_CODE_BRIDGE
+			&& self.header.subclass == PCI_SUBCLASS_CODE_PCI_BRIDGE;
+		is_bridge.then_some((self.header.base_addr_2 >> 8) as u8)
+	}
+}
+/// Struct that holds an Array of  [`Devices`] that we can expose to other
+/// modules
+#[derive(Debug)]
+pub struct Devices([Option<Device>; MAX_DEVICES]);
+
+impl core::fmt::Display for Devices {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "PCI: {} devices", self.iter().count())?;
+		#[cfg(feature = "lspci")]
+		for device in self.iter() {
+			write!(f, "\n  {}", device)?;
+		}
+		Ok(())
+	}
+}
+
+/// Walks the buses from bus 0 collecting every function it finds
+struct Scan {
+	devices: Devices,
+	/// Buses we have scanned, so a misconfigured bridge cannot loop us
+	visited: [bool; PCI_BUS_LEN],
+}
+
+impl Scan {
+	/// Scan every slot on `bus`
+	fn bus(&mut self, bus: u8) -> Result<()> {
+		if core::mem::replace(&mut self.visited[bus as usize], true) {
+			return Ok(());
+		}
+		for device in 0..PCI_DEVICE_LEN {
+			self.device(bus, device)?;
+		}
+		Ok(())
+	}
+	/// Scan function 0 of a slot and the rest if it is multi-function
+	fn device(&mut self, bus: u8, device: u8) -> Result<()> {
+		let first = match self.function(bus, device, 0)? {
+			Some(first) => first,
+			None => return Ok(()),
+		};
+		if first.is_multifunction() {
+			for function in 1..PCI_FUNCTION_LEN {
+				self.function(bus, device, function)?;
+			}
+		}
+		Ok(())
+	}
+	/// Record the function if it exists and follow it if it is a bridge
+	fn function(
+		&mut self,
+		bus: u8,
+		device: u8,
+		function: u8,
+	) -> Result<Option<Device>> {
+		// If no PCI Device we recieve 0xFFFFFFFF
+		if config::read_32(bus, device, function, 0) == !0 {
+			return Ok(None);
+		}
+		let found = Device::new(bus, device, function);
+		self.devices.push(found)?;
+		if let Some(secondary) = found.secondary_bus() {
+			self.bus(secondary)?;
+		}
+		Ok(Some(found))
+	}
+}
+
+/// Starts the process of finding the PCI devices and exposing them to the rest
+/// of the program. If 0:0.0 is a multi-function host bridge each function is a
+/// separate host controller for the bus of the same number. Finding more than
+/// [`MAX_DEVICES`] is an error but we keep the ones that fit
+pub fn init() -> Result<&'static Devices> {
+	let mut scan = Scan {
+		devices: Devices([None; MAX_DEVICES]),
+		visited: [false; PCI_BUS_LEN],
+	};
+	let host = Header::new(0, 0, 0);
+	let result = if host.header_type & HEADER_TYPE_MULTIFUNCTION == 0 {
+		scan.bus(0)
+	} else {
+		(0..PCI_FUNCTION_LEN)
+			.filter(|&function| config::read_32(0, 0, function, 0) != !0)
+			.try_for_each(|function| scan.bus(function))
+	};
+	let devices = unsafe {
+		DEVICES = Some(scan.devices);
+		(*core::ptr::addr_of!(DEVICES)).as_ref().unwrap()
+	};
+	result.map(|_| devices)
+}
+
+/// The devices [`init`] found, [`None`] if it has not run
+pub fn get() -> Option<&'static Devices> {
+	unsafe { (*core::ptr::addr_of!(DEVICES)).as_ref() }
+}
+
+impl Devices {
+	/// Add a device, errors once we have [`MAX_DEVICES`]
+	fn push(&mut self, device: Device) -> Result<()> {
+		match self.0.iter_mut().find(|slot| slot.is_none()) {
+			Some(slot) => {
+				*slot = Some(device);
+				Ok(())
+			}
+			None => Err(Error::TooManyPciDevices(MAX_DEVICES)),
+		}
+	}
+	/// Every device found
+	pub fn iter(&self) -> impl Iterator<Item = &Device> {
+		self.0.iter().flatten()
+	}
+	/// Returns the first NIC it finds of type [`Some`] [`Device`] and [`None`]
+	/// if no PCI NIC is found
+	pub fn get_nic(&self) -> Option<Device> {
+		self.0.iter().find_map(|&device| match device {
+			Some(d) => {
+				if (d.header.class_code == PCI_CLASS_CODE_NETWORK)
+					&& (d.header.subclass == PCI_SUBCLASS_CODE_ETHERNET)
+				{
+					Some(d)
+				} else {
+					None
+				}
+			}
+			None => None,
+		})
+	}
+}
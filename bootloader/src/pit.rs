@@ -0,0 +1,81 @@
+//! The legacy Programmable Interval Timer, it runs at a known frequency so we
+//! use it to calibrate the faster timers. We only ever poll it as we have no
+//! IDT `<https://wiki.osdev.org/Programmable_Interval_Timer>`
+use crate::cpu;
+
+/// The PIT input clock in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Channel 0 data port, we let this channel free run as a clock when there is
+/// no HPET
+const CHANNEL_0: u16 = 0x40;
+/// Channel 2 data port, this channel's gate and output can be read through
+/// [`PORT_B`]
+const CHANNEL_2: u16 = 0x42;
+/// Mode/Command register
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B, bit 0 gates channel 2, bit 1 connects it to the
+/// speaker and bit 5 is the channel 2 output
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT: u8 = 1 << 5;
+
+/// Channel 2 one shot: Bits 7-6 select channel 2 (`10`), bits 5-4 access
+/// lobyte then hibyte (`11`), bits 3-1 mode 0, interrupt on terminal count
+/// (`000`) and bit 0 binary counting (`0`)
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// Channel 0 rate: Bits 7-6 select channel 0 (`00`), bits 5-4 access lobyte
+/// then hibyte (`11`), bits 3-1 mode 2, rate generator (`010`) and bit 0
+/// binary counting (`0`)
+const CHANNEL_0_RATE: u8 = 0b0011_0100;
+/// Channel 0 latch: Bits 7-6 select channel 0 (`00`) and bits 5-4 latch the
+/// current count (`00`), the rest is ignored
+const CHANNEL_0_LATCH: u8 = 0b0000_0000;
+
+/// The longest wait one count of the 16-bit counter can give us
+const MAX_WAIT_US: u32 = 50_000;
+
+/// Busy wait for `us` microseconds using channel 2, anything longer than
+/// [`MAX_WAIT_US`] is split into multiple counts
+pub fn wait_us(mut us: u32) {
+	while us > 0 {
+		let chunk = us.min(MAX_WAIT_US);
+		one_shot((PIT_FREQUENCY as u64 * chunk as u64 / 1_000_000) as u16);
+		us -= chunk;
+	}
+}
+
+/// Count down `count` ticks on channel 2 and wait for the output to go high
+fn one_shot(count: u16) {
+	// Gate off and disconnect the speaker while we program the count
+	let port_b = cpu::in8(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER);
+	cpu::out8(PORT_B, port_b);
+
+	cpu::out8(COMMAND, CHANNEL_2_ONE_SHOT);
+	cpu::out8(CHANNEL_2, count as u8);
+	cpu::out8(CHANNEL_2, (count >> 8) as u8);
+
+	// Raising the gate starts the count
+	cpu::out8(PORT_B, port_b | PORT_B_GATE);
+	while cpu::in8(PORT_B) & PORT_B_OUT == 0 {}
+
+	cpu::out8(PORT_B, port_b);
+}
+
+/// Start channel 0 counting down from 65536 over and over, IRQ0 stays masked
+/// so this is only a clock for [`read_counter`]
+pub fn start_counter() {
+	cpu::out8(COMMAND, CHANNEL_0_RATE);
+	cpu::out8(CHANNEL_0, 0);
+	cpu::out8(CHANNEL_0, 0);
+}
+
+/// Latch and read the current count of channel 0, it counts down and wraps
+/// every 65536 ticks (~55ms)
+pub fn read_counter() -> u16 {
+	cpu::out8(COMMAND, CHANNEL_0_LATCH);
+	let low = cpu::in8(CHANNEL_0) as u16;
+	let high = cpu::in8(CHANNEL_0) as u16;
+	high << 8 | low
+}
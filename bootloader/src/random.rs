@@ -0,0 +1,293 @@
+//! This crate gives us random numbers for protocol fields that must not
+//! collide between machines, like DHCP transaction IDs, UDP source ports and
+//! IPv4 identification. The seed comes from RDRAND when CPUID reports it,
+//! otherwise we mix whatever changes between boots: the TSC, the RTC, the
+//! HPET counter and later the MAC address. The seed keys a ChaCha20 generator
+//! which rekeys itself from its own output after every block, so reading the
+//! state later does not give away the numbers already handed out
+//! `<https://www.rfc-editor.org/rfc/rfc8439>`
+use crate::cpu;
+use crate::cpuid;
+use crate::hpet;
+use crate::time;
+
+/// "expand 32-byte k", the ChaCha constants
+const CONSTANTS: [u32; 4] =
+	[0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+/// Bytes of key at the start of each block we keep for the next key
+const KEY_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+/// The SDM recommends giving up on RDRAND after 10 failed attempts
+const RDRAND_RETRIES: usize = 10;
+
+/// Seeded by [`init`], or by the first caller if nothing called it
+static mut RNG: Option<ChaCha> = None;
+
+/// A ChaCha20 generator with fast key erasure
+#[derive(Clone)]
+struct ChaCha {
+	key: [u32; 8],
+	counter: u64,
+	/// Output of the last block past the bytes we took for the key
+	buf: [u8; BLOCK_LEN],
+	/// Next byte of `buf` to hand out
+	pos: usize,
+}
+
+/// The ChaCha quarter round on the state words `a`, `b`, `c` and `d`
+fn quarter_round(
+	state: &mut [u32; 16],
+	a: usize,
+	b: usize,
+	c: usize,
+	d: usize,
+) {
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(16);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(12);
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(8);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+impl ChaCha {
+	fn new(key: [u32; 8]) -> Self {
+		let mut rng = Self {
+			key,
+			counter: 0,
+			buf: [0; BLOCK_LEN],
+			pos: BLOCK_LEN,
+		};
+		rng.refill();
+		rng
+	}
+	/// Run the 20 rounds on the next block, the first [`KEY_LEN`] bytes
+	/// become the key and the rest are handed out
+	fn refill(&mut self) {
+		let mut input = [0u32; 16];
+		input[..4].copy_from_slice(&CONSTANTS);
+		input[4..12].copy_from_slice(&self.key);
+		input[12] = self.counter as u32;
+		input[13] = (self.counter >> 32) as u32;
+		self.counter += 1;
+
+		let mut state = input;
+		for _ in 0..10 {
+			quarter_round(&mut state, 0, 4, 8, 12);
+			quarter_round(&mut state, 1, 5, 9, 13);
+			quarter_round(&mut state, 2, 6, 10, 14);
+			quarter_round(&mut state, 3, 7, 11, 15);
+			quarter_round(&mut state, 0, 5, 10, 15);
+			quarter_round(&mut state, 1, 6, 11, 12);
+			quarter_round(&mut state, 2, 7, 8, 13);
+			quarter_round(&mut state, 3, 4, 9, 14);
+		}
+		for (index, word) in state.iter().enumerate() {
+			let word = word.wrapping_add(input[index]);
+			self.buf[index * 4..index * 4 + 4]
+				.copy_from_slice(&word.to_le_bytes());
+		}
+
+		for (index, word) in self.key.iter_mut().enumerate() {
+			let bytes = &mut self.buf[index * 4..index * 4 + 4];
+			*word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+			bytes.fill(0);
+		}
+		self.pos = KEY_LEN;
+	}
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		for byte in dest.iter_mut() {
+			if self.pos == BLOCK_LEN {
+				self.refill();
+			}
+			*byte = self.buf[self.pos];
+			self.buf[self.pos] = 0;
+			self.pos += 1;
+		}
+	}
+	/// XOR `data` into the key and start a new block so it takes effect
+	fn mix(&mut self, data: &[u8]) {
+		for (index, byte) in data.iter().enumerate() {
+			self.key[(index / 4) % 8] ^= (*byte as u32) << ((index % 4) * 8);
+		}
+		self.refill();
+	}
+}
+
+/// One word from RDRAND, retrying as the SDM recommends
+fn rdrand() -> Option<u32> {
+	(0..RDRAND_RETRIES).find_map(|_| cpu::rdrand())
+}
+
+/// Build a key from RDRAND if we have it, the TSC and the RTC are mixed in
+/// either way as they cost nothing and never hurt
+fn seed() -> [u32; 8] {
+	let features = cpuid::features();
+	let mut key = [0u32; 8];
+	let mut from_rdrand = features.rdrand;
+	if from_rdrand {
+		for word in key.iter_mut() {
+			match rdrand() {
+				Some(val) => *word = val,
+				None => {
+					from_rdrand = false;
+					break;
+				}
+			}
+		}
+	}
+	if !from_rdrand {
+		print!("No RDRAND, seeding random numbers from the clocks\n");
+	}
+
+	if features.tsc {
+		let tsc = cpu::rdtsc();
+		key[0] ^= tsc as u32;
+		key[1] ^= (tsc >> 32) as u32;
+	}
+	let rtc = time::DateTime::now().raw();
+	key[2] ^= u32::from_le_bytes([rtc[0], rtc[1], rtc[2], rtc[3]]);
+	key[3] ^= u32::from_le_bytes([rtc[4], rtc[5], rtc[6], 0]);
+	if let Some(hpet) = hpet::get() {
+		key[4] ^= hpet.counter() as u32;
+	}
+	key[5] ^= cpu::esp();
+	// The TSC again, how long the reads above took varies from boot to boot
+	if features.tsc {
+		key[6] ^= cpu::rdtsc() as u32;
+	}
+	key
+}
+
+/// Run `f` with the generator, seeding it first if [`init`] has not run
+fn with<T>(f: impl FnOnce(&mut ChaCha) -> T) -> T {
+	let rng = unsafe { &mut *core::ptr::addr_of_mut!(RNG) };
+	f(rng.get_or_insert_with(|| ChaCha::new(seed())))
+}
+
+/// Seed the generator, call this once the clock source is up so there is as
+/// much as possible to mix in when there is no RDRAND
+pub fn init() {
+	let rng = ChaCha::new(seed());
+	unsafe { RNG = Some(rng) };
+}
+
+/// Mix more entropy into the generator, such as a MAC address so two
+/// machines that boot together without RDRAND still diverge
+pub fn mix(data: &[u8]) {
+	with(|rng| rng.mix(data));
+}
+
+/// Fill `dest` with random bytes
+pub fn fill_bytes(dest: &mut [u8]) {
+	with(|rng| rng.fill_bytes(dest));
+}
+
+/// A random u32
+pub fn u32() -> u32 {
+	let mut bytes = [0u8; 4];
+	fill_bytes(&mut bytes);
+	u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The whole keystream block for `key` and `counter`, put back together
+	/// from the next key and the bytes [`ChaCha::refill`] leaves to hand out
+	fn block(key: [u8; 32], counter: u64) -> [u8; BLOCK_LEN] {
+		let mut rng = ChaCha {
+			key: [0; 8],
+			counter,
+			buf: [0; BLOCK_LEN],
+			pos: BLOCK_LEN,
+		};
+		let (words, _) = key.as_chunks::<4>();
+		for (word, bytes) in rng.key.iter_mut().zip(words) {
+			*word = u32::from_le_bytes(*bytes);
+		}
+		rng.refill();
+		let mut block = rng.buf;
+		for (index, word) in rng.key.iter().enumerate() {
+			block[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+		}
+		block
+	}
+
+	fn hex(s: &str) -> [u8; BLOCK_LEN] {
+		let mut bytes = [0; BLOCK_LEN];
+		for (index, byte) in bytes.iter_mut().enumerate() {
+			*byte = u8::from_str_radix(&s[index * 2..index * 2 + 2], 16).unwrap();
+		}
+		bytes
+	}
+
+	/// RFC 8439 2.1.1
+	#[test]
+	fn quarter_round_vector() {
+		let mut state = [0u32; 16];
+		state[..4].copy_from_slice(&[
+			0x1111_1111,
+			0x0102_0304,
+			0x9B8D_6F43,
+			0x0123_4567,
+		]);
+		quarter_round(&mut state, 0, 1, 2, 3);
+		assert_eq!(
+			state[..4],
+			[0xEA2A_92F4, 0xCB1C_F8CE, 0x4581_472E, 0x5881_C4BB]
+		);
+	}
+
+	/// RFC 8439 A.1 test vectors 1-4, our nonce is always zero
+	#[test]
+	fn block_vectors() {
+		let mut key = [0u8; 32];
+		assert_eq!(
+			block(key, 0),
+			hex(
+				"76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+				 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+			)
+		);
+		assert_eq!(
+			block(key, 1),
+			hex(
+				"9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+				 29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f"
+			)
+		);
+		key[31] = 1;
+		assert_eq!(
+			block(key, 1),
+			hex(
+				"3aeb5224ecf849929b9d828db1ced4dd832025e8018b8160b82284f3c949aa5a\
+				 8eca00bbb4a73bdad192b5c42f73f2fd4e273644c8b36125a64addeb006c13a0"
+			)
+		);
+		key[31] = 0;
+		key[1] = 0xFF;
+		assert_eq!(
+			block(key, 2),
+			hex(
+				"72d54dfbf12ec44b362692df94137f328fea8da73990265ec1bbbea1ae9af0ca\
+				 13b25aa26cb4a648cb9b9d1be65b2c0924a66c54d545ec1b7374f4872e99f096"
+			)
+		);
+	}
+
+	/// The key for the next block never stays in the buffer and bytes are
+	/// wiped as they are handed out
+	#[test]
+	fn key_erasure() {
+		let mut rng = ChaCha::new([0; 8]);
+		assert_eq!(rng.buf[..KEY_LEN], [0; KEY_LEN]);
+		let mut first = [0u8; 8];
+		rng.fill_bytes(&mut first);
+		assert_eq!(first[..], block([0; 32], 0)[KEY_LEN..KEY_LEN + 8]);
+		assert_eq!(rng.buf[..KEY_LEN + 8], [0; KEY_LEN + 8]);
+	}
+}
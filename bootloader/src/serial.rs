@@ -76,6 +76,7 @@ impl core::fmt::Write for SerialWriter {
 			print!("\n\x1b[1;32mInitialising Serial...\n");
 		}
 		write(s.as_bytes());
+		crate::log::record(s.as_bytes());
 		Ok(())
 	}
 }
@@ -0,0 +1,262 @@
+//! This crate brings up the application processors (APs) listed in the ACPI
+//! MADT with the INIT-SIPI-SIPI sequence. Each AP starts in real mode in our
+//! trampoline, switches itself to protected mode and lands in [`ap_entry`] on
+//! its own stack, where it waits for work given to it with [`run_on`]
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use crate::acpi::{self, MadtEntry};
+use crate::apic::{self, ICR_ASSERT, ICR_INIT, ICR_STARTUP};
+use crate::error::{Error, Result};
+use crate::memory::{paging, regions};
+use crate::pit;
+use crate::stack;
+
+/// Top of the BSP stack set up by `stage0.asm`
+pub const BSP_STACK_TOP: usize = 0x2000000;
+/// How much of the region below [`BSP_STACK_TOP`] belongs to the BSP
+pub const BSP_STACK_SIZE: usize = 0x100000;
+/// Each AP gets a stack of this size carved out below the BSP stack
+pub const AP_STACK_SIZE: usize = 0x10000;
+/// Most processors we bring up, including the BSP
+pub const MAX_CPUS: usize = 16;
+
+/// The page we copy the trampoline to, an AP starts executing at the page
+/// given in the SIPI so this must be page aligned and below 1MiB
+const TRAMPOLINE_ADDR: usize = 0x1000;
+/// MADT Local APIC flags: Enabled or can be enabled
+const MADT_LAPIC_ENABLED: u32 = 1 << 0;
+const MADT_LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Stack top the next AP to start should use, read by the trampoline
+static AP_STACK: AtomicUsize = AtomicUsize::new(0);
+/// CPU index the next AP to start should use, read by the trampoline
+static AP_CPU: AtomicU32 = AtomicU32::new(0);
+/// Set by the AP once it is running Rust so the BSP can start the next one
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The APIC ID of each CPU index, the BSP is always index 0
+static mut CPUS: [Option<u8>; MAX_CPUS] = [None; MAX_CPUS];
+/// Work for each CPU, 0 when idle otherwise the address of a `fn()`
+static MAILBOX: [AtomicUsize; MAX_CPUS] =
+	[const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// The AP starts here in real mode with CS:IP at TRAMPOLINE_ADDR:0, this part is
+// copied down to TRAMPOLINE_ADDR so everything it touches has to be relative
+// to the start of the trampoline. Once in protected mode we far jump back
+// into our image and pick up the stack and CPU index the BSP left for us
+#[cfg(not(test))]
+global_asm!(
+	".code16",
+	".globl ap_trampoline",
+	".globl ap_trampoline_end",
+	"ap_trampoline:",
+	"cli",
+	"cld",
+	"mov ax, cs",
+	"mov ds, ax",
+	"lgdt [AP_TRAMPOLINE_GDTR]",
+	"mov eax, cr0",
+	"or al, 1",
+	"mov cr0, eax",
+	// jmp dword 0x08:ap_protected
+	".byte 0x66, 0xEA",
+	".long ap_protected",
+	".word 0x08",
+	".balign 8",
+	"ap_trampoline_gdt:",
+	".quad 0x0000000000000000",
+	".quad 0x00CF9A000000FFFF",
+	".quad 0x00CF92000000FFFF",
+	"ap_trampoline_gdtr:",
+	".word ap_trampoline_gdtr - ap_trampoline_gdt - 1",
+	".long {base} + ap_trampoline_gdt - ap_trampoline",
+	"ap_trampoline_end:",
+	".set AP_TRAMPOLINE_GDTR, ap_trampoline_gdtr - ap_trampoline",
+	".code32",
+	"ap_protected:",
+	"mov ax, 0x10",
+	"mov ds, ax",
+	"mov es, ax",
+	"mov fs, ax",
+	"mov gs, ax",
+	"mov ss, ax",
+	"mov esp, dword ptr [{stack}]",
+	"push dword ptr [{cpu}]",
+	"call {entry}",
+	base = const TRAMPOLINE_ADDR,
+	stack = sym AP_STACK,
+	cpu = sym AP_CPU,
+	entry = sym ap_entry,
+);
+
+/// The bytes of the trampoline we copy to [`TRAMPOLINE_ADDR`]
+fn trampoline() -> &'static [u8] {
+	let (start, end): (usize, usize);
+	unsafe {
+		asm!(
+			"lea {start}, [ap_trampoline]",
+			"lea {end}, [ap_trampoline_end]",
+			start = out(reg) start,
+			end = out(reg) end,
+		);
+		core::slice::from_raw_parts(start as *const u8, end - start)
+	}
+}
+
+/// Where every AP lands once the trampoline has it in protected mode
+extern "C" fn ap_entry(cpu: u32) -> ! {
+	paging::init_ap();
+	if let Some(apic) = apic::get() {
+		apic.local.enable(None);
+	}
+	AP_STARTED.store(true, Ordering::Release);
+
+	let mailbox = &MAILBOX[cpu as usize];
+	loop {
+		let work = mailbox.load(Ordering::Acquire);
+		if work == 0 {
+			core::hint::spin_loop();
+			continue;
+		}
+		let work: fn() = unsafe { core::mem::transmute(work) };
+		work();
+		mailbox.store(0, Ordering::Release);
+	}
+}
+
+/// Stack top for the CPU at `index`, the BSP has the one `stage0.asm` made
+pub fn stack_top(index: usize) -> usize {
+	match index {
+		0 => BSP_STACK_TOP,
+		_ => BSP_STACK_TOP - BSP_STACK_SIZE - (index - 1) * AP_STACK_SIZE,
+	}
+}
+
+/// The page at the bottom of the stack for the CPU at `index`, paging leaves
+/// it unmapped so running off the end of the stack faults
+pub fn stack_guard(index: usize) -> usize {
+	match index {
+		0 => BSP_STACK_TOP - BSP_STACK_SIZE,
+		_ => stack_top(index) - AP_STACK_SIZE,
+	}
+}
+
+/// INIT-SIPI-SIPI a processor and wait for it to reach [`ap_entry`]
+fn start_ap(apic: &apic::Apic, apic_id: u8, index: usize) -> bool {
+	stack::paint(index);
+	AP_STACK.store(stack_top(index), Ordering::Release);
+	AP_CPU.store(index as u32, Ordering::Release);
+	AP_STARTED.store(false, Ordering::Release);
+
+	apic.local.send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+	pit::wait_us(10_000);
+
+	// A second SIPI is only needed if the first one was missed
+	let sipi = ICR_STARTUP | ICR_ASSERT | (TRAMPOLINE_ADDR >> 12) as u32;
+	for _ in 0..2 {
+		apic.local.send_ipi(apic_id, sipi);
+		pit::wait_us(200);
+		if AP_STARTED.load(Ordering::Acquire) {
+			return true;
+		}
+	}
+
+	// Give a slow processor up to 100ms before giving up on it
+	for _ in 0..100 {
+		if AP_STARTED.load(Ordering::Acquire) {
+			return true;
+		}
+		pit::wait_us(1000);
+	}
+	false
+}
+
+/// Start every enabled processor in the MADT, returns how many CPUs are
+/// running including the BSP
+pub fn init() -> Result<usize> {
+	let apic = apic::get().ok_or(Error::NoMadt)?;
+	let madt = acpi::get()
+		.and_then(|acpi| acpi.madt())
+		.ok_or(Error::NoMadt)?;
+
+	let trampoline = trampoline();
+	regions::claim(
+		"AP trampoline",
+		TRAMPOLINE_ADDR as u64,
+		trampoline.len() as u64,
+	);
+	regions::claim(
+		"AP stacks",
+		(stack_top(MAX_CPUS - 1) - AP_STACK_SIZE) as u64,
+		((MAX_CPUS - 1) * AP_STACK_SIZE) as u64,
+	);
+	unsafe {
+		core::ptr::copy_nonoverlapping(
+			trampoline.as_ptr(),
+			TRAMPOLINE_ADDR as *mut u8,
+			trampoline.len(),
+		);
+	}
+
+	let bsp_id = apic.local.id();
+	unsafe { CPUS[0] = Some(bsp_id) };
+	let mut online = 1;
+
+	for entry in madt.entries() {
+		let (apic_id, flags) = match entry {
+			MadtEntry::LocalApic { apic_id, flags, .. } => (apic_id, flags),
+			_ => continue,
+		};
+		if apic_id == bsp_id
+			|| flags & (MADT_LAPIC_ENABLED | MADT_LAPIC_ONLINE_CAPABLE) == 0
+		{
+			continue;
+		}
+		if online == MAX_CPUS {
+			print!("Too many CPUs, ignoring APIC ID: {}\n", apic_id);
+			continue;
+		}
+		if start_ap(&apic, apic_id, online) {
+			unsafe { CPUS[online] = Some(apic_id) };
+			online += 1;
+		} else {
+			print!("CPU with APIC ID {} did not start\n", apic_id);
+		}
+	}
+	Ok(online)
+}
+
+/// Number of CPUs running, including the BSP
+pub fn count() -> usize {
+	(0..MAX_CPUS)
+		.take_while(|&cpu| apic_id(cpu).is_some())
+		.count()
+}
+
+/// The APIC ID of the CPU at `index`
+pub fn apic_id(index: usize) -> Option<u8> {
+	let cpus = unsafe { CPUS };
+	cpus.get(index).copied().flatten()
+}
+
+/// Ask the AP at `index` to run `work`, it runs asynchronously on that CPU.
+/// Errors if there is no such AP or it has not finished its last work yet
+#[allow(dead_code)]
+pub fn run_on(index: usize, work: fn()) -> Result<()> {
+	if index == 0 || apic_id(index).is_none() {
+		return Err(Error::NoSuchCpu(index));
+	}
+	MAILBOX[index]
+		.compare_exchange(0, work as usize, Ordering::AcqRel, Ordering::Acquire)
+		.map(|_| ())
+		.map_err(|_| Error::CpuBusy(index))
+}
+
+/// True if the AP at `index` has no work queued or running
+#[allow(dead_code)]
+pub fn is_idle(index: usize) -> bool {
+	MAILBOX
+		.get(index)
+		.is_some_and(|mailbox| mailbox.load(Ordering::Acquire) == 0)
+}
@@ -0,0 +1,153 @@
+//! This crate watches the stacks for overflows. The page above each guard page
+//! is a canary region filled with a known pattern that `__chkstk` will not let
+//! a frame reach, it panics with the caller instead. Frames smaller than a page
+//! never call `__chkstk` so we also check the canaries are intact with
+//! [`check`]. The rest of each stack is painted with the same pattern so the
+//! deepest the stack has been is where the pattern stops, see [`usage`]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu;
+use crate::smp;
+
+const PAGE_SIZE: usize = 0x1000;
+/// What we paint unused stack with
+const CANARY: u32 = 0x5AC4_C0DE;
+/// Bytes above the guard page `__chkstk` keeps frames out of
+const CANARY_SIZE: usize = 0x1000;
+/// Room we leave below the BSP stack pointer when painting its stack, so we
+/// do not paint over our own frame
+const PAINT_MARGIN: usize = 0x100;
+
+/// Set once we are panicking about an overflow, from then on frames may use
+/// the canary region so the panic has room to print
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+/// How much of a stack has been used
+#[derive(Debug, Clone, Copy)]
+pub struct StackUsage {
+	pub cpu: usize,
+	/// Deepest the stack has been in bytes
+	pub high_water: usize,
+	/// Bytes usable above the canary region
+	pub size: usize,
+	pub canary_intact: bool,
+}
+
+impl core::fmt::Display for StackUsage {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"CPU {} stack: {}/{} KiB high water",
+			self.cpu,
+			self.high_water / 1024,
+			self.size / 1024,
+		)?;
+		if !self.canary_intact {
+			write!(f, ", canary overwritten")?;
+		}
+		Ok(())
+	}
+}
+
+/// The first usable byte of the stack for the CPU at `index`, above its
+/// guard page
+fn bottom(index: usize) -> usize {
+	smp::stack_guard(index) + PAGE_SIZE
+}
+
+/// The CPU whose stack `esp` is in
+fn cpu_of(esp: usize) -> Option<usize> {
+	(0..smp::MAX_CPUS)
+		.find(|&index| (bottom(index)..smp::stack_top(index)).contains(&esp))
+}
+
+/// The stack `addr` is in, from the bottom of its canary region to its top
+pub fn bounds(addr: usize) -> Option<(usize, usize)> {
+	cpu_of(addr).map(|index| (bottom(index), smp::stack_top(index)))
+}
+
+/// Fill `start..end` with [`CANARY`]
+fn paint_range(start: usize, end: usize) {
+	for addr in (start..end).step_by(4) {
+		unsafe { (addr as *mut u32).write_volatile(CANARY) };
+	}
+}
+
+/// Paint the whole stack of the AP at `index`, call this before starting it
+pub fn paint(index: usize) {
+	paint_range(bottom(index), smp::stack_top(index));
+}
+
+/// Paint the unused part of the BSP stack we are running on
+pub fn init() {
+	let esp = cpu::esp() as usize;
+	paint_range(bottom(0), (esp - PAINT_MARGIN) & !3);
+}
+
+/// Bytes from `start` up to `end` still holding [`CANARY`]
+fn untouched(start: usize, end: usize) -> usize {
+	(start..end)
+		.step_by(4)
+		.take_while(|&addr| {
+			let word = unsafe { (addr as *const u32).read_volatile() };
+			word == CANARY
+		})
+		.count()
+		* 4
+}
+
+/// True if nothing has written into the canary region of the stack at `index`
+fn canary_intact(index: usize) -> bool {
+	let start = bottom(index);
+	untouched(start, start + CANARY_SIZE) == CANARY_SIZE
+}
+
+/// How much of the stack of the CPU at `index` has ever been used
+pub fn usage(index: usize) -> StackUsage {
+	let total = smp::stack_top(index) - bottom(index);
+	let untouched = untouched(bottom(index), smp::stack_top(index));
+	StackUsage {
+		cpu: index,
+		high_water: total - untouched,
+		size: total - CANARY_SIZE,
+		canary_intact: untouched >= CANARY_SIZE,
+	}
+}
+
+/// Panic if any running CPU has written into its canary region, this only
+/// reads the canary regions so it is cheap enough for the main loop
+pub fn check() {
+	for index in 0..smp::count().max(1) {
+		if !canary_intact(index) {
+			panic!("Stack overflow detected: {}", usage(index));
+		}
+	}
+}
+
+/// Print the high water mark of every running CPU over serial
+pub fn report() {
+	for index in 0..smp::count().max(1) {
+		print!("{}\n", usage(index));
+	}
+}
+
+/// Called by `__chkstk` with the stack pointer of the function wanting a big
+/// frame, the lowest the new frame may take it. Stacks we do not know about
+/// are not checked
+pub extern "C" fn chkstk_limit(esp: usize) -> usize {
+	match cpu_of(esp) {
+		Some(index) if OVERFLOWED.load(Ordering::Relaxed) => bottom(index),
+		Some(index) => bottom(index) + CANARY_SIZE,
+		None => 0,
+	}
+}
+
+/// Called by `__chkstk` when a frame would reach past [`chkstk_limit`],
+/// `caller` is the return address into the function that wanted the frame
+pub extern "C" fn chkstk_overflow(caller: usize, esp: usize) -> ! {
+	OVERFLOWED.store(true, Ordering::Relaxed);
+	panic!(
+		"stack overflow in {:#X}, its frame would take esp to {:#X}",
+		caller, esp
+	);
+}
@@ -1,8 +1,83 @@
 //! This crate gets the time from the CMOS on the motherboard, currently can
-//! just just capture current time with pretty print
+//! capture and set the current time with pretty print, and configure the RTC
+//! periodic and alarm interrupts as an alternate timer source. It also
+//! provides a monotonic [`Instant`] backed by the TSC when CPUID says it is
+//! invariant, otherwise the HPET main counter, or the PIT when there is no HPET
 //! #TODO
 //! * Epoch Time
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::apic;
 use crate::cpu;
+use crate::cpuid;
+use crate::error::{Error, Result};
+use crate::hpet::{self, Hpet};
+use crate::pit;
+
+/// RTC register offsets `<https://wiki.osdev.org/CMOS>`
+const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+const RTC_CENTURY: u8 = 0x32;
+
+/// Status Register A: Update in progress, the time registers are unstable
+const STATUS_A_UIP: u8 = 1 << 7;
+/// Status Register A: Low nibble selects the periodic interrupt rate
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+/// Status Register B: Halt updates so we can safely write the time registers
+const STATUS_B_SET: u8 = 1 << 7;
+/// Status Register B: Periodic interrupt enable
+const STATUS_B_PIE: u8 = 1 << 6;
+/// Status Register B: Alarm interrupt enable
+const STATUS_B_AIE: u8 = 1 << 5;
+/// Status Register B: Registers are binary instead of BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status Register B: Hours are 24 hour instead of 12 hour
+const STATUS_B_24HOUR: u8 = 1 << 1;
+/// Status Register C: Interrupt request flag, set when any enabled flag below
+/// is set
+const STATUS_C_IRQF: u8 = 1 << 7;
+const STATUS_C_PF: u8 = 1 << 6;
+const STATUS_C_AF: u8 = 1 << 5;
+const STATUS_C_UF: u8 = 1 << 4;
+/// 12 hour mode sets the top bit of the hours register for PM
+const HOUR_PM: u8 = 1 << 7;
+/// The century register holds two BCD digits so the year has at most four
+const MAX_YEAR: u16 = 9999;
+/// An alarm register value of `0xC0..=0xFF` matches every value
+const ALARM_ANY: u8 = 0xFF;
+
+/// Slowest and fastest periodic rates the RTC can generate, the frequency is
+/// `32768 >> (rate - 1)` Hz so this is 2Hz to 8192Hz
+pub const RTC_RATE_MIN: u8 = 15;
+pub const RTC_RATE_MAX: u8 = 3;
+
+/// Count of periodic interrupts we have seen via [`poll`]
+static mut PERIODIC_TICKS: u64 = 0;
+
+/// Where [`Instant::now`] gets the time from, picked by [`init`]
+static mut SOURCE: ClockSource = ClockSource::Pit;
+/// The ticks accumulated from the PIT above its count when we last read it in
+/// the low 16 bits, packed so any CPU can update both with one compare and
+/// exchange. The counter wraps every ~55ms so [`Instant::now`] has to be
+/// called at least that often when we are running on the PIT
+static PIT_STATE: AtomicU64 = AtomicU64::new(0);
+/// The TSC when we picked it as the clock source
+static mut TSC_START: u64 = 0;
+/// How long we count TSC ticks for against the HPET, or the PIT without one,
+/// to find its frequency. Reading either reference takes a microsecond or so
+/// which over this window keeps the error to around 10ppm
+const TSC_CALIBRATION_US: u32 = 100_000;
 
 /// Stores the current time in its raw parts
 pub struct DateTime {
@@ -26,15 +101,83 @@ impl DateTime {
 	/// Captures the current time
 	pub fn now() -> Self {
 		Self {
-			sec: cpu::rtc_register(0x00),      // Seconds
-			min: cpu::rtc_register(0x02),      // Minutes
-			hour: cpu::rtc_register(0x04),     // Hours
-			day: cpu::rtc_register(0x07),      // Days
-			month: cpu::rtc_register(0x08),    // Months
-			year: cpu::rtc_register(0x09),     // Years
-			centuary: cpu::rtc_register(0x32), // Centuaries
+			sec: cpu::rtc_register(RTC_SECONDS), // Seconds
+			min: cpu::rtc_register(RTC_MINUTES), // Minutes
+			hour: cpu::rtc_register(RTC_HOURS),  // Hours
+			day: cpu::rtc_register(RTC_DAY),     // Days
+			month: cpu::rtc_register(RTC_MONTH), // Months
+			year: cpu::rtc_register(RTC_YEAR),   // Years
+			centuary: cpu::rtc_register(RTC_CENTURY), // Centuaries
 		}
 	}
+	/// Creates a [`DateTime`] from a time source such as SNTP or DHCP, the
+	/// values are encoded in whatever format (BCD/binary, 12/24 hour) the RTC
+	/// is currently configured for so [`DateTime::set`] can write them as is.
+	/// The hour is 24 hour, anything the RTC cannot hold is an
+	/// [`Error::InvalidDateTime`]
+	#[allow(dead_code)]
+	pub fn new(
+		year: u16,
+		month: u8,
+		day: u8,
+		hour: u8,
+		min: u8,
+		sec: u8,
+	) -> Result<Self> {
+		if year > MAX_YEAR
+			|| !(1..=12).contains(&month)
+			|| day == 0
+			|| day > days_in_month(year, month)
+			|| hour > 23
+			|| min > 59
+			|| sec > 59
+		{
+			return Err(Error::InvalidDateTime);
+		}
+		let status_b = cpu::rtc_register(RTC_STATUS_B);
+		Ok(Self {
+			sec: encode(status_b, sec),
+			min: encode(status_b, min),
+			hour: encode_hour(status_b, hour),
+			day: encode(status_b, day),
+			month: encode(status_b, month),
+			year: encode(status_b, (year % 100) as u8),
+			centuary: encode(status_b, (year / 100) as u8),
+		})
+	}
+	/// Writes this time back to the RTC, we wait for any update in progress to
+	/// finish and then hold the SET bit so the RTC does not tick while we are
+	/// halfway through writing the seconds to centuary registers
+	#[allow(dead_code)]
+	pub fn set(&self) {
+		while cpu::rtc_register(RTC_STATUS_A) & STATUS_A_UIP != 0 {}
+
+		let status_b = cpu::rtc_register(RTC_STATUS_B);
+		cpu::rtc_write(RTC_STATUS_B, status_b | STATUS_B_SET);
+
+		cpu::rtc_write(RTC_SECONDS, self.sec);
+		cpu::rtc_write(RTC_MINUTES, self.min);
+		cpu::rtc_write(RTC_HOURS, self.hour);
+		cpu::rtc_write(RTC_DAY, self.day);
+		cpu::rtc_write(RTC_MONTH, self.month);
+		cpu::rtc_write(RTC_YEAR, self.year);
+		cpu::rtc_write(RTC_CENTURY, self.centuary);
+
+		// Let the clock run again
+		cpu::rtc_write(RTC_STATUS_B, status_b & !STATUS_B_SET);
+	}
+	/// The register values as read, seconds first
+	pub fn raw(&self) -> [u8; 7] {
+		[
+			self.sec,
+			self.min,
+			self.hour,
+			self.day,
+			self.month,
+			self.year,
+			self.centuary,
+		]
+	}
 }
 /// Implement display for DateTime
 impl core::fmt::Display for DateTime {
@@ -53,6 +196,282 @@ impl core::fmt::Display for DateTime {
 	}
 }
 
+/// Days in `month` of `year`, February has 29 in Gregorian leap years
+fn days_in_month(year: u16, month: u8) -> u8 {
+	match month {
+		2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+/// Converts a binary value into the RTC register format given by status B
+fn encode(status_b: u8, val: u8) -> u8 {
+	if status_b & STATUS_B_BINARY != 0 {
+		val
+	} else {
+		((val / 10) << 4) | (val % 10)
+	}
+}
+
+/// Converts a 24 hour value into the RTC hour format, in 12 hour mode 0 is
+/// 12AM and the top bit marks PM
+fn encode_hour(status_b: u8, hour: u8) -> u8 {
+	if status_b & STATUS_B_24HOUR != 0 {
+		return encode(status_b, hour);
+	}
+	let pm = if hour >= 12 { HOUR_PM } else { 0 };
+	let hour = match hour % 12 {
+		0 => 12,
+		h => h,
+	};
+	encode(status_b, hour) | pm
+}
+
+/// Interrupt flags we read back from status register C
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RtcEvents {
+	pub periodic: bool,
+	pub alarm: bool,
+	pub update: bool,
+}
+
+/// Enables the RTC periodic interrupt on IRQ8 at `32768 >> (rate - 1)` Hz,
+/// `rate` is clamped between [`RTC_RATE_MAX`] and [`RTC_RATE_MIN`]
+#[allow(dead_code)]
+pub fn enable_periodic(rate: u8) {
+	let rate = rate.clamp(RTC_RATE_MAX, RTC_RATE_MIN);
+	let status_a = cpu::rtc_register(RTC_STATUS_A);
+	cpu::rtc_write(RTC_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+	let status_b = cpu::rtc_register(RTC_STATUS_B);
+	cpu::rtc_write(RTC_STATUS_B, status_b | STATUS_B_PIE);
+	unmask_irq8();
+	// Reading C clears any stale flag so the first interrupt can fire
+	cpu::rtc_register(RTC_STATUS_C);
+}
+
+/// Stops the RTC periodic interrupt
+#[allow(dead_code)]
+pub fn disable_periodic() {
+	let status_b = cpu::rtc_register(RTC_STATUS_B);
+	cpu::rtc_write(RTC_STATUS_B, status_b & !STATUS_B_PIE);
+}
+
+/// Enables the RTC alarm interrupt on IRQ8, a field of [`None`] matches any
+/// value so `set_alarm(None, None, Some(0))` fires every minute
+#[allow(dead_code)]
+pub fn set_alarm(hour: Option<u8>, min: Option<u8>, sec: Option<u8>) {
+	let status_b = cpu::rtc_register(RTC_STATUS_B);
+	cpu::rtc_write(
+		RTC_HOURS_ALARM,
+		hour.map_or(ALARM_ANY, |h| encode_hour(status_b, h)),
+	);
+	cpu::rtc_write(
+		RTC_MINUTES_ALARM,
+		min.map_or(ALARM_ANY, |m| encode(status_b, m)),
+	);
+	cpu::rtc_write(
+		RTC_SECONDS_ALARM,
+		sec.map_or(ALARM_ANY, |s| encode(status_b, s)),
+	);
+	cpu::rtc_write(RTC_STATUS_B, status_b | STATUS_B_AIE);
+	unmask_irq8();
+	cpu::rtc_register(RTC_STATUS_C);
+}
+
+/// Stops the RTC alarm interrupt
+#[allow(dead_code)]
+pub fn disable_alarm() {
+	let status_b = cpu::rtc_register(RTC_STATUS_B);
+	cpu::rtc_write(RTC_STATUS_B, status_b & !STATUS_B_AIE);
+}
+
+/// Reads and acknowledges status register C, the RTC will not raise IRQ8
+/// again until this is read. We have no IDT yet so this is also how we use
+/// the RTC as a timer source, by polling it from the main loop
+#[allow(dead_code)]
+pub fn poll() -> RtcEvents {
+	let status_c = cpu::rtc_register(RTC_STATUS_C);
+	if status_c & STATUS_C_IRQF == 0 {
+		return RtcEvents::default();
+	}
+	let events = RtcEvents {
+		periodic: status_c & STATUS_C_PF != 0,
+		alarm: status_c & STATUS_C_AF != 0,
+		update: status_c & STATUS_C_UF != 0,
+	};
+	if events.periodic {
+		unsafe { PERIODIC_TICKS += 1 };
+	}
+	events
+}
+
+/// Number of periodic interrupts seen by [`poll`] since boot, ticks are lost
+/// if we poll slower than the configured rate
+#[allow(dead_code)]
+pub fn periodic_ticks() -> u64 {
+	unsafe { PERIODIC_TICKS }
+}
+
+/// Unmask IRQ8 on the I/O APIC if we have one, otherwise it is on the slave
+/// PIC so we need both it and the cascade (IRQ2) on the master unmasked.
+/// Interrupts stay disabled with `cli` until we have an IDT
+fn unmask_irq8() {
+	if let Some(apic) = apic::get() {
+		if apic.route_isa_irq(8, false).is_ok() {
+			return;
+		}
+	}
+	cpu::out8(0xA1, cpu::in8(0xA1) & !(1 << 0));
+	cpu::out8(0x21, cpu::in8(0x21) & !(1 << 2));
+}
+
+/// The clock behind [`Instant`]
+#[derive(Debug, Clone, Copy)]
+pub enum ClockSource {
+	/// Invariant TSC, with its frequency in Hz
+	Tsc(u64),
+	Hpet(Hpet),
+	Pit,
+}
+
+impl core::fmt::Display for ClockSource {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			ClockSource::Tsc(frequency) => write!(
+				f,
+				"Clock source: invariant TSC at {} MHz",
+				frequency / 1_000_000
+			),
+			ClockSource::Hpet(hpet) => write!(f, "Clock source: {}", hpet),
+			ClockSource::Pit => {
+				write!(f, "Clock source: PIT at {} Hz", pit::PIT_FREQUENCY)
+			}
+		}
+	}
+}
+
+/// Pick the clock for [`Instant`], the TSC is the cheapest to read so we use it
+/// if it is invariant, then the HPET if ACPI describes one that works,
+/// otherwise we start the PIT channel 0 counter. We still bring up the HPET
+/// with the TSC so its comparator can be used as a timer
+pub fn init() -> ClockSource {
+	let hpet = hpet::init();
+	let features = cpuid::features();
+	let source = if features.tsc && features.invariant_tsc {
+		let frequency = calibrate_tsc(hpet.as_ref().ok());
+		unsafe { TSC_START = cpu::rdtsc() };
+		ClockSource::Tsc(frequency)
+	} else {
+		match hpet {
+			Ok(hpet) => ClockSource::Hpet(hpet),
+			Err(e) => {
+				print!("No HPET, falling back to the PIT: {:X?}\n", e);
+				pit::start_counter();
+				PIT_STATE.store(pit::read_counter() as u64, Ordering::Relaxed);
+				ClockSource::Pit
+			}
+		}
+	};
+	unsafe { SOURCE = source };
+	source
+}
+
+/// Count TSC ticks over [`TSC_CALIBRATION_US`] to find its frequency in Hz,
+/// timed by the HPET main counter when we have one as it is far more precise
+/// than the PIT
+fn calibrate_tsc(hpet: Option<&Hpet>) -> u64 {
+	let hpet = match hpet {
+		Some(hpet) => hpet,
+		None => {
+			let start = cpu::rdtsc();
+			pit::wait_us(TSC_CALIBRATION_US);
+			let ticks = cpu::rdtsc() - start;
+			return ticks * 1_000_000 / TSC_CALIBRATION_US as u64;
+		}
+	};
+
+	// Line up with an HPET tick so we time whole ticks
+	let window = Duration::from_micros(TSC_CALIBRATION_US as u64);
+	let edge = hpet.elapsed();
+	let mut start = hpet.elapsed();
+	while start == edge {
+		start = hpet.elapsed();
+	}
+	let tsc_start = cpu::rdtsc();
+	let mut end = start;
+	while end - start < window {
+		end = hpet.elapsed();
+	}
+	let ticks = cpu::rdtsc() - tsc_start;
+	ticks * 1_000_000_000 / (end - start).as_nanos() as u64
+}
+
+/// A monotonic point in time measured from when the clock source was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+	/// Captures the current time from the clock source
+	pub fn now() -> Self {
+		let source = unsafe { SOURCE };
+		match source {
+			ClockSource::Tsc(frequency) => Self(tsc_elapsed(frequency)),
+			ClockSource::Hpet(hpet) => Self(hpet.elapsed()),
+			ClockSource::Pit => Self(pit_elapsed()),
+		}
+	}
+	/// Time since this instant was captured
+	pub fn elapsed(&self) -> Duration {
+		Self::now().duration_since(*self)
+	}
+	/// Time from `earlier` to this instant, zero if `earlier` is later
+	pub fn duration_since(&self, earlier: Instant) -> Duration {
+		self.0.saturating_sub(earlier.0)
+	}
+}
+
+impl core::ops::Add<Duration> for Instant {
+	type Output = Instant;
+	fn add(self, rhs: Duration) -> Instant {
+		Instant(self.0 + rhs)
+	}
+}
+
+/// TSC ticks since we picked it, split so the nanoseconds do not overflow
+fn tsc_elapsed(frequency: u64) -> Duration {
+	let ticks = cpu::rdtsc().wrapping_sub(unsafe { TSC_START });
+	let nanos = (ticks % frequency) * 1_000_000_000 / frequency;
+	Duration::new(ticks / frequency, nanos as u32)
+}
+
+/// Accumulate the PIT ticks since we last looked, the counter counts down
+/// from 65536 so the difference wraps naturally in a u16
+fn pit_elapsed() -> Duration {
+	let mut state = PIT_STATE.load(Ordering::Relaxed);
+	let ticks = loop {
+		// Read the counter after the state so it is never older than the
+		// count it was last updated from
+		let count = pit::read_counter();
+		let ticks = (state >> 16) + (state as u16).wrapping_sub(count) as u64;
+		let next = ticks << 16 | count as u64;
+		match PIT_STATE.compare_exchange_weak(
+			state,
+			next,
+			Ordering::Relaxed,
+			Ordering::Relaxed,
+		) {
+			Ok(_) => break ticks,
+			Err(current) => state = current,
+		}
+	};
+	let frequency = pit::PIT_FREQUENCY as u64;
+	let nanos = (ticks % frequency) * 1_000_000_000 / frequency;
+	Duration::new(ticks / frequency, nanos as u32)
+}
+
 // Bugged as it doesnt use Epoch time yet, hacked it by adding minutes
 // pub fn sleep(seconds: usize) {
 //     let dt = DateTime::now();
@@ -65,3 +484,58 @@ impl core::fmt::Display for DateTime {
 //     {}
 //     //serial_print!(" Awake now at: {:X}\n", DateTime::now().sec);
 // }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Status B as the RTC ships, BCD and 12 hour
+	const BCD_12HOUR: u8 = 0;
+	const BCD_24HOUR: u8 = STATUS_B_24HOUR;
+	const BINARY_24HOUR: u8 = STATUS_B_BINARY | STATUS_B_24HOUR;
+
+	#[test]
+	fn encode_bcd() {
+		assert_eq!(encode(BCD_24HOUR, 0), 0x00);
+		assert_eq!(encode(BCD_24HOUR, 9), 0x09);
+		assert_eq!(encode(BCD_24HOUR, 10), 0x10);
+		assert_eq!(encode(BCD_24HOUR, 59), 0x59);
+		assert_eq!(encode(BCD_24HOUR, 99), 0x99);
+	}
+
+	#[test]
+	fn encode_binary() {
+		assert_eq!(encode(BINARY_24HOUR, 59), 59);
+		assert_eq!(encode(BINARY_24HOUR, 99), 99);
+	}
+
+	#[test]
+	fn encode_hour_24() {
+		assert_eq!(encode_hour(BCD_24HOUR, 0), 0x00);
+		assert_eq!(encode_hour(BCD_24HOUR, 13), 0x13);
+		assert_eq!(encode_hour(BCD_24HOUR, 23), 0x23);
+		assert_eq!(encode_hour(BINARY_24HOUR, 23), 23);
+	}
+
+	#[test]
+	fn encode_hour_12() {
+		// Midnight is 12AM and noon is 12PM
+		assert_eq!(encode_hour(BCD_12HOUR, 0), 0x12);
+		assert_eq!(encode_hour(BCD_12HOUR, 1), 0x01);
+		assert_eq!(encode_hour(BCD_12HOUR, 11), 0x11);
+		assert_eq!(encode_hour(BCD_12HOUR, 12), HOUR_PM | 0x12);
+		assert_eq!(encode_hour(BCD_12HOUR, 13), HOUR_PM | 0x01);
+		assert_eq!(encode_hour(BCD_12HOUR, 23), HOUR_PM | 0x11);
+		assert_eq!(encode_hour(STATUS_B_BINARY, 23), HOUR_PM | 11);
+	}
+
+	#[test]
+	fn leap_years() {
+		assert_eq!(days_in_month(2024, 2), 29);
+		assert_eq!(days_in_month(2023, 2), 28);
+		assert_eq!(days_in_month(1900, 2), 28);
+		assert_eq!(days_in_month(2000, 2), 29);
+		assert_eq!(days_in_month(2023, 4), 30);
+		assert_eq!(days_in_month(2023, 12), 31);
+	}
+}
@@ -46,10 +46,22 @@ fn main() {
         pe.image_base, pe.entry_point
     );
 
+    // The `payload` feature appends a 64-bit payload for the bootloader to
+    // hand off to in long mode
+    let payload = bootloader_features().iter().any(|f| f == "payload");
+    if payload {
+        build_payload().expect("Cannot assemble payload64.asm");
+    }
+
     // Link the PE to the stage0.asm bootloader and set the entry point to match
     // the PE first instruction
-    build_asm(pe.image_base + pe.entry_point)
-        .expect("Cannot assemble stage0.asm");
+    build_asm(
+        pe.image_base + pe.entry_point,
+        pe.image_end(),
+        pe.readonly_range(),
+        payload,
+    )
+    .expect("Cannot assemble stage0.asm");
     println!("PE Written to: {}", FLATTENED_IMAGE_PATH);
 
     // Tells the user how much space they have left, does not include the
@@ -70,25 +82,69 @@ fn write_flattened_image(bytes: &[u8], path: &str) -> Result<()> {
     Ok(())
 }
 /// This function compiles the assembly code with the entry point found in the
-/// PE
-fn build_asm(entry: u32) -> Result<()> {
+/// PE, where the image ends in memory and which part of it is read only so
+/// the kernel can protect it. With `payload` the 64-bit payload is appended
+/// past the end of the image
+fn build_asm(
+    entry: u32,
+    image_end: u32,
+    readonly: (u32, u32),
+    payload: bool,
+) -> Result<()> {
+    use std::process::Command;
+
+    let mut args = vec![
+        "bootloader/asm/stage0.asm".to_string(),
+        "-f".to_string(),
+        "bin".to_string(),
+        format!("-Dentry_point={:#X}", entry),
+        format!("-Dimage_end={:#X}", image_end),
+        format!("-Dreadonly_start={:#X}", readonly.0),
+        format!("-Dreadonly_end={:#X}", readonly.1),
+        "-o".to_string(),
+        "bootloader/build/stage0.bin".to_string(),
+    ];
+    if payload {
+        args.push("-Dpayload".to_string());
+    }
+
+    let res = Command::new("nasm")
+        .args(&args)
+        .output()
+        .map_err(Error::NasmMissing)?;
+
+    match &res.status.code() {
+        Some(0) => {
+            println!("Bootloader: Nasm sucess, rust entry point: {:#X}", entry);
+            Ok(())
+        }
+        Some(_) => {
+            let stderr = String::from_utf8(res.stderr)
+                .map_err(Error::CantConvertToUtf)?;
+            Err(Error::NasmBuildFailed(stderr))
+        }
+        None => Err(Error::CommandDidNotComplete),
+    }
+}
+/// This function assembles the 64-bit payload that stage0.asm appends with the
+/// `payload` feature
+fn build_payload() -> Result<()> {
     use std::process::Command;
 
     let res = Command::new("nasm")
         .args([
-            "bootloader/asm/stage0.asm",
+            "bootloader/asm/payload64.asm",
             "-f",
             "bin",
-            &format!("-Dentry_point={:#X}", entry),
             "-o",
-            "bootloader/build/stage0.bin",
+            "bootloader/build/payload64.bin",
         ])
         .output()
         .map_err(Error::NasmMissing)?;
 
     match &res.status.code() {
         Some(0) => {
-            println!("Bootloader: Nasm sucess, rust entry point: {:#X}", entry);
+            println!("Payload: Nasm sucess");
             Ok(())
         }
         Some(_) => {
@@ -99,13 +155,26 @@ fn build_asm(entry: u32) -> Result<()> {
         None => Err(Error::CommandDidNotComplete),
     }
 }
+/// The bootloader cargo features from `BOOTLOADER_FEATURES`, separated by
+/// commas or spaces like cargo accepts them
+fn bootloader_features() -> Vec<String> {
+    std::env::var("BOOTLOADER_FEATURES")
+        .unwrap_or_default()
+        .split([',', ' '])
+        .filter(|feature| !feature.is_empty())
+        .map(String::from)
+        .collect()
+}
 /// This function comiples the bootloader in the subfolder and returns an error
 /// if it fails
 fn build_bootloader() -> Result<()> {
     use std::process::Command;
 
+    // Pass through any bootloader cargo features such as `panic-poweroff`
+    let features = bootloader_features().join(",");
+
     let res = Command::new("cargo")
-        .args(["build", "--release"])
+        .args(["build", "--release", "--features", &features])
         .current_dir("bootloader")
         .output()
         .map_err(Error::CargoMissing)?;
@@ -286,6 +355,36 @@ impl Pe {
             bytes,
         })
     }
+    /// The first address past the loaded image, this includes sections such
+    /// as `.bss` that have no raw data in the file
+    fn image_end(&self) -> u32 {
+        let size = self
+            .sections
+            .iter()
+            .map(|section| section.virtual_addr + section.virtual_size)
+            .max()
+            .unwrap_or(0);
+        self.image_base + size
+    }
+    /// The span of read only sections such as `.text` and `.rdata` from the
+    /// start of the image up to the first writable section
+    fn readonly_range(&self) -> (u32, u32) {
+        const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+        let mut sections: Vec<&Section> = self.sections.iter().collect();
+        sections.sort_by_key(|section| section.virtual_addr);
+        let start = sections.first().map_or(0, |section| section.virtual_addr);
+        let end = sections
+            .iter()
+            .take_while(|section| {
+                section.characteristics & IMAGE_SCN_MEM_WRITE == 0
+            })
+            .last()
+            .map_or(start, |section| {
+                section.virtual_addr + section.virtual_size
+            });
+        (self.image_base + start, self.image_base + end)
+    }
     /// Converts the sections into a flat binary we can append to our stage0.asm
     fn flatten(&self) -> Result<Vec<u8>> {
         println!("{:#X?}", self.sections);
@@ -326,7 +425,9 @@ macro_rules! consume {
         //     <$ty>::from_le_bytes(buf),
         //     <$ty>::from_be_bytes(buf),
         // );
-        <$ty>::from_le_bytes(buf)
+        // Bind the value so skipped fields dont trip `unused_must_use`
+        let value = <$ty>::from_le_bytes(buf);
+        value
     }};
 }
 